@@ -0,0 +1,81 @@
+use crate::{
+    errors::Errors,
+    prop::Prop,
+    webdav::{PropfindDepth, Quota, Version},
+};
+
+/// A kind of server the filesystem can mount. WebDAV servers support every operation, other
+/// backends may only serve reads and refuse changes with `Errors::ReadOnlyBackend`
+pub trait Backend: Send {
+    /// Lists `path`, which is relative to the root of the backend
+    fn list(&self, path: &str, depth: PropfindDepth) -> Result<Vec<Prop>, Errors>;
+
+    /// Downloads `size` bytes of the file at `path`, starting at `offset`
+    fn get_range(&self, path: &str, offset: u64, size: u64) -> Result<Vec<u8>, Errors>;
+
+    /// URL of `path` on the server
+    fn href(&self, path: &str) -> String;
+
+    /// A token that changes with every change below `path`, if the backend has one
+    fn sync_token(&self, _path: &str) -> Result<Option<String>, Errors> {
+        Ok(None)
+    }
+
+    /// Storage used and available below `path`, if the backend knows a limit
+    fn quota(&self, _path: &str) -> Result<Option<Quota>, Errors> {
+        Ok(None)
+    }
+
+    /// Earlier versions of the file with the Nextcloud `fileid`, oldest first. Empty if the
+    /// backend keeps none
+    fn versions(&self, _fileid: &str) -> Result<Vec<Version>, Errors> {
+        Ok(vec![])
+    }
+
+    /// Downloads `size` bytes of an earlier version, starting at `offset`
+    fn get_version_range(
+        &self,
+        version: &Version,
+        _offset: u64,
+        _size: u64,
+    ) -> Result<Vec<u8>, Errors> {
+        Err(Errors::FileDoesNotExist(version.url.clone().into()))
+    }
+
+    fn put(&self, path: &str, _data: Vec<u8>, _mtime: Option<u64>) -> Result<(), Errors> {
+        Err(Errors::ReadOnlyBackend(path.into()))
+    }
+
+    fn mkcol(&self, path: &str) -> Result<(), Errors> {
+        Err(Errors::ReadOnlyBackend(path.into()))
+    }
+
+    fn delete(&self, path: &str) -> Result<(), Errors> {
+        Err(Errors::ReadOnlyBackend(path.into()))
+    }
+
+    fn move_to(&self, from: &str, _to: &str, _overwrite: bool) -> Result<(), Errors> {
+        Err(Errors::ReadOnlyBackend(from.into()))
+    }
+
+    fn copy_to(&self, from: &str, _to: &str) -> Result<(), Errors> {
+        Err(Errors::ReadOnlyBackend(from.into()))
+    }
+
+    fn set_mtime(&self, path: &str, _mtime: u64) -> Result<(), Errors> {
+        Err(Errors::ReadOnlyBackend(path.into()))
+    }
+
+    /// Stores the dead property `name` of our own namespace on `path`
+    fn set_property(&self, path: &str, _name: &str, _value: &str) -> Result<(), Errors> {
+        Err(Errors::ReadOnlyBackend(path.into()))
+    }
+
+    fn remove_property(&self, path: &str, _name: &str) -> Result<(), Errors> {
+        Err(Errors::ReadOnlyBackend(path.into()))
+    }
+
+    fn create_symlink(&self, path: &str, _target: &str) -> Result<(), Errors> {
+        Err(Errors::ReadOnlyBackend(path.into()))
+    }
+}
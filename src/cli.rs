@@ -0,0 +1,739 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use fuser::MountOption;
+
+use crate::{
+    config::{Config, HiddenFiles, Normalization, COMMON_SERVER_JUNK},
+    daemon,
+    delete::DEFAULT_DELETE_THREADS,
+    dirs,
+    download::DEFAULT_DOWNLOAD_THREADS,
+    errors::Errors,
+    profile::Profiles,
+    systemd::SystemdArgs,
+    watch::DEFAULT_WATCH_INTERVAL,
+};
+
+pub const USAGE: &str = "\
+usage: rust_webdav <url> <mountpoint> [--daemon] [--remote-root <path>] [--as-of <time>]
+                   [--uid <uid>] [--gid <gid>] [--file-mode <mode>] [--dir-mode <mode>]
+                   [-o option[,option...]]
+       rust_webdav umount <mountpoint> [--pidfile <file>]
+       rust_webdav systemd --profile <name> --mountpoint <dir> [--output-dir <dir>]
+       rust_webdav fsck --profile <name> [--repair]
+       rust_webdav watch --profile <name> [--interval <seconds>] [<path>]
+       rust_webdav set <mountpoint> --read-only on|off
+       rust_webdav history <path>
+       rust_webdav delete <path>
+       rust_webdav put [-r] --profile <name> <local path> <remote path>
+       rust_webdav --paths [<url>]
+
+Every form accepts --error-format text|json to choose how a fatal error is reported.
+       mount.webdavfs <url> <mountpoint> [-sfnv] [-o option[,option...]]
+
+options:
+    profile=NAME          apply the options of a profile from the configuration file
+    config=FILE           configuration file holding the profiles
+    url=URL               WebDAV URL, if <url> is not a URL
+    mirror=URL            server with the same content, read from while <url> is down
+    remote_root=PATH      mount only this directory of the remote, same as --remote-root
+    remotes=NAME[:NAME...]
+                          serve the remotes of these profiles as top-level directories
+    union=NAME[:NAME...]  merge the remotes of these profiles, earlier ones win
+    write_target=NAME     profile of the union that receives changes (default the first)
+    username=NAME         user to log in as
+    password=SECRET       password of the user
+    password_file=FILE    read the password from the first line of FILE
+    user_agent=STRING     User-Agent sent to the server, default rust_webdav/<version>
+    rate_limit=REQUESTS   send at most this many WebDAV requests per second
+    atomic_uploads        upload to a hidden .NAME.part file and move it into place
+    watch[=SECONDS]       poll the remote for changes (default every 30 seconds) and drop
+                          changed entries from the caches of the kernel
+    download_threads=COUNT
+                          read from the server on this many threads (default 4), so a
+                          slow download does not hold up other operations. 0 reads on
+                          the thread that serves the kernel
+    delete_threads=COUNT  delete the entries of a tree on this many threads (default 4) when
+                          the server does not delete it at once. 0 deletes on the thread that
+                          serves the kernel
+    backend=webdav|http   protocol of the server, http reads JSON directory indexes, read-only
+    snapshot              serve the tree listed at mount time and never refresh it, read-only
+    as_of=TIME            show files as they were at TIME, from Nextcloud versions, read-only.
+                          TIME is seconds since the epoch, YYYY-MM-DD or YYYY-MM-DDTHH:MM[:SS]
+                          in local time, or RFC 3339, same as --as-of
+    ro, rw                mount read-only (default) or read-write
+    uid=UID, gid=GID      owner of every entry (default the mounting user), same as --uid
+                          and --gid
+    file_mode=MODE, dir_mode=MODE
+                          octal permission bits of files (default 644) and directories
+                          (default 755), same as --file-mode and --dir-mode
+    blksize=BYTES         block size reported to applications
+    hide=NAME[:NAME...]   hide entries with these names
+    hide_junk             hide common server artifacts like .DS_Store
+    probe_names=NAME[:NAME...]
+                          names remembered as missing until their directory changes,
+                          instead of .localized, .hidden, desktop.ini and the like
+    hidden=show|hide|strip
+                          show (default), hide or show without leading dot the entries
+                          the server marks as hidden
+    case_insensitive      resolve lookups regardless of case
+    normalize=nfc|nfd     create names in this unicode form on the server and resolve
+                          lookups regardless of the form
+    refresh_on_open=PATH[:PATH...]
+                          list these directories from the server whenever they are opened
+    write_barrier=PATH[:PATH...]
+                          upload files below these paths on fsync and wait for the server,
+                          for databases and other files that have to survive a crash
+    state_file[=FILE]     keep inode numbers stable across remounts, stored below
+                          $XDG_STATE_HOME/rust_webdav if no FILE is given
+    max_inodes=COUNT      upper bound of inodes kept in memory
+    max_buffer=BYTES      largest file that can be written to (default 1073741824)
+    attr_ttl=SECONDS      how long the kernel caches attributes
+    negative_ttl=SECONDS  how long the kernel caches missing names, 0 disables (default 1)
+    log_level=LEVEL       off, error, warn, info, debug or trace
+    hung_timeout=SECONDS  log operations running longer, 0 disables (default 30)
+    cancel_hung           let hung operations fail instead of reconnecting
+    allow_other, allow_root, auto_unmount, default_permissions
+                          passed on to FUSE
+    foreground            do not detach when started as mount helper
+    daemon                detach once mounted, same as --daemon
+    pidfile=FILE          where a detached process stores its pid
+    log_file=FILE         where a detached process writes its log";
+
+/// What the program was asked to do
+#[derive(Debug)]
+pub enum Command {
+    Mount(MountArgs),
+    /// Generate systemd units for a profile
+    Systemd(SystemdArgs),
+    /// Unmount a mountpoint and wait for the daemon serving it
+    Umount { mountpoint: PathBuf, pidfile: PathBuf },
+    /// Show where configuration, cache and state are stored, optionally for a remote
+    Paths { url: Option<String> },
+    /// Check the state database of a profile against the remote, fixing it with `repair`
+    Fsck { mount: MountArgs, repair: bool },
+    /// Print the changes below a path of a profile's remote as they are found
+    Watch {
+        mount: MountArgs,
+        path: String,
+        interval: Duration,
+    },
+    /// Switch a running mount between read-only and read-write
+    Set { mountpoint: PathBuf, read_only: bool },
+    /// Show the etags a running mount has seen for a file
+    History { path: PathBuf },
+    /// Delete a path of a running mount with everything below it
+    Delete { path: PathBuf },
+    /// Upload a local file, or with `recursive` a directory, to a path of a profile's remote
+    Put {
+        mount: MountArgs,
+        source: PathBuf,
+        target: String,
+        recursive: bool,
+    },
+}
+
+/// Everything needed to mount a remote, gathered from the command line and profiles
+#[derive(Debug)]
+pub struct MountArgs {
+    pub url: String,
+    /// Directory of the remote that is shown at the mountpoint, "/" for everything
+    pub remote_root: String,
+    pub mountpoint: PathBuf,
+    pub username: String,
+    pub password: String,
+    pub user_agent: Option<String>,
+    pub rate_limit: Option<u32>,
+    pub mirror: Option<String>,
+    pub atomic_uploads: bool,
+    pub http_index: bool,
+    pub config: Config,
+    pub fuse_options: Vec<MountOption>,
+    /// Only check the arguments, do not mount (`mount -f`)
+    pub fake: bool,
+    /// Stay attached to the calling process
+    pub foreground: bool,
+    pub pidfile: Option<PathBuf>,
+    pub log_file: Option<PathBuf>,
+    /// Remotes served in their own top-level directories instead of `url`
+    pub remotes: Vec<Remote>,
+    /// Merge `remotes` into one tree instead of giving each a directory
+    pub union: bool,
+    /// Name of the remote of a union that receives changes
+    pub write_target: Option<String>,
+    /// Poll the remote for changes in this interval and drop them from the caches of the
+    /// kernel. `None` leaves changes to show up once `attr_ttl` expired
+    pub watch: Option<Duration>,
+    /// Threads that reads from the server are handed to, 0 reads while the kernel waits
+    pub download_threads: usize,
+    /// Threads deleting the entries of a tree level by level, 0 deletes while the kernel waits
+    pub delete_threads: usize,
+}
+
+/// A remote shown as a top-level directory of the mountpoint. Only the connection settings
+/// of its profile are used, everything else comes from the mount
+#[derive(Debug, Clone)]
+pub struct Remote {
+    /// Name of the profile, which is also the name of the directory
+    pub name: String,
+    pub url: String,
+    pub remote_root: String,
+    pub username: String,
+    pub password: String,
+    /// Sent instead of the default User-Agent
+    pub user_agent: Option<String>,
+    /// Most requests sent per second
+    pub rate_limit: Option<u32>,
+    /// Server with the same content that serves reads while `url` is unreachable
+    pub mirror: Option<String>,
+    /// Upload to a temporary name and move into place once complete
+    pub atomic_uploads: bool,
+    /// Plain HTTP server publishing JSON directory indexes instead of a WebDAV server
+    pub http_index: bool,
+}
+
+impl Remote {
+    fn from_profile(name: &str, profiles: &Profiles) -> Result<Self, Errors> {
+        let profile = profiles
+            .get(name)
+            .ok_or_else(|| Errors::InvalidArguments(format!("unknown profile {name}")))?;
+        let mut args = MountArgs::new(PathBuf::new());
+        for (key, value) in profile {
+            args.apply_option(key, value.as_deref())?;
+        }
+        if args.url.is_empty() {
+            return Err(Errors::InvalidArguments(format!("profile {name} has no url")));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            ..args.remote()
+        })
+    }
+}
+
+impl MountArgs {
+    fn new(mountpoint: PathBuf) -> Self {
+        Self {
+            url: String::new(),
+            remote_root: "/".into(),
+            mountpoint,
+            username: std::env::var("WEBDAV_USERNAME").unwrap_or_default(),
+            password: std::env::var("WEBDAV_PASSWORD").unwrap_or_default(),
+            user_agent: None,
+            rate_limit: None,
+            mirror: None,
+            atomic_uploads: false,
+            http_index: false,
+            config: Config::default(),
+            fuse_options: vec![],
+            fake: false,
+            foreground: true,
+            pidfile: None,
+            log_file: None,
+            watch: None,
+            download_threads: DEFAULT_DOWNLOAD_THREADS,
+            delete_threads: DEFAULT_DELETE_THREADS,
+            remotes: vec![],
+            union: false,
+            write_target: None,
+        }
+    }
+
+    /// Connection settings of the remote given by `url`, shown at the mountpoint itself
+    pub fn remote(&self) -> Remote {
+        Remote {
+            name: String::new(),
+            url: self.url.clone(),
+            remote_root: self.remote_root.clone(),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            user_agent: self.user_agent.clone(),
+            rate_limit: self.rate_limit,
+            mirror: self.mirror.clone(),
+            atomic_uploads: self.atomic_uploads,
+            http_index: self.http_index,
+        }
+    }
+
+    /// Replaces a `state_file` option without a value by the default location for the remote
+    fn resolve_state_file(&mut self) {
+        if self.config.state_file.as_deref() != Some(Path::new("")) {
+            return;
+        }
+        let remote = match self.remotes.as_slice() {
+            [] => self.url.trim_end_matches('/').to_string() + &self.remote_root,
+            remotes => remotes.iter().map(|r| r.name.as_str()).collect::<Vec<_>>().join("+"),
+        };
+        self.config.state_file = Some(dirs::default_state_file(&remote));
+    }
+
+    /// Applies a single `key[=value]` mount option
+    fn apply_option(&mut self, key: &str, value: Option<&str>) -> Result<(), Errors> {
+        let invalid = || Errors::InvalidArguments(format!("invalid value for option {key}"));
+        let required =
+            || value.ok_or_else(|| Errors::InvalidArguments(format!("{key} needs a value")));
+
+        match key {
+            "url" => self.url = required()?.to_string(),
+            "remote_root" => {
+                let root = required()?.trim_matches('/');
+                self.remote_root = format!("/{root}");
+            }
+            "username" => self.username = required()?.to_string(),
+            "password" => self.password = required()?.to_string(),
+            "password_file" => {
+                let content = std::fs::read_to_string(required()?).map_err(|_| invalid())?;
+                self.password = content.lines().next().unwrap_or_default().to_string();
+            }
+            "user_agent" => self.user_agent = Some(required()?.to_string()),
+            "rate_limit" => {
+                let limit = required()?.parse().map_err(|_| invalid())?;
+                self.rate_limit = Some(limit).filter(|&limit| limit > 0);
+            }
+            "mirror" => self.mirror = Some(required()?.to_string()),
+            "atomic_uploads" => self.atomic_uploads = true,
+            "watch" => {
+                let seconds = match value {
+                    Some(seconds) => seconds.parse().map_err(|_| invalid())?,
+                    None => DEFAULT_WATCH_INTERVAL.as_secs(),
+                };
+                self.watch = Some(Duration::from_secs(seconds)).filter(|w| !w.is_zero());
+            }
+            "download_threads" => {
+                self.download_threads = required()?.parse().map_err(|_| invalid())?
+            }
+            "delete_threads" => {
+                self.delete_threads = required()?.parse().map_err(|_| invalid())?
+            }
+            "backend" => {
+                self.http_index = match required()? {
+                    "webdav" => false,
+                    "http" => true,
+                    _ => return Err(invalid()),
+                }
+            }
+            "write_target" => self.write_target = Some(required()?.to_string()),
+            "ro" => self.config.read_only = true,
+            "rw" => self.config.read_only = false,
+            "uid" => self.config.uid = required()?.parse().map_err(|_| invalid())?,
+            "gid" => self.config.gid = required()?.parse().map_err(|_| invalid())?,
+            "file_mode" => self.config.file_mode = parse_mode(required()?).ok_or_else(invalid)?,
+            "dir_mode" => self.config.dir_mode = parse_mode(required()?).ok_or_else(invalid)?,
+            "blksize" => self.config.block_size = required()?.parse().map_err(|_| invalid())?,
+            "hide" => {
+                let names = required()?.split(':').map(String::from);
+                self.config.hidden_names.extend(names);
+            }
+            "probe_names" => {
+                let names = required()?.split(':').filter(|name| !name.is_empty());
+                self.config.probe_names = names.map(String::from).collect();
+            }
+            "hide_junk" => {
+                let junk = COMMON_SERVER_JUNK.map(String::from);
+                self.config.hidden_names.extend(junk);
+            }
+            "hidden" => {
+                self.config.hidden_files = match required()? {
+                    "show" => HiddenFiles::Show,
+                    "hide" => HiddenFiles::Hide,
+                    "strip" => HiddenFiles::Strip,
+                    _ => return Err(invalid()),
+                }
+            }
+            "case_insensitive" => self.config.case_insensitive = true,
+            "normalize" => {
+                self.config.normalization = match required()? {
+                    "nfc" => Some(Normalization::Nfc),
+                    "nfd" => Some(Normalization::Nfd),
+                    _ => return Err(invalid()),
+                }
+            }
+            "snapshot" => self.config.snapshot = true,
+            "as_of" => self.config.as_of = Some(parse_time(required()?).ok_or_else(invalid)?),
+            "refresh_on_open" => {
+                let paths = required()?.split(':');
+                let paths = paths.map(|p| format!("/{}", p.trim_matches('/')));
+                self.config.refresh_on_open.extend(paths);
+            }
+            "write_barrier" => {
+                let paths = required()?.split(':');
+                let paths = paths.map(|p| format!("/{}", p.trim_matches('/')));
+                self.config.write_barrier.extend(paths);
+            }
+            // Without a value the location is derived from the URL once it is known
+            "state_file" => self.config.state_file = value.map(PathBuf::from).or(Some("".into())),
+            "max_inodes" => {
+                self.config.max_inodes = Some(required()?.parse().map_err(|_| invalid())?)
+            }
+            "max_buffer" => {
+                self.config.max_buffer_size = required()?.parse().map_err(|_| invalid())?
+            }
+            "attr_ttl" => {
+                let secs = required()?.parse().map_err(|_| invalid())?;
+                self.config.attr_ttl = Duration::from_secs(secs);
+            }
+            "negative_ttl" => {
+                let secs = required()?.parse().map_err(|_| invalid())?;
+                self.config.negative_ttl = Duration::from_secs(secs);
+            }
+            "log_level" => self.config.log_level = required()?.parse().map_err(|_| invalid())?,
+            "hung_timeout" => {
+                let secs = required()?.parse().map_err(|_| invalid())?;
+                self.config.hung_threshold = (secs > 0).then(|| Duration::from_secs(secs));
+            }
+            "cancel_hung" => self.config.cancel_hung = true,
+            "allow_other" => self.fuse_options.push(MountOption::AllowOther),
+            "allow_root" => self.fuse_options.push(MountOption::AllowRoot),
+            "auto_unmount" => self.fuse_options.push(MountOption::AutoUnmount),
+            "default_permissions" => self.fuse_options.push(MountOption::DefaultPermissions),
+            "foreground" => self.foreground = true,
+            "daemon" => self.foreground = false,
+            "pidfile" => self.pidfile = Some(required()?.into()),
+            "log_file" => self.log_file = Some(required()?.into()),
+            // Interpreted by mount(8) and systemd, not by us
+            "defaults" | "auto" | "noauto" | "nofail" | "_netdev" | "user" | "users"
+            | "nouser" => {}
+            key if key.starts_with("x-") || key.starts_with("comment") => {}
+            unknown => warn!("Ignoring unsupported mount option {unknown}"),
+        }
+        Ok(())
+    }
+
+    /// Applies a comma separated option string. `config`, `profile`, `remotes` and `union` are
+    /// handled first, so options given explicitly override the ones from the profile
+    fn apply_options(&mut self, options: &[String]) -> Result<(), Errors> {
+        let options: Vec<(&str, Option<&str>)> = options
+            .iter()
+            .flat_map(|o| o.split(','))
+            .filter(|o| !o.is_empty())
+            .map(|o| match o.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (o, None),
+            })
+            .collect();
+        let find = |wanted: &str| options.iter().find(|(key, _)| *key == wanted);
+
+        let config_file = match find("config") {
+            Some((_, Some(file))) => PathBuf::from(file),
+            _ => dirs::config_file(),
+        };
+        if let Some((_, Some(name))) = find("profile") {
+            let profiles = Profiles::load(&config_file)?;
+            let profile = profiles
+                .get(name)
+                .ok_or_else(|| Errors::InvalidArguments(format!("unknown profile {name}")))?;
+            for (key, value) in profile {
+                self.apply_option(key, value.as_deref())?;
+            }
+        }
+        let union = find("union");
+        if let Some((_, Some(names))) = union.or_else(|| find("remotes")) {
+            let profiles = Profiles::load(&config_file)?;
+            for name in names.split(':') {
+                self.remotes.push(Remote::from_profile(name, &profiles)?);
+            }
+            self.union = union.is_some();
+        }
+        for (key, value) in options {
+            if !matches!(key, "profile" | "config" | "remotes" | "union") {
+                self.apply_option(key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a fatal error is written to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Text,
+    /// A single JSON object, for wrapper scripts
+    Json,
+}
+
+/// Removes `--error-format <format>` from the arguments, as it applies to every command
+pub fn take_error_format(args: &mut Vec<String>) -> Result<ErrorFormat, Errors> {
+    let Some(idx) = args.iter().position(|a| a == "--error-format") else {
+        return Ok(ErrorFormat::Text);
+    };
+    let value = args.get(idx + 1).cloned();
+    args.drain(idx..(idx + 2).min(args.len()));
+    match value.as_deref() {
+        Some("text") => Ok(ErrorFormat::Text),
+        Some("json") => Ok(ErrorFormat::Json),
+        _ => Err(Errors::InvalidArguments(
+            "--error-format expects text or json".into(),
+        )),
+    }
+}
+
+/// Parses the command line. `args` includes the program name
+pub fn parse(args: &[String]) -> Result<Command, Errors> {
+    match args.get(1).map(String::as_str) {
+        Some("systemd") => Ok(Command::Systemd(SystemdArgs::parse(&args[2..])?)),
+        Some("umount") => parse_umount(&args[2..]),
+        Some("fsck") => parse_fsck(&args[2..]),
+        Some("set") => parse_set(&args[2..]),
+        Some("watch") => parse_watch(&args[2..]),
+        Some("put") => parse_put(&args[2..]),
+        Some("history") => match &args[2..] {
+            [path] => Ok(Command::History { path: path.into() }),
+            _ => Err(Errors::InvalidArguments("expected a path inside of a mount".into())),
+        },
+        Some("delete") => match &args[2..] {
+            [path] => Ok(Command::Delete { path: path.into() }),
+            _ => Err(Errors::InvalidArguments("expected a path inside of a mount".into())),
+        },
+        Some("--paths") => Ok(Command::Paths {
+            url: args.get(2).cloned(),
+        }),
+        _ => Ok(Command::Mount(parse_mount(args)?)),
+    }
+}
+
+fn parse_umount(args: &[String]) -> Result<Command, Errors> {
+    let (mountpoint, pidfile) = match args {
+        [mountpoint] => (PathBuf::from(mountpoint), None),
+        [mountpoint, flag, pidfile] if flag == "--pidfile" => {
+            (PathBuf::from(mountpoint), Some(PathBuf::from(pidfile)))
+        }
+        _ => return Err(Errors::InvalidArguments("expected a mountpoint".into())),
+    };
+    let pidfile = pidfile.unwrap_or_else(|| daemon::default_pidfile(&mountpoint));
+    Ok(Command::Umount {
+        mountpoint,
+        pidfile,
+    })
+}
+
+fn parse_set(args: &[String]) -> Result<Command, Errors> {
+    let [mountpoint, flag, value] = args else {
+        return Err(Errors::InvalidArguments("expected a mountpoint and a setting".into()));
+    };
+    let read_only = match (flag.as_str(), value.as_str()) {
+        ("--read-only", "on") => true,
+        ("--read-only", "off") => false,
+        _ => return Err(Errors::InvalidArguments("expected --read-only on|off".into())),
+    };
+    Ok(Command::Set {
+        mountpoint: mountpoint.into(),
+        read_only,
+    })
+}
+
+fn parse_fsck(args: &[String]) -> Result<Command, Errors> {
+    let (profile, repair) = match args {
+        [flag, profile] if flag == "--profile" => (profile, false),
+        [flag, profile, repair] | [repair, flag, profile]
+            if flag == "--profile" && repair == "--repair" =>
+        {
+            (profile, true)
+        }
+        _ => return Err(Errors::InvalidArguments("expected --profile <name>".into())),
+    };
+    let mut mount = MountArgs::new(PathBuf::new());
+    mount.apply_options(&[format!("profile={profile}")])?;
+    if mount.url.is_empty() && mount.remotes.is_empty() {
+        return Err(Errors::InvalidArguments(format!("profile {profile} has no url")));
+    }
+    // The database is checked even if the profile does not ask for one, a mount may pass
+    // state_file on the command line
+    mount.config.state_file.get_or_insert_with(PathBuf::new);
+    mount.resolve_state_file();
+    Ok(Command::Fsck { mount, repair })
+}
+
+fn parse_watch(args: &[String]) -> Result<Command, Errors> {
+    let mut profile = None;
+    let mut interval = DEFAULT_WATCH_INTERVAL;
+    let mut path = "/".to_string();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--profile" => profile = iter.next(),
+            "--interval" => {
+                let seconds = iter.next().and_then(|s| s.parse().ok()).filter(|&s| s > 0);
+                let invalid = || Errors::InvalidArguments("--interval needs seconds".into());
+                interval = Duration::from_secs(seconds.ok_or_else(invalid)?);
+            }
+            flag if flag.starts_with('-') => {
+                return Err(Errors::InvalidArguments(format!("unknown flag {flag}")))
+            }
+            _ => path = format!("/{}", arg.trim_matches('/')),
+        }
+    }
+    let Some(profile) = profile else {
+        return Err(Errors::InvalidArguments("expected --profile <name>".into()));
+    };
+    let mut mount = MountArgs::new(PathBuf::new());
+    mount.apply_options(&[format!("profile={profile}")])?;
+    if mount.url.is_empty() && mount.remotes.is_empty() {
+        return Err(Errors::InvalidArguments(format!("profile {profile} has no url")));
+    }
+    Ok(Command::Watch {
+        mount,
+        path,
+        interval,
+    })
+}
+
+fn parse_put(args: &[String]) -> Result<Command, Errors> {
+    let mut profile = None;
+    let mut recursive = false;
+    let mut paths = vec![];
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--profile" => profile = iter.next(),
+            "-r" => recursive = true,
+            flag if flag.starts_with('-') => {
+                return Err(Errors::InvalidArguments(format!("unknown flag {flag}")))
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let Some(profile) = profile else {
+        return Err(Errors::InvalidArguments("expected --profile <name>".into()));
+    };
+    let [source, target] = paths[..] else {
+        return Err(Errors::InvalidArguments("expected a local and a remote path".into()));
+    };
+    let mut mount = MountArgs::new(PathBuf::new());
+    mount.apply_options(&[format!("profile={profile}")])?;
+    if mount.url.is_empty() && mount.remotes.is_empty() {
+        return Err(Errors::InvalidArguments(format!("profile {profile} has no url")));
+    }
+    Ok(Command::Put {
+        mount,
+        source: source.into(),
+        target: format!("/{}", target.trim_matches('/')),
+        recursive,
+    })
+}
+
+/// Parses the arguments of both the regular invocation and the mount(8) helper
+fn parse_mount(args: &[String]) -> Result<MountArgs, Errors> {
+    let is_mount_helper = args
+        .first()
+        .map(PathBuf::from)
+        .and_then(|p| p.file_name().map(|n| n == "mount.webdavfs"))
+        .unwrap_or(false);
+
+    let mut positional = vec![];
+    let mut options = vec![];
+    let mut fake = false;
+    let mut remote_root = None;
+    let mut as_of = None;
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => options.push(
+                iter.next()
+                    .ok_or_else(|| Errors::InvalidArguments("-o needs options".into()))?
+                    .clone(),
+            ),
+            // sloppy, no mtab and verbose are passed by mount(8) but have no meaning here
+            "-s" | "-n" | "-v" => {}
+            "-f" => fake = true,
+            "--daemon" => options.push("daemon".into()),
+            // Kept apart from the -o options, so the path may contain commas
+            "--remote-root" => {
+                let root = iter.next();
+                let missing = || Errors::InvalidArguments("--remote-root needs a path".into());
+                remote_root = Some(root.ok_or_else(missing)?);
+            }
+            flag @ ("--uid" | "--gid" | "--file-mode" | "--dir-mode") => {
+                let value = iter.next().ok_or_else(|| {
+                    Errors::InvalidArguments(format!("{flag} needs a value"))
+                })?;
+                options.push(format!("{}={value}", flag[2..].replace('-', "_")));
+            }
+            "--as-of" => {
+                let time = iter.next();
+                let missing = || Errors::InvalidArguments("--as-of needs a time".into());
+                as_of = Some(time.ok_or_else(missing)?);
+            }
+            "-t" => {
+                iter.next();
+            }
+            flag if flag.starts_with("-o") => options.push(flag[2..].to_string()),
+            flag if flag.starts_with('-') => {
+                return Err(Errors::InvalidArguments(format!("unknown flag {flag}")))
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    let [source, mountpoint] = positional.as_slice() else {
+        return Err(Errors::InvalidArguments(
+            "expected a URL and a mountpoint".into(),
+        ));
+    };
+    let mut mount = MountArgs::new(mountpoint.into());
+    mount.fake = fake;
+    // mount(8) waits for its helper to exit
+    mount.foreground = !is_mount_helper;
+    mount.apply_options(&options)?;
+    if let Some(root) = remote_root {
+        mount.apply_option("remote_root", Some(root))?;
+    }
+    if let Some(time) = as_of {
+        mount.apply_option("as_of", Some(time))?;
+    }
+    // fstab entries that take the URL from a profile can use any placeholder as source
+    if source.contains("://") {
+        mount.url = source.clone();
+    }
+
+    if mount.url.is_empty() && mount.remotes.is_empty() {
+        return Err(Errors::InvalidArguments("no URL given".into()));
+    }
+    if mount.config.snapshot && !mount.config.read_only {
+        return Err(Errors::InvalidArguments("snapshot mounts are read-only".into()));
+    }
+    if mount.config.as_of.is_some() && !mount.config.read_only {
+        return Err(Errors::InvalidArguments("as_of mounts are read-only".into()));
+    }
+    let frozen = mount.config.snapshot || mount.config.as_of.is_some();
+    if frozen && mount.watch.is_some() {
+        return Err(Errors::InvalidArguments("snapshot and as_of mounts never change".into()));
+    }
+    let http_index = mount.http_index || mount.remotes.iter().any(|r| r.http_index);
+    if http_index && !mount.config.read_only {
+        return Err(Errors::InvalidArguments("http remotes are read-only".into()));
+    }
+    if let Some(target) = &mount.write_target {
+        if !mount.remotes.iter().any(|r| &r.name == target) {
+            let message = format!("write_target {target} is not part of the union");
+            return Err(Errors::InvalidArguments(message));
+        }
+    }
+    mount.resolve_state_file();
+    Ok(mount)
+}
+
+/// Parses the time of `as_of`: seconds since the epoch, a date or a date and time in local
+/// time, or RFC 3339. A date alone means its midnight
+fn parse_time(text: &str) -> Option<u64> {
+    if let Ok(seconds) = text.parse() {
+        return Some(seconds);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return time.timestamp().try_into().ok();
+    }
+    let local = ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .or_else(|| NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?.and_hms_opt(0, 0, 0))?;
+    let time = Local.from_local_datetime(&local).earliest()?;
+    time.timestamp().try_into().ok()
+}
+
+/// Parses octal permission bits like 0640
+fn parse_mode(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 8).ok().filter(|&mode| mode <= 0o7777)
+}
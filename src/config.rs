@@ -0,0 +1,235 @@
+use std::{borrow::Cow, ffi::OsStr, path::PathBuf, time::Duration};
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::platform;
+
+/// Mode of files if nothing else is configured, before write permission is taken away
+pub const DEFAULT_FILE_MODE: u16 = 0o644;
+
+/// Mode of directories if nothing else is configured
+pub const DEFAULT_DIR_MODE: u16 = 0o755;
+
+/// Block size reported to the kernel if nothing else is configured
+pub const DEFAULT_BLOCK_SIZE: u32 = 4096;
+
+/// How long the kernel may cache attributes and entries if nothing else is configured
+pub const DEFAULT_ATTR_TTL: Duration = Duration::from_secs(5);
+
+/// How long the kernel may remember that a name does not exist if nothing else is configured
+pub const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(1);
+
+/// Largest file that is buffered in memory for writing if nothing else is configured
+pub const DEFAULT_MAX_BUFFER_SIZE: u64 = 1 << 30;
+
+/// How long a FUSE operation may run before it is reported as hung
+pub const DEFAULT_HUNG_THRESHOLD: Duration = Duration::from_secs(30);
+
+/// Files that servers or other clients commonly leave behind and that are of no use at the
+/// mountpoint. Can be used as `Config::hidden_names`
+pub const COMMON_SERVER_JUNK: [&str; 6] = [
+    ".DS_Store",
+    ".AppleDouble",
+    "Thumbs.db",
+    "desktop.ini",
+    "lost+found",
+    ".TemporaryItems",
+];
+
+/// Files that desktop environments look for in every directory they show, and that hardly ever
+/// exist. Used as `Config::probe_names` if nothing else is configured
+pub const COMMON_PROBE_NAMES: [&str; 6] = [
+    ".localized",
+    ".hidden",
+    ".directory",
+    "desktop.ini",
+    "Desktop.ini",
+    "autorun.inf",
+];
+
+/// How entries the server marks as hidden, by a leading dot or Nextcloud's `hidden` property,
+/// are presented at the mountpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HiddenFiles {
+    /// Show them under the name the server reports
+    Show,
+    /// Leave them out of listings and lookups
+    Hide,
+    /// Show dot files without their leading dot, unless that name is taken by a sibling
+    Strip,
+}
+
+/// Unicode normalization form that names are sent to the server in. macOS clients write
+/// decomposed names (NFD), most others composed ones (NFC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    Nfc,
+    Nfd,
+}
+
+/// Settings that change how the remote is presented at the mountpoint
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Preferred I/O block size reported in `st_blksize`
+    pub block_size: u32,
+    /// Entries with one of these names are left out of listings and cannot be looked up
+    pub hidden_names: Vec<String>,
+    /// Names that are remembered as missing per directory, across remounts with a state file,
+    /// until the etag of the directory changes. Lookups of them are answered without asking
+    /// the server
+    pub probe_names: Vec<String>,
+    /// What to do with entries the server marks as hidden
+    pub hidden_files: HiddenFiles,
+    /// Resolve lookups regardless of case, while listings keep the case the server reports.
+    /// Helps applications coming from SMB or macOS that assume case-insensitive names
+    pub case_insensitive: bool,
+    /// Form that names of new entries get on the server. Lookups then match names regardless
+    /// of their form, listings keep the names the server reports. `None` leaves names as they
+    /// are
+    pub normalization: Option<Normalization>,
+    /// Database that keeps inode numbers stable across remounts. `None` hands out fresh inode
+    /// numbers on every mount
+    pub state_file: Option<PathBuf>,
+    /// Upper bound of inodes kept in memory. Unreferenced, unchanged inodes beyond it are
+    /// evicted and fetched from the server again when needed. `None` never evicts
+    pub max_inodes: Option<usize>,
+    /// Largest size in bytes a file may have while it is written to. Its whole content is kept
+    /// in memory until it is uploaded, a write or truncate beyond fails with EFBIG
+    pub max_buffer_size: u64,
+    /// Mount the filesystem read-only
+    pub read_only: bool,
+    /// Owner of every entry, the mounting user unless configured otherwise
+    pub uid: u32,
+    pub gid: u32,
+    /// Permission bits of files and directories. Write permission is taken away from entries
+    /// the server does not allow to change and on read-only mounts
+    pub file_mode: u16,
+    pub dir_mode: u16,
+    /// How long the kernel may cache attributes and directory entries
+    pub attr_ttl: Duration,
+    /// How long the kernel remembers that a name does not exist, zero disables it
+    pub negative_ttl: Duration,
+    /// Most verbose level that is logged
+    pub log_level: log::LevelFilter,
+    /// FUSE operations running longer are logged with the request they wait for. `None`
+    /// disables the check
+    pub hung_threshold: Option<Duration>,
+    /// Hung operations stop reconnecting and fail instead of waiting for the server
+    pub cancel_hung: bool,
+    /// Directories, given as paths below the mountpoint, that are listed from the server every
+    /// time they are opened. Other directories reuse listings younger than `attr_ttl`
+    pub refresh_on_open: Vec<String>,
+    /// Files below these paths, given relative to the mountpoint, are uploaded on fsync and
+    /// fsync waits for the server to acknowledge them. Elsewhere fsync returns right away and
+    /// changes are uploaded on close only, which saves uploading whole files over and over
+    pub write_barrier: Vec<String>,
+    /// Serve the tree listed at mount time and never ask the server again, so backups see a
+    /// stable view. Implies `read_only`
+    pub snapshot: bool,
+    /// Show files as they were at this time, in seconds since the epoch, reading changed files
+    /// from the versions Nextcloud keeps. Implies `read_only`
+    pub as_of: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            block_size: DEFAULT_BLOCK_SIZE,
+            hidden_names: vec![],
+            probe_names: COMMON_PROBE_NAMES.map(String::from).to_vec(),
+            hidden_files: HiddenFiles::Show,
+            case_insensitive: false,
+            normalization: None,
+            state_file: None,
+            max_inodes: None,
+            max_buffer_size: DEFAULT_MAX_BUFFER_SIZE,
+            read_only: true,
+            uid: platform::current_uid(),
+            gid: platform::current_gid(),
+            file_mode: DEFAULT_FILE_MODE,
+            dir_mode: DEFAULT_DIR_MODE,
+            attr_ttl: DEFAULT_ATTR_TTL,
+            negative_ttl: DEFAULT_NEGATIVE_TTL,
+            log_level: log::LevelFilter::Warn,
+            hung_threshold: Some(DEFAULT_HUNG_THRESHOLD),
+            cancel_hung: false,
+            refresh_on_open: vec![],
+            write_barrier: vec![],
+            snapshot: false,
+            as_of: None,
+        }
+    }
+}
+
+impl Config {
+    /// Takes over every setting of `new` that can change while mounted. Returns the names of
+    /// the settings that differ but only take effect on the next mount
+    pub fn reload(&mut self, new: Config) -> Vec<&'static str> {
+        let mut ignored = vec![];
+        if new.block_size != self.block_size {
+            ignored.push("blksize");
+        }
+        if new.state_file != self.state_file {
+            ignored.push("state_file");
+        }
+        if new.snapshot != self.snapshot {
+            ignored.push("snapshot");
+        }
+        if new.as_of != self.as_of {
+            ignored.push("as_of");
+        }
+        // Names of known inodes would change under the feet of the kernel
+        if new.hidden_files != self.hidden_files {
+            ignored.push("hidden");
+        }
+
+        // Snapshots and views of the past stay read-only whatever the configuration says
+        self.read_only = new.read_only || self.snapshot || self.as_of.is_some();
+        self.uid = new.uid;
+        self.gid = new.gid;
+        self.file_mode = new.file_mode;
+        self.dir_mode = new.dir_mode;
+        self.hidden_names = new.hidden_names;
+        self.probe_names = new.probe_names;
+        self.case_insensitive = new.case_insensitive;
+        self.normalization = new.normalization;
+        self.max_inodes = new.max_inodes;
+        self.max_buffer_size = new.max_buffer_size;
+        self.attr_ttl = new.attr_ttl;
+        self.negative_ttl = new.negative_ttl;
+        self.log_level = new.log_level;
+        self.hung_threshold = new.hung_threshold;
+        self.cancel_hung = new.cancel_hung;
+        self.refresh_on_open = new.refresh_on_open;
+        self.write_barrier = new.write_barrier;
+        ignored
+    }
+
+    /// Whether an entry called `name` is hidden from the mountpoint
+    pub fn is_hidden(&self, name: &OsStr) -> bool {
+        self.hidden_names.iter().any(|hidden| OsStr::new(hidden) == name)
+    }
+
+    /// `name` in the form configured by `normalization`, as it is sent to the server
+    pub fn normalize<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match self.normalization {
+            None => Cow::Borrowed(name),
+            Some(Normalization::Nfc) => Cow::Owned(name.nfc().collect()),
+            Some(Normalization::Nfd) => Cow::Owned(name.nfd().collect()),
+        }
+    }
+
+    /// What lookups compare names by when they have no exact match
+    pub fn lookup_key(&self, name: &str) -> String {
+        let name = self.normalize(name);
+        match self.case_insensitive {
+            true => name.to_lowercase(),
+            false => name.into_owned(),
+        }
+    }
+
+    /// Whether a missing entry called `name` is remembered as missing
+    pub fn is_probe_name(&self, name: &OsStr) -> bool {
+        self.probe_names.iter().any(|probe| OsStr::new(probe) == name)
+    }
+}
@@ -0,0 +1,33 @@
+/// Marker Nextcloud clients put into the names of conflicted copies, e.g.
+/// "a (conflicted copy 2023-04-01 120000).txt"
+const NEXTCLOUD_MARKER: &str = " (conflicted copy ";
+/// Marker ownCloud clients put into the names of conflicted copies, followed by the date and
+/// time, e.g. "a_conflict-20230401-120000.txt"
+const OWNCLOUD_MARKER: &str = "_conflict-";
+/// Length of the ownCloud timestamp "YYYYMMDD-HHMMSS"
+const OWNCLOUD_TIMESTAMP_LEN: usize = 15;
+
+/// Name of the file a conflicted copy called `name` was made of. `None` if `name` is no
+/// conflicted copy
+pub fn original_name(name: &str) -> Option<String> {
+    if let Some(start) = name.find(NEXTCLOUD_MARKER) {
+        let end = start + name[start..].find(')')?;
+        return Some(format!("{}{}", &name[..start], &name[end + 1..]));
+    }
+    let start = name.find(OWNCLOUD_MARKER)?;
+    let end = start + OWNCLOUD_MARKER.len() + OWNCLOUD_TIMESTAMP_LEN;
+    let timestamp = name.get(start + OWNCLOUD_MARKER.len()..end)?;
+    let is_timestamp = timestamp
+        .bytes()
+        .enumerate()
+        .all(|(idx, byte)| if idx == 8 { byte == b'-' } else { byte.is_ascii_digit() });
+    is_timestamp.then(|| format!("{}{}", &name[..start], &name[end..]))
+}
+
+/// Name of the entry of the conflict inbox for the conflicted copy at `path`. Slashes cannot
+/// be part of a name, so they are escaped together with the escape character
+pub fn entry_name(path: &str) -> String {
+    path.trim_start_matches('/')
+        .replace('%', "%25")
+        .replace('/', "%2F")
+}
@@ -0,0 +1,256 @@
+use std::{
+    ffi::OsStr,
+    fs::File,
+    os::fd::AsRawFd,
+    path::Path,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use crate::{errors::Errors, platform, state::EtagRecord, xattr::HISTORY_XATTR};
+
+/// Name of the synthetic directory at the root of the mount. Writing a path into one of the
+/// files inside of it triggers the corresponding action on that path
+pub const CONTROL_DIR_NAME: &str = ".webdavfs";
+
+/// Inode of the control directory. The entries inside of it use the inodes that follow.
+/// They are taken from the very top of the range so they never collide with remote files
+pub const CONTROL_DIR_INODE: u64 = u64::MAX - 64;
+
+/// Name of the read-only file in the control directory that shows the counters of `metrics`
+pub const METRICS_FILE_NAME: &str = "metrics";
+
+/// Inode of the metrics file, after the ones of the command files
+pub const METRICS_INODE: u64 = CONTROL_DIR_INODE + 32;
+
+/// Name of the directory in the control directory that holds every known conflicted copy
+pub const CONFLICTS_DIR_NAME: &str = "conflicts";
+
+/// Inode of the conflict inbox. Its entries are the inodes of the conflicted copies themselves
+pub const CONFLICTS_DIR_INODE: u64 = CONTROL_DIR_INODE + 33;
+
+/// Name of the read-only file in the control directory that dumps the inode table as JSON
+pub const INODES_FILE_NAME: &str = "inodes";
+
+/// Inode of the inode table dump
+pub const INODES_INODE: u64 = CONTROL_DIR_INODE + 34;
+
+/// Name of the read-only file in the control directory that reports the last recursive delete
+pub const DELETE_REPORT_FILE_NAME: &str = "delete-report";
+
+/// Inode of the report of the last recursive delete
+pub const DELETE_REPORT_INODE: u64 = CONTROL_DIR_INODE + 35;
+
+/// How often `delete_tree` looks at the report while the mount deletes entry by entry
+const DELETE_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Name of the directory in the control directory that holds scratch files, see
+/// `scratch::ScratchDir`
+pub const SCRATCH_DIR_NAME: &str = "tmp";
+
+/// Inode of the scratch directory
+pub const SCRATCH_DIR_INODE: u64 = CONTROL_DIR_INODE + 36;
+
+/// Type byte of our ioctl numbers
+const IOCTL_TYPE: u32 = b'W' as u32;
+
+/// ioctl without argument, `_IO('W', nr)`
+#[cfg(target_os = "linux")]
+const fn io(nr: u32) -> u32 {
+    (IOCTL_TYPE << 8) | nr
+}
+#[cfg(not(target_os = "linux"))]
+const fn io(nr: u32) -> u32 {
+    0x2000_0000 | (IOCTL_TYPE << 8) | nr
+}
+
+/// ioctl returning an u32, `_IOR('W', nr, u32)`
+#[cfg(target_os = "linux")]
+const fn ior_u32(nr: u32) -> u32 {
+    (2 << 30) | (4 << 16) | (IOCTL_TYPE << 8) | nr
+}
+#[cfg(not(target_os = "linux"))]
+const fn ior_u32(nr: u32) -> u32 {
+    0x4000_0000 | (4 << 16) | (IOCTL_TYPE << 8) | nr
+}
+
+/// ioctl returning the state flags of the file it is issued on
+pub const IOCTL_GET_STATE: u32 = ior_u32(6);
+/// ioctl making the whole mount read-only, issued on any file of it
+pub const IOCTL_SET_READ_ONLY: u32 = io(7);
+/// ioctl making the whole mount writable again
+pub const IOCTL_SET_READ_WRITE: u32 = io(8);
+/// State flag: the metadata is pinned in memory
+pub const STATE_PINNED: u32 = 1;
+/// State flag: the directory listing is cached
+pub const STATE_LISTED: u32 = 1 << 1;
+/// State flag: the file has local changes that are not on the server yet
+pub const STATE_DIRTY: u32 = 1 << 2;
+
+/// Actions that can be triggered through the files of the control directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Fetch a fresh listing of the directory (or the parent of the file) from the server
+    Refresh,
+    /// Keep the metadata of a path and everything below it in memory
+    Pin,
+    /// Undo `Pin`, the cached metadata stays until it is evicted
+    Unpin,
+    /// Undo `Pin` and forget the cached listing, so it is fetched again on next access
+    Evict,
+    /// Refresh every known directory below the path
+    SyncNow,
+    /// Delete the path with everything below it on the server
+    Delete,
+}
+
+impl ControlCommand {
+    pub const ALL: [ControlCommand; 6] = [
+        ControlCommand::Refresh,
+        ControlCommand::Pin,
+        ControlCommand::Unpin,
+        ControlCommand::Evict,
+        ControlCommand::SyncNow,
+        ControlCommand::Delete,
+    ];
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            ControlCommand::Refresh => "refresh",
+            ControlCommand::Pin => "pin",
+            ControlCommand::Unpin => "unpin",
+            ControlCommand::Evict => "evict",
+            ControlCommand::SyncNow => "sync-now",
+            ControlCommand::Delete => "delete",
+        }
+    }
+
+    pub fn inode(&self) -> u64 {
+        let idx = Self::ALL.iter().position(|c| c == self).unwrap_or_default();
+        CONTROL_DIR_INODE + 1 + idx as u64
+    }
+
+    pub fn from_inode(inode: u64) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.inode() == inode)
+    }
+
+    /// Number of the ioctl that runs the command on the file it is issued on
+    pub fn ioctl(&self) -> u32 {
+        match self {
+            ControlCommand::Pin => io(1),
+            ControlCommand::Unpin => io(2),
+            ControlCommand::Evict => io(3),
+            ControlCommand::Refresh => io(4),
+            ControlCommand::SyncNow => io(5),
+            ControlCommand::Delete => io(9),
+        }
+    }
+
+    pub fn from_ioctl(cmd: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.ioctl() == cmd)
+    }
+
+    pub fn from_file_name(name: &OsStr) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|c| OsStr::new(c.file_name()) == name)
+    }
+}
+
+/// Splits what was written to a command file into the paths it names, one per line.
+/// Paths are relative to the mountpoint, a leading slash is optional
+pub fn parse_paths(data: &[u8]) -> Option<Vec<String>> {
+    let text = std::str::from_utf8(data).ok()?;
+    Some(
+        text.lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .filter(|line| !line.is_empty())
+            .map(|line| format!("/{}", line.trim_start_matches('/')))
+            .collect(),
+    )
+}
+
+/// The etags a mount has seen for the file at `path`, oldest first
+pub fn read_history(path: &Path) -> Result<Vec<EtagRecord>, Errors> {
+    let failed = |err: String| Errors::ControlRequestFailed(format!("{}: {}", path.display(), err));
+    let value = platform::getxattr(path, HISTORY_XATTR).map_err(|err| failed(err.to_string()))?;
+    let entries: Vec<serde_json::Value> =
+        serde_json::from_slice(&value).map_err(|err| failed(err.to_string()))?;
+    Ok(entries
+        .iter()
+        .map(|entry| EtagRecord {
+            etag: entry["etag"].as_str().unwrap_or_default().to_string(),
+            mtime: entry["mtime"].as_u64().unwrap_or_default(),
+            seen_at: entry["seen_at"].as_u64().unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// Deletes `path` with everything below it through the mount it belongs to. Returns the
+/// report of the mount as JSON, which lists the entries that could not be deleted. While the
+/// mount deletes entry by entry, `progress` gets the report every `DELETE_PROGRESS_INTERVAL`
+pub fn delete_tree(
+    path: &Path,
+    mut progress: impl FnMut(&serde_json::Value),
+) -> Result<serde_json::Value, Errors> {
+    let failed = |err: String| Errors::ControlRequestFailed(format!("{}: {}", path.display(), err));
+    // Resolved before the path is gone
+    let absolute = path.canonicalize().map_err(|err| failed(err.to_string()))?;
+    // The report lies in the control directory at the root of the mount
+    let report_file = absolute
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.join(CONTROL_DIR_NAME).join(DELETE_REPORT_FILE_NAME))
+        .find(|report| report.exists())
+        .ok_or_else(|| failed("not inside of a mount".into()))?;
+    let read_report = || -> Result<serde_json::Value, Errors> {
+        let report = std::fs::read(&report_file).map_err(|err| failed(err.to_string()))?;
+        serde_json::from_slice(&report).map_err(|err| failed(err.to_string()))
+    };
+    // The report of another delete may still be there until the mount starts on this one
+    let reports_path = |report: &serde_json::Value| {
+        let mount_path = report["path"].as_str().unwrap_or_default().trim_matches('/');
+        !mount_path.is_empty() && absolute.ends_with(mount_path)
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let target = absolute.clone();
+    thread::spawn(move || {
+        let _ = sender.send(issue_ioctl(&target, ControlCommand::Delete.ioctl()));
+    });
+    let result = loop {
+        match receiver.recv_timeout(DELETE_PROGRESS_INTERVAL) {
+            Ok(result) => break result,
+            Err(RecvTimeoutError::Disconnected) => {
+                break Err(failed("the ioctl did not return".into()))
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        if let Ok(report) = read_report() {
+            if reports_path(&report) && report["finished"] == false {
+                progress(&report);
+            }
+        }
+    };
+    let report = read_report()?;
+    // A delete that failed partially reports what is left, other failures leave no report
+    match result {
+        Err(err) if !reports_path(&report) => Err(err),
+        _ => Ok(report),
+    }
+}
+
+/// Issues the ioctl `cmd` without argument on `path`, which has to be inside of a mount
+pub fn issue_ioctl(path: &Path, cmd: u32) -> Result<(), Errors> {
+    let failed = |err: std::io::Error| {
+        Errors::ControlRequestFailed(format!("{}: {}", path.display(), err))
+    };
+    let file = File::open(path).map_err(failed)?;
+    // SAFETY: the descriptor is open for the duration of the call and the command takes no
+    // argument
+    match unsafe { libc::ioctl(file.as_raw_fd(), cmd as _) } {
+        0.. => Ok(()),
+        _ => Err(failed(std::io::Error::last_os_error())),
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    os::unix::io::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::Duration,
+};
+
+use crate::{errors::Errors, platform::UNMOUNT_COMMAND, systemd::escape_path};
+
+/// Child side of a daemonized process. The parent waits until `ready` or `exit` is reported
+/// and then exits with the matching status, so callers like mount(8) learn whether mounting
+/// worked
+pub struct Daemon {
+    status: File,
+}
+
+/// Forks into the background and detaches from the terminal's session. Only the child
+/// returns. Must be called before any thread is started
+pub fn daemonize() -> Daemon {
+    let mut fds = [0; 2];
+    // SAFETY: plain libc calls, no threads are running yet
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            eprintln!("failed to create status pipe");
+            std::process::exit(1);
+        }
+        match libc::fork() {
+            -1 => {
+                eprintln!("failed to fork into the background");
+                std::process::exit(1);
+            }
+            0 => {
+                libc::close(fds[0]);
+                libc::setsid();
+                Daemon {
+                    status: File::from_raw_fd(fds[1]),
+                }
+            }
+            _ => {
+                libc::close(fds[1]);
+                let mut status = File::from_raw_fd(fds[0]);
+                let mut byte = [0u8];
+                // EOF means the child died before reporting anything
+                let code = match status.read(&mut byte) {
+                    Ok(1) => byte[0] as i32,
+                    _ => 1,
+                };
+                std::process::exit(code);
+            }
+        }
+    }
+}
+
+impl Daemon {
+    /// Called once mounted: writes the pidfile, sends the output to the log file and lets the
+    /// parent exit successfully
+    pub fn ready(mut self, pidfile: &Path, log_file: Option<&Path>) -> Result<(), Errors> {
+        if let Some(dir) = pidfile.parent() {
+            std::fs::create_dir_all(dir).map_err(io_error)?;
+        }
+        std::fs::write(pidfile, format!("{}\n", std::process::id())).map_err(io_error)?;
+
+        let log = match log_file {
+            Some(file) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file)
+                .map_err(io_error)?,
+            None => OpenOptions::new()
+                .write(true)
+                .open("/dev/null")
+                .map_err(io_error)?,
+        };
+        let null = File::open("/dev/null").map_err(io_error)?;
+        // SAFETY: replaces the standard descriptors with ones that stay open for the lifetime
+        // of the process
+        unsafe {
+            libc::dup2(null.as_raw_fd(), 0);
+            libc::dup2(log.as_raw_fd(), 1);
+            libc::dup2(log.as_raw_fd(), 2);
+        }
+        self.status.write_all(&[0]).map_err(io_error)
+    }
+
+    /// Lets the parent exit with `code`, because the child is about to fail
+    pub fn exit(mut self, code: i32) {
+        let _ = self.status.write_all(&[code as u8]);
+    }
+}
+
+/// Where the pidfile of the mount at `mountpoint` lives unless configured otherwise
+pub fn default_pidfile(mountpoint: &Path) -> PathBuf {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/run"));
+    let mountpoint = mountpoint.canonicalize().unwrap_or(mountpoint.to_path_buf());
+    runtime_dir
+        .join("rust_webdav")
+        .join(format!("{}.pid", escape_path(&mountpoint)))
+}
+
+/// Unmounts `mountpoint` and waits for the daemon serving it to exit
+pub fn umount(mountpoint: &Path, pidfile: &Path) -> Result<(), Errors> {
+    let (command, args) = UNMOUNT_COMMAND;
+    let status = Command::new(command)
+        .args(args)
+        .arg(mountpoint)
+        .status()
+        .map_err(io_error)?;
+    if !status.success() {
+        return Err(Errors::InvalidArguments(format!(
+            "{} could not unmount {}",
+            command,
+            mountpoint.display()
+        )));
+    }
+
+    let pid: Option<libc::pid_t> = std::fs::read_to_string(pidfile)
+        .ok()
+        .and_then(|p| p.trim().parse().ok());
+    if let Some(pid) = pid {
+        // The session ends with the unmount, give the daemon time to shut down cleanly
+        // SAFETY: signal 0 only checks whether the process exists
+        for _ in 0..50 {
+            if unsafe { libc::kill(pid, 0) } != 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+    let _ = std::fs::remove_file(pidfile);
+    Ok(())
+}
+
+fn io_error(err: std::io::Error) -> Errors {
+    Errors::InvalidArguments(err.to_string())
+}
@@ -0,0 +1,215 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use crate::{errors::Errors, remotes::Remotes};
+
+/// Threads deleting the entries of a tree if nothing else is configured
+pub const DEFAULT_DELETE_THREADS: usize = 4;
+
+/// Outcome of the last recursive delete as JSON, `null` before the first one. Shared with the
+/// thread running a delete, so `.webdavfs/delete-report` shows its progress
+pub type DeleteReport = Arc<Mutex<serde_json::Value>>;
+
+/// The answer of a server to a DELETE of a whole collection that only says it does not delete
+/// collections that way, so deleting entry by entry may still work
+pub fn is_unsupported(err: &Errors) -> bool {
+    matches!(err, Errors::HttpStatus(405 | 501) | Errors::InsufficientStorage(_))
+}
+
+/// A delete of a single entry, handed to the workers
+struct Job {
+    index: usize,
+    path: String,
+    done: Sender<(usize, Result<(), Errors>)>,
+}
+
+/// Workers deleting the entries of one depth level of a tree at the same time
+pub struct Deletes {
+    sender: Sender<Job>,
+}
+
+impl Deletes {
+    /// Starts `threads` workers, each with remotes of its own from `connect`, since a connection
+    /// serves one request at a time. They end once `Deletes` is dropped
+    pub fn spawn(threads: usize, connect: impl Fn() -> Remotes) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+            let remotes = connect();
+            thread::spawn(move || loop {
+                // The lock is held while waiting for a job, not while running it
+                let next = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                let Ok(job) = next else {
+                    return;
+                };
+                let result = remotes.delete(&job.path);
+                let _ = job.done.send((job.index, result));
+            });
+        }
+        Self { sender }
+    }
+
+    /// Deletes all of `paths` on the workers and waits for them. The results are in the order
+    /// of `paths`
+    pub fn delete_all(&self, paths: &[String]) -> Vec<Result<(), Errors>> {
+        let (done, finished) = mpsc::channel();
+        let mut results: Vec<Result<(), Errors>> = paths
+            .iter()
+            .map(|_| Err(Errors::ControlRequestFailed("delete workers are gone".into())))
+            .collect();
+        for (index, path) in paths.iter().enumerate() {
+            let job = Job {
+                index,
+                path: path.clone(),
+                done: done.clone(),
+            };
+            if self.sender.send(job).is_err() {
+                return results;
+            }
+        }
+        drop(done);
+        for (index, result) in finished {
+            results[index] = result;
+        }
+        results
+    }
+}
+
+/// Deletes `entries` of the tree at `path` one depth level at a time, deepest first, so the
+/// content of a directory is gone when its turn comes. `delete` removes the entries of one
+/// level. Directories above an entry that failed are left. `report` is updated after every
+/// level and holds the result of every entry in the end. Returns how many entries failed
+pub fn delete_levels(
+    path: &str,
+    entries: Vec<String>,
+    report: &DeleteReport,
+    mut delete: impl FnMut(&[String]) -> Vec<Result<(), Errors>>,
+) -> usize {
+    let total = entries.len();
+    let mut levels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    for entry in entries {
+        levels.entry(entry.matches('/').count()).or_default().push(entry);
+    }
+
+    let mut results: Vec<serde_json::Value> = vec![];
+    let mut failed: Vec<String> = vec![];
+    let update = |results: &[serde_json::Value], failed: &[String], finished: bool| {
+        let failures: Vec<&serde_json::Value> =
+            results.iter().filter(|result| result["ok"] != true).collect();
+        *report.lock().unwrap_or_else(|e| e.into_inner()) = serde_json::json!({
+            "path": path,
+            "total": total,
+            "done": results.len(),
+            "deleted": results.len() - failed.len(),
+            "finished": finished,
+            "failed": failures,
+            "results": results,
+        });
+    };
+    update(&results, &failed, false);
+    for level in levels.into_values().rev() {
+        // A directory with an entry that is left cannot be deleted either
+        let (left, pending): (Vec<String>, Vec<String>) = level
+            .into_iter()
+            .partition(|entry| failed.iter().any(|f| crate::path::is_below(f, entry)));
+        for (entry, result) in pending.iter().zip(delete(&pending)) {
+            if result.is_err() {
+                failed.push(entry.clone());
+            }
+            results.push(delete_result(entry, result.map_err(Some)));
+        }
+        for entry in left {
+            results.push(delete_result(&entry, Err(None)));
+            failed.push(entry);
+        }
+        info!("Deleted {} of {} entries below {}", results.len(), total, path);
+        update(&results, &failed, false);
+    }
+    update(&results, &failed, true);
+    failed.len()
+}
+
+/// The outcome of one entry of a batch for its report: the error kind and HTTP status of a
+/// failure, next to the error itself
+pub fn item_result(path: &str, result: Result<(), Errors>) -> serde_json::Value {
+    let err = match result {
+        Ok(()) => return serde_json::json!({ "path": path, "ok": true }),
+        Err(err) => err,
+    };
+    let (kind, status) = match &err {
+        Errors::HttpStatus(403) => ("forbidden", Some(403)),
+        Errors::HttpStatus(404) => ("not_found", Some(404)),
+        Errors::HttpStatus(423) => ("locked", Some(423)),
+        Errors::HttpStatus(status) => ("http", Some(*status)),
+        Errors::InsufficientStorage(_) => ("insufficient_storage", None),
+        Errors::ServerUnreachable(_) => ("unreachable", None),
+        Errors::AuthenticationFailed => ("auth_failed", None),
+        Errors::LocalFileError(_) => ("local", None),
+        _ => ("other", None),
+    };
+    serde_json::json!({
+        "path": path,
+        "ok": false,
+        "error": kind,
+        "status": status,
+        "message": format!("{:?}", err),
+    })
+}
+
+/// The outcome of deleting one entry of a tree for the delete report. No error means the entry
+/// was left because something below it could not be deleted
+pub fn delete_result(path: &str, result: Result<(), Option<Errors>>) -> serde_json::Value {
+    match result {
+        Ok(()) => item_result(path, Ok(())),
+        Err(Some(err)) => item_result(path, Err(err)),
+        Err(None) => serde_json::json!({
+            "path": path,
+            "ok": false,
+            "error": "not_empty",
+            "status": null,
+            "message": "a remaining entry is below it",
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delete_results_carry_kind_and_status() {
+        let locked = delete_result("/a/b.txt", Err(Some(Errors::HttpStatus(423))));
+        assert_eq!(locked["error"], "locked");
+        assert_eq!(locked["status"], 423);
+        let left = delete_result("/a", Err(None));
+        assert_eq!(left["error"], "not_empty");
+        assert!(left["status"].is_null());
+        assert_eq!(delete_result("/c", Ok(()))["ok"], true);
+        let unreadable = item_result("/d.txt", Err(Errors::LocalFileError("d.txt".into())));
+        assert_eq!(unreadable["error"], "local");
+    }
+
+    #[test]
+    fn levels_are_deleted_deepest_first() {
+        let entries = ["/d", "/d/e", "/d/e/x.txt", "/d/y.txt"].map(String::from).to_vec();
+        let report = DeleteReport::default();
+        let mut levels = vec![];
+        let failed = delete_levels("/d", entries, &report, |level| {
+            levels.push(level.to_vec());
+            level.iter().map(|_| Ok(())).collect()
+        });
+        assert_eq!(failed, 0);
+        let levels: Vec<usize> = levels.iter().map(|level| level.len()).collect();
+        assert_eq!(levels, vec![1, 2, 1]);
+        let report = report.lock().unwrap();
+        assert_eq!(report["deleted"], 4);
+        assert_eq!(report["finished"], true);
+    }
+}
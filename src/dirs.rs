@@ -0,0 +1,75 @@
+use std::path::{Path, PathBuf};
+
+/// Name of the subdirectory used inside of the XDG base directories
+const APP_NAME: &str = "rust_webdav";
+
+/// Whether the process runs as root. fstab mounts run as root and have no meaningful home
+/// directory, so system wide locations are used instead
+fn is_root() -> bool {
+    // SAFETY: geteuid cannot fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Resolves an XDG base directory: the absolute value of `variable`, otherwise `fallback`
+/// below the home directory
+fn base_dir(variable: &str, fallback: &str) -> Option<PathBuf> {
+    std::env::var_os(variable)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(fallback)))
+}
+
+/// Configuration file holding the profiles, `$XDG_CONFIG_HOME/rust_webdav/config`
+pub fn config_file() -> PathBuf {
+    match base_dir("XDG_CONFIG_HOME", ".config") {
+        Some(dir) if !is_root() => dir.join(APP_NAME).join("config"),
+        _ => PathBuf::from("/etc/rust_webdav.conf"),
+    }
+}
+
+/// Directory for data that can be fetched from the server again, `$XDG_CACHE_HOME/rust_webdav`
+pub fn cache_dir() -> PathBuf {
+    match base_dir("XDG_CACHE_HOME", ".cache") {
+        Some(dir) if !is_root() => dir.join(APP_NAME),
+        _ => PathBuf::from("/var/cache").join(APP_NAME),
+    }
+}
+
+/// Directory for state that should survive remounts, like the inode database,
+/// `$XDG_STATE_HOME/rust_webdav`
+pub fn state_dir() -> PathBuf {
+    match base_dir("XDG_STATE_HOME", ".local/state") {
+        Some(dir) if !is_root() => dir.join(APP_NAME),
+        _ => PathBuf::from("/var/lib").join(APP_NAME),
+    }
+}
+
+/// Name of the subdirectory holding the cache and state of one remote. Derived from the URL,
+/// `https://cloud.example/dav/files/me` becomes `cloud.example-dav-files-me`
+fn remote_dir_name(url: &str) -> String {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    without_scheme
+        .trim_matches('/')
+        .chars()
+        .map(|c| match c {
+            '/' => '-',
+            c if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Cache directory of the remote at `url`
+pub fn remote_cache_dir(url: &str) -> PathBuf {
+    cache_dir().join(remote_dir_name(url))
+}
+
+/// State directory of the remote at `url`
+pub fn remote_state_dir(url: &str) -> PathBuf {
+    state_dir().join(remote_dir_name(url))
+}
+
+/// Inode database used by a bare `state_file` option
+pub fn default_state_file(url: &str) -> PathBuf {
+    remote_state_dir(url).join("inodes")
+}
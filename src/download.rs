@@ -0,0 +1,78 @@
+use std::{
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use fuser::ReplyData;
+
+use crate::{inflight, remotes::Remotes, webdav::Version};
+
+/// Threads reading from the server if nothing else is configured
+pub const DEFAULT_DOWNLOAD_THREADS: usize = 4;
+
+/// A read of file content that the server has to answer
+pub struct Download {
+    pub inode: u64,
+    /// Process that issued the read
+    pub pid: u32,
+    pub path: String,
+    /// Earlier version read instead of the current content
+    pub version: Option<Version>,
+    pub offset: u64,
+    pub size: u64,
+    pub reply: ReplyData,
+}
+
+impl Download {
+    /// Fetches the data from `remotes` and replies with it
+    pub fn run(self, remotes: &Remotes) {
+        let data = match &self.version {
+            Some(version) => remotes.get_version_range(&self.path, version, self.offset, self.size),
+            None => remotes.get_range(&self.path, self.offset, self.size),
+        };
+        match data {
+            Ok(data) => self.reply.data(&data),
+            Err(err) => {
+                warn!("Reading inode {} failed: {:?}", self.inode, err);
+                self.reply.error(err.into())
+            }
+        }
+    }
+}
+
+/// Workers answering reads from the server while the FUSE session goes on with other
+/// operations. fuser dispatches from a single thread, but a reply can be sent from any thread
+pub struct Downloads {
+    sender: Sender<Download>,
+}
+
+impl Downloads {
+    /// Starts `threads` workers, each with remotes of its own from `connect`, so a slow download
+    /// holds up neither the session nor the other workers. They end once `Downloads` is dropped
+    pub fn spawn(threads: usize, connect: impl Fn() -> Remotes) -> Self {
+        let (sender, receiver) = mpsc::channel::<Download>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+            let remotes = connect();
+            thread::spawn(move || loop {
+                // The lock is held while waiting for a download, not while running it
+                let next = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                let Ok(download) = next else {
+                    return;
+                };
+                let _op = inflight::track("read", download.inode, download.pid);
+                download.run(&remotes);
+            });
+        }
+        Self { sender }
+    }
+
+    /// Queues `download` for the workers, or hands it back if they are gone
+    pub fn start(&self, download: Download) -> Result<(), Download> {
+        self.sender.send(download).map_err(|err| err.0)
+    }
+}
@@ -1,11 +1,16 @@
 use std::ffi::OsString;
 
-use crate::filesystem::InodeId;
+use crate::{
+    filesystem::{FileHandleId, InodeId},
+    platform,
+};
 
 #[derive(Debug, Clone)]
 pub enum Errors {
     /// The reqeust from the server errored out
     WebDavReqeustFailed,
+    /// The server answered with an error status
+    HttpStatus(u16),
     /// The size of a prop that was returned is nonsense
     PropSizeError,
     /// The xml cannot be parsed. This happens when a response is malformed
@@ -19,6 +24,199 @@ pub enum Errors {
     ChildInodeNotFound(InodeId),
     FileEntryMissing(InodeId),
     FileDoesNotExist(OsString),
+    /// A file with the name to create exists already
+    FileExists(OsString),
+    /// The kernel used a file handle that was never handed out or is released already
+    FileHandleNotFound(FileHandleId),
+    /// Buffered changes of the file would not fit into the quota left on the server, or the
+    /// server refused them for exceeding the quota of the user
+    QuotaExceeded(InodeId),
+    /// The server has no storage left for an upload, contains the message it gave
+    InsufficientStorage(String),
+    /// The file would grow beyond `Config::max_buffer_size`
+    FileTooLarge(InodeId),
+    /// rmdir on a directory that still has children
+    DirectoryNotEmpty(InodeId),
+    /// rmdir on a file
+    NotADirectory(InodeId),
+    /// unlink on a directory
+    IsADirectory(InodeId),
+    /// write on a handle that was opened read-only
+    HandleNotWritable(FileHandleId),
+    /// readlink on an inode that is no emulated symlink
+    NotASymlink(InodeId),
+    /// The requested extended attribute is not set on the file
+    XattrNotFound(OsString),
+    /// No namespace is registered for the name of the extended attribute
+    XattrNamespaceUnsupported(OsString),
+    /// The extended attribute cannot be changed
+    XattrReadOnly(OsString),
+    /// The name or value of the extended attribute cannot be stored as a WebDAV property
+    XattrInvalid(OsString),
 
     NonUnicodeInPath(OsString),
+    /// Reading or writing the state database failed
+    StateIoError(std::io::ErrorKind),
+    /// A href returned by the server does not decode to valid UTF-8
+    HrefDecodeError(String),
+    /// A href returned by the server lies outside of the root of the drive
+    HrefOutsideRoot(String),
+    /// The command line or mount options are not valid
+    InvalidArguments(String),
+    /// The configuration file cannot be read or parsed
+    ConfigFileError(String),
+    /// The kernel does not provide FUSE
+    FuseUnavailable(String),
+    /// The server rejected the credentials
+    AuthenticationFailed,
+    /// The server could not be reached, even after reconnecting
+    ServerUnreachable(String),
+    /// Something is already mounted at the mountpoint or it is in use
+    MountpointBusy(String),
+    /// Mounting failed for another reason
+    MountFailed(String),
+    /// A directory index of a plain HTTP remote cannot be parsed
+    IndexParseError(String),
+    /// copy_file_range asked for a partial copy, which the server cannot do on its own
+    ServerCopyUnsupported,
+    /// A recursive delete left entries behind, contains how many
+    PartiallyDeleted(usize),
+    /// Another recursive delete is still going on in the background, contains its path
+    DeleteInProgress(String),
+    /// A local file or directory to upload cannot be read
+    LocalFileError(String),
+    /// Source and destination of a rename or copy belong to different remotes
+    MoveAcrossRemotes(String),
+    /// The backend of the remote cannot store changes
+    ReadOnlyBackend(String),
+    /// A running mount did not accept a request sent through its control interface
+    ControlRequestFailed(String),
+    /// The process waiting for the operation is being killed, its request was abandoned
+    Interrupted,
+    /// An entry of a listing came without a resource type, contains its path
+    InvalidResourceType(String),
+}
+
+impl Errors {
+    /// Exit status of the process when failing with this error. The values are part of the
+    /// interface and must not change, scripts and systemd units depend on them
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Errors::InvalidArguments(_) | Errors::ConfigFileError(_) => 2,
+            Errors::AuthenticationFailed => 3,
+            Errors::ServerUnreachable(_) => 4,
+            Errors::MountpointBusy(_) => 5,
+            Errors::FuseUnavailable(_) => 6,
+            Errors::HttpStatus(403) => 7,
+            _ => 1,
+        }
+    }
+
+    /// Stable identifier of the exit code, used by `--error-format json`
+    pub fn kind(&self) -> &'static str {
+        match self.exit_code() {
+            2 => "config",
+            3 => "auth_failed",
+            4 => "unreachable",
+            5 => "mountpoint_busy",
+            6 => "fuse_unavailable",
+            7 => "forbidden",
+            _ => "other",
+        }
+    }
+
+    /// The error as a single line JSON object for `--error-format json`
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "error": self.kind(),
+            "code": self.exit_code(),
+            "message": format!("{:?}", self),
+        })
+        .to_string()
+    }
+}
+
+/// The errno a FUSE operation that failed with the error answers the kernel with. Handlers
+/// match the errors that mean something else for their operation first
+impl From<Errors> for libc::c_int {
+    fn from(err: Errors) -> Self {
+        match err {
+            Errors::FileDoesNotExist(_)
+            | Errors::InodeNotFound(_)
+            | Errors::ParentInodeNotFound(_)
+            | Errors::ChildInodeNotFound(_)
+            | Errors::FileEntryMissing(_)
+            | Errors::HttpStatus(404) => libc::ENOENT,
+            Errors::FileExists(_) | Errors::HttpStatus(412) => libc::EEXIST,
+            Errors::FileHandleNotFound(_) | Errors::HandleNotWritable(_) => libc::EBADF,
+            Errors::QuotaExceeded(_) => libc::EDQUOT,
+            Errors::InsufficientStorage(_) | Errors::HttpStatus(507) => libc::ENOSPC,
+            Errors::FileTooLarge(_) => libc::EFBIG,
+            Errors::DirectoryNotEmpty(_) => libc::ENOTEMPTY,
+            Errors::NotADirectory(_) => libc::ENOTDIR,
+            Errors::IsADirectory(_) => libc::EISDIR,
+            Errors::NotASymlink(_)
+            | Errors::XattrInvalid(_)
+            | Errors::NonUnicodeInPath(_)
+            | Errors::InvalidArguments(_) => libc::EINVAL,
+            Errors::XattrNotFound(_) => platform::ENOATTR,
+            Errors::XattrNamespaceUnsupported(_) => libc::ENOTSUP,
+            Errors::XattrReadOnly(_) => libc::EPERM,
+            Errors::ReadOnlyBackend(_) => libc::EROFS,
+            Errors::AuthenticationFailed | Errors::HttpStatus(401 | 403) => libc::EACCES,
+            Errors::HttpStatus(423) | Errors::DeleteInProgress(_) => libc::EBUSY,
+            Errors::ServerCopyUnsupported => libc::EOPNOTSUPP,
+            Errors::MoveAcrossRemotes(_) => libc::EXDEV,
+            Errors::Interrupted => libc::EINTR,
+            _ => libc::EIO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errno(err: Errors) -> libc::c_int {
+        err.into()
+    }
+
+    #[test]
+    fn statuses_map_to_errno() {
+        assert_eq!(errno(Errors::HttpStatus(404)), libc::ENOENT);
+        assert_eq!(errno(Errors::HttpStatus(412)), libc::EEXIST);
+        assert_eq!(errno(Errors::HttpStatus(403)), libc::EACCES);
+        assert_eq!(errno(Errors::HttpStatus(423)), libc::EBUSY);
+        assert_eq!(errno(Errors::HttpStatus(507)), libc::ENOSPC);
+        assert_eq!(errno(Errors::HttpStatus(500)), libc::EIO);
+    }
+
+    #[test]
+    fn errors_map_to_errno() {
+        assert_eq!(errno(Errors::FileDoesNotExist("a".into())), libc::ENOENT);
+        assert_eq!(errno(Errors::XattrNotFound("user.a".into())), platform::ENOATTR);
+        assert_eq!(errno(Errors::ReadOnlyBackend("/a".into())), libc::EROFS);
+        assert_eq!(errno(Errors::AuthenticationFailed), libc::EACCES);
+        assert_eq!(errno(Errors::InsufficientStorage("".into())), libc::ENOSPC);
+        assert_eq!(errno(Errors::Interrupted), libc::EINTR);
+        // Failures to reach the server are no reason to claim the file is gone
+        assert_eq!(errno(Errors::ServerUnreachable("timeout".into())), libc::EIO);
+        assert_eq!(errno(Errors::InvalidResourceType("/a".into())), libc::EIO);
+    }
+
+    #[test]
+    fn forbidden_requests_exit_apart_from_rejected_credentials() {
+        assert_eq!(Errors::AuthenticationFailed.exit_code(), 3);
+        assert_eq!(Errors::HttpStatus(403).exit_code(), 7);
+        assert_eq!(Errors::HttpStatus(404).exit_code(), 1);
+    }
+
+    #[test]
+    fn json_errors_escape_their_message() {
+        let json = Errors::InvalidArguments("a \"b\"\n".into()).to_json();
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["error"], "config");
+        assert_eq!(parsed["code"], 2);
+        assert_eq!(parsed["message"], "InvalidArguments(\"a \\\"b\\\"\\n\")");
+    }
 }
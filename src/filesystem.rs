@@ -1,23 +1,52 @@
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyOpen, Request, FUSE_ROOT_ID,
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyIoctl, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
+    FUSE_ROOT_ID,
 };
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     ffi::{OsStr, OsString},
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
     time::Duration,
-    time::UNIX_EPOCH,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use libc;
 
 use crate::{
+    config::{Config, HiddenFiles},
+    conflicts,
+    control::{
+        self, ControlCommand, CONFLICTS_DIR_INODE, CONFLICTS_DIR_NAME, CONTROL_DIR_INODE,
+        CONTROL_DIR_NAME, DELETE_REPORT_FILE_NAME, DELETE_REPORT_INODE, INODES_FILE_NAME,
+        INODES_INODE, METRICS_FILE_NAME, METRICS_INODE, SCRATCH_DIR_INODE, SCRATCH_DIR_NAME,
+    },
+    delete::{self, DeleteReport, Deletes},
+    download::{Download, Downloads},
     errors::Errors,
-    prop::{Prop, ResourceType},
-    webdav::{PropfindDepth, WebdavDrive},
+    inflight,
+    metrics::{self, Counter},
+    path,
+    platform,
+    prop::{Prop, PropTree, ResourceType},
+    state::StateStore,
+    remotes::Remotes,
+    scratch::ScratchDir,
+    watch::ChangeEvent,
+    webdav::{PropfindDepth, Quota, Version, SYMLINK_PROPERTY},
+    xattr::{XattrRegistry, DEAD_PROP_PREFIX, HISTORY_XATTR},
 };
 
-const TTL: std::time::Duration = Duration::from_secs(5);
+/// How long a blocked upload waits before it is retried for the first time. Every failed retry
+/// doubles the wait, up to `MAX_UPLOAD_RETRY_INTERVAL`
+const UPLOAD_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_UPLOAD_RETRY_INTERVAL: Duration = Duration::from_secs(600);
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
 pub struct InodeId(u64);
@@ -31,8 +60,34 @@ impl InodeId {
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
 pub struct FileHandleId(u64);
 
+/// A file opened by the kernel
+struct Handle {
+    inode: InodeId,
+    /// Flags the file was opened with
+    flags: i32,
+    /// Whole content of the file, downloaded once it is written to. `None` while the file is
+    /// only read, reads then go to the server
+    data: Option<Vec<u8>>,
+    /// Whether `data` holds changes that are not uploaded yet
+    dirty: bool,
+    /// Size of the file on the server, growing beyond it takes up quota once uploaded
+    uploaded_size: u64,
+}
+
+/// Content of a closed file the server did not take for a reason that may go away, see
+/// `is_retryable`
+struct BlockedUpload {
+    data: Vec<u8>,
+    /// Size of the file on the server, see `Handle::uploaded_size`
+    uploaded_size: u64,
+    /// Uploads that failed so far
+    attempts: u32,
+    /// Listings before this time do not retry the upload
+    retry_at: Instant,
+}
+
 /// Contains all states a file can be in
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum FileState {
     /// File is downloaded and (to our knowledge) up to date
     Local,
@@ -46,6 +101,16 @@ pub enum FileState {
     Conflict,
     Downloading,
     Uploading,
+    /// Changed locally, but the server refused the upload for lack of storage. It is retried
+    /// once a listing shows enough quota again
+    UploadBlocked,
+}
+
+impl FileState {
+    /// Whether the file has no local data that would be lost if it was dropped from memory
+    pub fn is_clean(&self) -> bool {
+        matches!(self, FileState::Local | FileState::RemoteOnly)
+    }
 }
 
 #[derive(Debug)]
@@ -54,12 +119,46 @@ pub struct FileAttributes {
     size: u64,
     mtime: u64,
     is_directory: bool,
+    /// Target of an emulated symlink, stored in a dead property on the server
+    symlink_target: Option<String>,
     state: FileState,
+    /// `oc:permissions` of Nextcloud and ownCloud, like "RGDNVW". `None` on other servers
+    permissions: Option<String>,
 }
 
 impl FileAttributes {
+    pub fn state(&self) -> &FileState {
+        &self.state
+    }
+
+    /// POSIX permission bits, `Config::file_mode` or `Config::dir_mode` without write
+    /// permission where it is not allowed. Nextcloud and ownCloud report what the user may do
+    /// with the file, e.g. a read-only share becomes 0444. Read-only mounts never show write
+    /// permission
+    pub fn mode(&self, config: &Config) -> u16 {
+        if self.symlink_target.is_some() {
+            return 0o777;
+        }
+        let writable = match (&self.permissions, self.is_directory) {
+            (None, _) => true,
+            (Some(permissions), false) => permissions.contains('W'),
+            // Directories are writable if entries can be created in them
+            (Some(permissions), true) => permissions.contains(['C', 'K']),
+        };
+        let mode = match self.is_directory {
+            true => config.dir_mode,
+            false => config.file_mode,
+        };
+        match writable && !config.read_only {
+            true => mode,
+            false => mode & !0o222,
+        }
+    }
+
     pub fn fuser_filetype(&self) -> FileType {
-        if self.is_directory {
+        if self.symlink_target.is_some() {
+            FileType::Symlink
+        } else if self.is_directory {
             FileType::Directory
         } else {
             FileType::RegularFile
@@ -67,31 +166,49 @@ impl FileAttributes {
     }
 }
 
-impl From<Prop> for File {
+impl TryFrom<Prop> for File {
+    type Error = Errors;
+
     /// Converts a Prop to a file.
-    /// Only stores the file_name of the prop's path
-    fn from(value: Prop) -> Self {
+    /// Only stores the file_name of the prop's path. Fails for props the server sent without a
+    /// resource type
+    fn try_from(value: Prop) -> Result<Self, Errors> {
         let is_folder = match value.resource_type() {
             ResourceType::File => false,
             ResourceType::Collection => true,
             ResourceType::Invalid => {
-                panic!("Tried to convert prop with ResourceType 'Invalid' to Inode")
+                let path = value.path().to_string_lossy().into_owned();
+                return Err(Errors::InvalidResourceType(path));
             }
         };
-        Self {
+        let symlink_target = value.dead_props().get(SYMLINK_PROPERTY).cloned();
+        let name = value.path().file_name().and_then(OsStr::to_str);
+        let conflict_of = name.and_then(conflicts::original_name).map(|original| {
+            let parent = value.path().parent().unwrap_or(Path::new("/"));
+            parent.join(original).to_string_lossy().into_owned()
+        });
+        Ok(Self {
             attr: FileAttributes {
-                name: value
-                    .path()
-                    .file_name()
-                    .expect("File Name ended in '..'")
-                    .into(),
-                size: value.size(),
+                // Listings leave out entries without a name, see `apply_listing`
+                name: value.path().file_name().unwrap_or_default().into(),
+                // Like on local filesystems, the size of a symlink is the length of its target
+                size: symlink_target
+                    .as_ref()
+                    .map_or(value.size(), |target| target.len() as u64),
                 mtime: value.last_modified(),
                 is_directory: is_folder,
+                symlink_target,
                 state: FileState::RemoteOnly,
+                permissions: value.nextcloud_props().get("permissions").cloned(),
             },
+            remote_name: None,
             etag: value.etag().to_string(),
-        }
+            nextcloud_props: value.nextcloud_props().clone(),
+            dead_props: value.dead_props().clone(),
+            conflict_of,
+            quota_available: value.quota_available(),
+            version: None,
+        })
     }
 }
 
@@ -99,25 +216,70 @@ impl From<Prop> for File {
 pub struct Inode {
     children: BTreeMap<OsString, InodeId>,
     parent: InodeId,
+    /// How often the kernel looked this inode up without forgetting it again
+    lookups: u64,
+    /// Whether `children` holds the full listing of the directory. Unlisted directories and
+    /// directories whose children were evicted are incomplete
+    complete: bool,
+    /// When the children were last listed from the server
+    listed_at: Option<Instant>,
+    /// Pinned inodes are never evicted
+    pinned: bool,
+    /// Tells the kernel this inode apart from earlier ones that had the same number
+    generation: u64,
 }
 
 impl Inode {
-    pub fn new(parent: InodeId) -> Self {
+    pub fn new(parent: InodeId, generation: u64) -> Self {
         Self {
             children: BTreeMap::new(),
             parent,
+            lookups: 0,
+            complete: false,
+            listed_at: None,
+            pinned: false,
+            generation,
         }
     }
 
     pub fn add_child(&mut self, name: OsString, inode: InodeId) {
         self.children.insert(name, inode);
     }
+
+    /// Finds a child by name. An exact match always wins. Case-insensitive or normalizing
+    /// lookups fall back to the first child whose name only differs in case or unicode form
+    pub fn find_child(&self, name: &OsStr, config: &Config) -> Option<InodeId> {
+        if let Some(inode) = self.children.get(name) {
+            return Some(*inode);
+        }
+        if !config.case_insensitive && config.normalization.is_none() {
+            return None;
+        }
+        let wanted = config.lookup_key(name.to_str()?);
+        self.children
+            .iter()
+            .find(|(child, _)| {
+                child.to_str().map(|child| config.lookup_key(child)).as_deref()
+                    == Some(wanted.as_str())
+            })
+            .map(|(_, inode)| *inode)
+    }
 }
 
 #[derive(Debug)]
 pub struct File {
     attr: FileAttributes,
+    /// Name on the server if it differs from the name shown at the mountpoint
+    remote_name: Option<OsString>,
     etag: String,
+    nextcloud_props: BTreeMap<String, String>,
+    dead_props: BTreeMap<String, String>,
+    /// Path of the file this one is a conflicted copy of, judging by its name
+    conflict_of: Option<String>,
+    /// Bytes the server still accepts below this directory, if it reports a quota
+    quota_available: Option<u64>,
+    /// Earlier version read instead of the current content on `as_of` mounts
+    version: Option<Version>,
 }
 
 impl File {
@@ -128,40 +290,110 @@ impl File {
                 size: 0,
                 mtime: 0,
                 is_directory: true,
+                symlink_target: None,
                 state: FileState::Local,
+                permissions: None,
             },
+            remote_name: None,
             etag: "root".to_string(),
+            nextcloud_props: BTreeMap::new(),
+            dead_props: BTreeMap::new(),
+            conflict_of: None,
+            quota_available: None,
+            version: None,
         };
         return root_inode;
     }
 
+    /// A file that only exists locally, like the entries of the control directory
+    fn synthetic(name: &str, is_directory: bool) -> Self {
+        File {
+            attr: FileAttributes {
+                name: name.into(),
+                size: 0,
+                mtime: 0,
+                is_directory,
+                symlink_target: None,
+                state: FileState::Local,
+                permissions: None,
+            },
+            remote_name: None,
+            etag: String::new(),
+            nextcloud_props: BTreeMap::new(),
+            dead_props: BTreeMap::new(),
+            conflict_of: None,
+            quota_available: None,
+            version: None,
+        }
+    }
+
     pub fn attributes(&self) -> &FileAttributes {
         &self.attr
     }
 
+    /// Name of the file on the server
+    pub fn remote_name(&self) -> &OsStr {
+        self.remote_name.as_deref().unwrap_or(&self.attr.name)
+    }
+
+    /// Whether the server marks the file as hidden, by a leading dot or Nextcloud's `hidden`
+    /// property
+    pub fn marked_hidden(&self) -> bool {
+        is_dot_name(self.remote_name())
+            || self.nextcloud_props.get("hidden").map_or(false, |v| v == "true" || v == "1")
+    }
+
+    pub fn etag(&self) -> &str {
+        &self.etag
+    }
+
+    pub fn conflict_of(&self) -> Option<&str> {
+        self.conflict_of.as_deref()
+    }
+
+    pub fn nextcloud_props(&self) -> &BTreeMap<String, String> {
+        &self.nextcloud_props
+    }
+
+    pub fn dead_props(&self) -> &BTreeMap<String, String> {
+        &self.dead_props
+    }
+
+    pub fn set_dead_prop(&mut self, name: &str, value: &str) {
+        self.dead_props.insert(name.into(), value.into());
+    }
+
+    /// Removes a dead property, returns whether it was set
+    pub fn remove_dead_prop(&mut self, name: &str) -> bool {
+        self.dead_props.remove(name).is_some()
+    }
+
+    /// Server-side id that stays the same when the file is moved, if the server reports one
+    pub fn fileid(&self) -> Option<&str> {
+        self.nextcloud_props.get("fileid").map(String::as_str)
+    }
+
     /// Transforms the FileAttributes of an inode into the libfuse-native FileAttr
-    pub fn to_file_attr(&self, inode: InodeId) -> FileAttr {
-        use std::os::unix::fs::MetadataExt;
-        let uid = std::fs::metadata("/proc/self").map(|m| m.uid()).unwrap();
-        let gid = std::fs::metadata("/proc/self").map(|m| m.gid()).unwrap();
+    pub fn to_file_attr(&self, inode: InodeId, nlink: u32, config: &Config) -> FileAttr {
         let attr = &self.attr;
         let ft = attr.fuser_filetype();
 
         FileAttr {
             ino: inode.0,
             size: attr.size,
-            blocks: attr.size / 4096,
+            // st_blocks is always counted in 512 byte units, independent of blksize
+            blocks: attr.size.div_ceil(512),
             atime: UNIX_EPOCH + Duration::from_secs(attr.mtime),
             mtime: UNIX_EPOCH + Duration::from_secs(attr.mtime),
             ctime: UNIX_EPOCH + Duration::from_secs(attr.mtime),
             crtime: UNIX_EPOCH + Duration::from_secs(attr.mtime),
             kind: ft,
-            perm: 0o77,
-            nlink: 0,
-            uid,
-            gid,
+            perm: attr.mode(config),
+            nlink,
+            uid: config.uid,
+            gid: config.gid,
             rdev: 0,
-            blksize: 4096,
+            blksize: config.block_size,
             flags: 0,
         }
     }
@@ -172,29 +404,164 @@ pub struct FuseFilesystem {
     files: BTreeMap<InodeId, File>,
     next_inode: InodeId,
     next_fd: FileHandleId,
-    drive: WebdavDrive,
+    handles: BTreeMap<FileHandleId, Handle>,
+    drive: Remotes,
+    config: Config,
+    xattrs: XattrRegistry,
+    state: StateStore,
+    /// Inodes by the `oc:fileid` of their file, used to recognize files moved on the server.
+    /// Entries may be stale and are checked against `files` before use
+    fileids: BTreeMap<String, InodeId>,
+    /// Configurations reloaded while mounted
+    config_updates: Option<Arc<Mutex<Receiver<Config>>>>,
+    /// Changes a watcher found on the remote, see `follow_changes`
+    remote_changes: Option<Receiver<ChangeEvent>>,
+    /// Workers that reads from the server are handed to, see `download_in_background`
+    downloads: Option<Downloads>,
+    /// Quota of the remotes and when it was fetched, `df` asks for it often
+    quota: Option<(Instant, Option<Quota>)>,
+    /// Uploads that failed for a reason that may go away, see `is_retryable`
+    blocked: BTreeMap<InodeId, BlockedUpload>,
+    /// Outcome of the last recursive delete, see `delete_tree_`
+    delete_report: DeleteReport,
+    /// Workers that the entries of a tree deleted entry by entry are handed to, see
+    /// `delete_in_background`
+    deletes: Option<Arc<Deletes>>,
+    /// Paths of trees deleted in the background and how many of their entries failed
+    finished_deletes: (Sender<(String, usize)>, Receiver<(String, usize)>),
+    /// Files of `.webdavfs/tmp`, kept in memory only
+    scratch: ScratchDir,
+    /// Entries of open directories, as they were when the directory was opened
+    dir_handles: BTreeMap<FileHandleId, Vec<(InodeId, FileType, OsString)>>,
+    /// Inodes that are gone from the tree but still referenced by the kernel. They are dropped
+    /// once the kernel forgets them
+    orphans: BTreeSet<InodeId>,
+    /// Counts dropped inodes. New inodes get the current value as generation, so a number that
+    /// is handed out again never has the generation it had before
+    generation: u64,
 }
 
 impl FuseFilesystem {
-    fn new(drive: WebdavDrive) -> Self {
+    fn new(drive: Remotes, config: Config, state: StateStore) -> Self {
+        // Never hand out an inode that a path got assigned during an earlier mount
+        let next_inode = InodeId(state.max_inode().map_or(2, |max| max.max(1) + 1));
         return Self {
             inodes: BTreeMap::new(),
             files: BTreeMap::new(),
-            next_inode: InodeId(2),
+            next_inode,
             next_fd: FileHandleId(2),
+            handles: BTreeMap::new(),
             drive,
+            config,
+            xattrs: XattrRegistry::default(),
+            state,
+            fileids: BTreeMap::new(),
+            config_updates: None,
+            remote_changes: None,
+            downloads: None,
+            quota: None,
+            blocked: BTreeMap::new(),
+            delete_report: DeleteReport::default(),
+            deletes: None,
+            finished_deletes: mpsc::channel(),
+            scratch: ScratchDir::default(),
+            dir_handles: BTreeMap::new(),
+            orphans: BTreeSet::new(),
+            generation: 0,
         };
     }
 
     /// Initializes a filesystem with an root node
-    pub fn init(drive: WebdavDrive) -> Self {
-        let mut fs = Self::new(drive);
-        let root_inode = Inode::new(InodeId(FUSE_ROOT_ID));
+    pub fn init(drive: Remotes, config: Config) -> Result<Self, Errors> {
+        let state = match &config.state_file {
+            Some(file) => StateStore::open(file.clone())?,
+            None => StateStore::in_memory(),
+        };
+        let mut fs = Self::new(drive, config, state);
+        let root_inode = Inode::new(InodeId(FUSE_ROOT_ID), 0);
         let root_file = File::init_root();
 
         fs.inodes.insert(InodeId(FUSE_ROOT_ID), root_inode);
         fs.files.insert(InodeId(FUSE_ROOT_ID), root_file);
-        fs
+        Ok(fs)
+    }
+
+    /// Applies configurations received on `updates` while mounted
+    pub fn reload_config_from(&mut self, updates: Arc<Mutex<Receiver<Config>>>) {
+        self.config_updates = Some(updates);
+    }
+
+    /// Takes in the changes of the remote that `changes` receives. The kernel is told to drop
+    /// its caches of changed entries after they were sent, see `watch::spawn_invalidator`
+    pub fn follow_changes(&mut self, changes: Receiver<ChangeEvent>) {
+        self.remote_changes = Some(changes);
+    }
+
+    /// Hands reads that go to the server to `downloads` instead of waiting for them, so other
+    /// operations go on meanwhile
+    pub fn download_in_background(&mut self, downloads: Downloads) {
+        self.downloads = Some(downloads);
+    }
+
+    /// Deletes the entries of trees that the server does not delete at once on `deletes`, so
+    /// other operations go on meanwhile and the delete report shows the progress
+    pub fn delete_in_background(&mut self, deletes: Deletes) {
+        self.deletes = Some(Arc::new(deletes));
+    }
+
+    /// Drops the trees deleted in the background since the last call from the tree
+    fn apply_finished_deletes(&mut self) {
+        let finished: Vec<(String, usize)> = self.finished_deletes.1.try_iter().collect();
+        for (full_path, failed) in finished {
+            match self.forget_deleted(&full_path, failed) {
+                Ok(()) | Err(Errors::PartiallyDeleted(_)) => {}
+                Err(err) => warn!("Could not list {} after deleting: {:?}", full_path, err),
+            }
+        }
+    }
+
+    /// Lists the known directories holding entries that changed on the remote again, so the
+    /// kernel gets fresh attributes when it asks after dropping its cache
+    fn apply_remote_changes(&mut self) {
+        let Some(changes) = &self.remote_changes else {
+            return;
+        };
+        let mut stale = BTreeSet::new();
+        for event in changes.try_iter() {
+            let (parent, _) = event.path().rsplit_once('/').unwrap_or_default();
+            if let Some(inode) = self.known_inode(parent) {
+                stale.insert(inode);
+            }
+        }
+        for inode in stale {
+            if let Err(err) = self.refresh_directory(inode) {
+                warn!("Could not list {:?} after a remote change: {:?}", inode, err);
+            }
+        }
+    }
+
+    /// The inode of a path if all directories along it are known already
+    fn known_inode(&self, path: &str) -> Option<InodeId> {
+        let mut current = InodeId(FUSE_ROOT_ID);
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            let ino = self.inodes.get(&current)?;
+            current = ino.find_child(OsStr::new(segment), &self.config)?;
+        }
+        Some(current)
+    }
+
+    /// Takes over the latest reloaded configuration, if there is one
+    fn apply_config_updates(&mut self) {
+        let updates = self.config_updates.as_ref().and_then(|u| u.lock().ok());
+        let Some(new) = updates.and_then(|u| u.try_iter().last()) else {
+            return;
+        };
+        for setting in self.config.reload(new) {
+            warn!("Changing {} requires a remount, keeping the old value", setting);
+        }
+        log::set_max_level(self.config.log_level);
+        inflight::configure(self.config.hung_threshold, self.config.cancel_hung);
+        info!("Configuration reloaded");
     }
 
     /// Returns next `InodeId` and increments `self.next_inode`
@@ -204,144 +571,2939 @@ impl FuseFilesystem {
         ino
     }
 
-    /// Gathers information about an inode by parent inode and name
-    fn lookup_(&self, parent: InodeId, name_of_file: &OsStr) -> Result<FileAttr, Errors> {
-        let mut parent_inode = self
+    /// Picks the inode for a newly discovered path. Paths seen during an earlier mount get their
+    /// old inode back, as long as it is not in use
+    fn allocate_inode(&mut self, path: &str) -> InodeId {
+        let inode = match self.state.inode_for(path) {
+            Some(ino) if !self.inodes.contains_key(&InodeId(ino)) => InodeId(ino),
+            _ => self.next_inode(),
+        };
+        if let Err(err) = self.state.record_inode(path, inode.0) {
+            warn!("Could not persist inode of {}: {:?}", path, err);
+        }
+        inode
+    }
+
+    /// Gathers information about an inode by parent inode and name and counts the lookup.
+    /// Unknown children are resolved from the server unless the listing of the parent is fresh
+    fn lookup_(&mut self, parent: InodeId, name_of_file: &OsStr) -> Result<FileAttr, Errors> {
+        if self.config.is_hidden(name_of_file) {
+            return Err(Errors::FileDoesNotExist(name_of_file.into()));
+        }
+        let parent_inode = self
             .inodes
             .get(&parent)
             .ok_or(Errors::ParentInodeNotFound(parent))?;
-        if parent_inode.children.is_empty() {
-            //self.readdir(parent, 0)?;
-            parent_inode = self
-                .inodes
-                .get(&parent)
-                .ok_or(Errors::ParentInodeNotFound(parent))?
-                .clone();
+        let mut inode = parent_inode.find_child(name_of_file, &self.config);
+        if inode.is_none() && self.known_missing(parent, name_of_file) {
+            return Err(Errors::FileDoesNotExist(name_of_file.into()));
         }
-        let inode = parent_inode
-            .children
-            .get(name_of_file)
-            .ok_or(Errors::FileDoesNotExist(name_of_file.into()))?;
+        // A miss lists all siblings at once. Bursts of lookups in the same directory, like a
+        // shell expanding a glob, are then answered from that listing until it gets stale
+        if inode.is_none() && !self.has_fresh_listing(parent) {
+            self.refresh_directory(parent)?;
+            inode = self.inodes[&parent].find_child(name_of_file, &self.config);
+        }
+        let Some(inode) = inode else {
+            self.remember_missing(parent, name_of_file);
+            return Err(Errors::FileDoesNotExist(name_of_file.into()));
+        };
+
         let file = self
             .files
-            .get(inode)
-            .ok_or(Errors::ChildInodeNotFound(*inode))?;
-        Ok(file.to_file_attr(*inode))
+            .get(&inode)
+            .ok_or(Errors::ChildInodeNotFound(inode))?;
+        let attr = file.to_file_attr(inode, self.nlink(inode), &self.config);
+        if let Some(ino) = self.inodes.get_mut(&inode) {
+            ino.lookups += 1;
+        }
+        Ok(attr)
+    }
+
+    /// Whether `name` is one of `Config::probe_names` and was missing from `parent` the last
+    /// time it had its current etag, possibly during an earlier mount
+    fn known_missing(&self, parent: InodeId, name: &OsStr) -> bool {
+        if !self.config.is_probe_name(name) {
+            return false;
+        }
+        let Some((directory, etag)) = self.path_and_etag(parent) else {
+            return false;
+        };
+        self.state.is_missing(&directory, &etag, &name.to_string_lossy())
+    }
+
+    /// Records that `name` is missing from `parent`, if it is one of `Config::probe_names`.
+    /// Names left out by `as_of` or `hidden_files` may well exist on the server
+    fn remember_missing(&mut self, parent: InodeId, name: &OsStr) {
+        let shows_everything =
+            self.config.as_of.is_none() && self.config.hidden_files == HiddenFiles::Show;
+        if !shows_everything || !self.config.is_probe_name(name) {
+            return;
+        }
+        let Some((directory, etag)) = self.path_and_etag(parent) else {
+            return;
+        };
+        if let Err(err) = self.state.record_missing(&directory, &etag, &name.to_string_lossy()) {
+            warn!("Could not record {:?} as missing from {}: {:?}", name, directory, err);
+        }
+    }
+
+    fn path_and_etag(&self, inode: InodeId) -> Option<(String, String)> {
+        let etag = self.files.get(&inode)?.etag.clone();
+        Some((self.full_path_of_inode(&inode).ok()?, etag))
+    }
+
+    /// Hands out a handle for a file opened with `flags`
+    fn open_(&mut self, inode: InodeId, flags: i32) -> Result<FileHandleId, Errors> {
+        if !self.files.contains_key(&inode) {
+            return Err(Errors::InodeNotFound(inode));
+        }
+        let fh = self.next_fd;
+        self.next_fd = FileHandleId(fh.0 + 1);
+        // A blocked upload is newer than the server, the handle takes it over
+        let handle = match self.blocked.remove(&inode) {
+            Some(blocked) => Handle {
+                inode,
+                flags,
+                data: Some(blocked.data),
+                dirty: true,
+                uploaded_size: blocked.uploaded_size,
+            },
+            None => Handle {
+                inode,
+                flags,
+                data: None,
+                dirty: false,
+                uploaded_size: self.files[&inode].attr.size,
+            },
+        };
+        self.handles.insert(fh, handle);
+        Ok(fh)
     }
 
-    fn readdir2(
+    /// Creates an empty file on the server and opens it
+    fn create_(
         &mut self,
-        inode: InodeId,
-        offset: usize,
-    ) -> Result<Vec<(InodeId, FileType, OsString)>, Errors> {
-        let mut result = Vec::new();
-        let ino = self
-            .inodes
-            .get(&inode)
-            .ok_or(Errors::InodeNotFound(inode))?;
+        parent: InodeId,
+        name: &OsStr,
+        flags: i32,
+    ) -> Result<(FileAttr, FileHandleId), Errors> {
+        // The upload would replace the content of an existing file
+        if self.resolve_child(parent, name)?.is_some() {
+            return Err(Errors::FileExists(name.into()));
+        }
+        let parent_path = self.full_path_of_inode(&parent)?;
+        let file_name = name
+            .to_str()
+            .ok_or_else(|| Errors::NonUnicodeInPath(name.into()))?;
+        let file_name = self.config.normalize(file_name);
+        self.drive
+            .put(&path::child(&parent_path, &file_name), vec![], None)?;
+        self.refresh_directory(parent)?;
+        let attr = self.lookup_(parent, name)?;
+        let fh = self.open_(InodeId(attr.ino), flags)?;
+        if let Some(handle) = self.handles.get_mut(&fh) {
+            handle.data = Some(vec![]);
+        }
+        Ok((attr, fh))
+    }
+
+    /// The handle `fh` with the whole content of its file, which is downloaded on first use
+    fn load_handle(&mut self, fh: FileHandleId) -> Result<&mut Handle, Errors> {
+        let handle = self
+            .handles
+            .get(&fh)
+            .ok_or(Errors::FileHandleNotFound(fh))?;
+        let content = match handle.data {
+            Some(_) => None,
+            None => {
+                let inode = handle.inode;
+                let file = self.files.get(&inode).ok_or(Errors::InodeNotFound(inode))?;
+                if file.attr.size > self.config.max_buffer_size {
+                    return Err(Errors::FileTooLarge(inode));
+                }
+                Some(self.read_(inode, 0, file.attr.size)?)
+            }
+        };
+        let handle = self
+            .handles
+            .get_mut(&fh)
+            .ok_or(Errors::FileHandleNotFound(fh))?;
+        if content.is_some() {
+            handle.data = content;
+        }
+        Ok(handle)
+    }
+
+    /// Writes `data` at `offset` into the buffer of `fh`. A gap between the end of the file and
+    /// `offset` is filled with zeros that take up memory like any other content, so the file
+    /// cannot grow beyond `Config::max_buffer_size`
+    fn write_(&mut self, fh: FileHandleId, offset: u64, data: &[u8]) -> Result<u32, Errors> {
+        let handle = self
+            .handles
+            .get(&fh)
+            .ok_or(Errors::FileHandleNotFound(fh))?;
+        if handle.flags & libc::O_ACCMODE == libc::O_RDONLY {
+            return Err(Errors::HandleNotWritable(fh));
+        }
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|end| *end <= self.config.max_buffer_size)
+            .ok_or(Errors::FileTooLarge(handle.inode))?;
+        let handle = self.load_handle(fh)?;
+        let (inode, len) = (handle.inode, handle.data.as_ref().map_or(0, Vec::len));
+        self.reserve_quota(inode, len as u64, end)?;
+        let handle = self
+            .handles
+            .get_mut(&fh)
+            .ok_or(Errors::FileHandleNotFound(fh))?;
+        let buffer = handle.data.get_or_insert_with(Vec::new);
+        let (start, end) = (offset as usize, end as usize);
+        if buffer.len() < end {
+            buffer.resize(end, 0);
+        }
+        buffer[start..end].copy_from_slice(data);
+        handle.dirty = true;
+        let (inode, size) = (handle.inode, buffer.len() as u64);
+        self.mark_changed(inode, size);
+        Ok(data.len() as u32)
+    }
 
-        if offset == 0 {
-            result.push((ino.parent, FileType::Directory, "..".into()));
-            result.push((inode, FileType::Directory, ".".into()));
+    /// Changes the size of a file. The buffer of an open handle is resized and uploaded when
+    /// it is flushed, without a handle the new content is uploaded right away. Truncating to
+    /// zero, as opening with O_TRUNC does, never downloads the old content
+    ///
+    /// A truncate by path goes to a handle of the file that already buffers its content, if
+    /// there is one. Otherwise that buffer would upload the old size again when flushed
+    fn truncate_(
+        &mut self,
+        inode: InodeId,
+        fh: Option<FileHandleId>,
+        size: u64,
+    ) -> Result<FileAttr, Errors> {
+        if self.is_directory(inode)? {
+            return Err(Errors::IsADirectory(inode));
+        }
+        if size > self.config.max_buffer_size {
+            return Err(Errors::FileTooLarge(inode));
         }
+        let buffering = || {
+            let mut handles = self.handles.iter();
+            let found = handles.find(|(_, h)| h.inode == inode && h.data.is_some());
+            found.map(|(fh, _)| *fh)
+        };
+        let fh = fh.filter(|fh| self.handles.contains_key(fh)).or_else(buffering);
+        let Some(fh) = fh else {
+            let fh = self.open_(inode, libc::O_WRONLY)?;
+            let result = self
+                .truncate_(inode, Some(fh), size)
+                .and_then(|_| self.flush_(fh));
+            self.handles.remove(&fh);
+            result?;
+            return self.getattributes(inode);
+        };
+        let handle = match size {
+            0 => self
+                .handles
+                .get_mut(&fh)
+                .ok_or(Errors::FileHandleNotFound(fh))?,
+            _ => self.load_handle(fh)?,
+        };
+        let (inode, len) = (handle.inode, handle.data.as_ref().map_or(0, Vec::len));
+        self.reserve_quota(inode, len as u64, size)?;
+        let handle = self
+            .handles
+            .get_mut(&fh)
+            .ok_or(Errors::FileHandleNotFound(fh))?;
+        handle.data.get_or_insert_with(Vec::new).resize(size as usize, 0);
+        handle.dirty = true;
+        let inode = handle.inode;
+        self.mark_changed(inode, size);
+        self.getattributes(inode)
+    }
 
+    /// Uploads the buffer of `fh` if it holds changes. Mounts switched to read-only since the
+    /// file was opened keep the changes until they are writable again
+    fn flush_(&mut self, fh: FileHandleId) -> Result<(), Errors> {
+        let handle = self
+            .handles
+            .get(&fh)
+            .ok_or(Errors::FileHandleNotFound(fh))?;
+        let (true, Some(data)) = (handle.dirty, &handle.data) else {
+            return Ok(());
+        };
+        let inode = handle.inode;
         let full_path = self.full_path_of_inode(&inode)?;
+        if self.config.read_only {
+            return Err(Errors::ReadOnlyBackend(full_path));
+        }
+        let mtime = self.files.get(&inode).map(|f| f.attr.mtime);
+        let (size, uploaded_size) = (data.len() as u64, handle.uploaded_size);
+        self.drive
+            .put(&full_path, data.clone(), mtime)
+            .map_err(|err| self.upload_error(inode, err))?;
+        if let Some(handle) = self.handles.get_mut(&fh) {
+            handle.dirty = false;
+            handle.uploaded_size = size;
+        }
+        // Keep the quota in line with the upload until the next listing brings the real value
+        if let Some(dir) = self.quota_dir(inode) {
+            let quota = self.files.get_mut(&dir).and_then(|f| f.quota_available.as_mut());
+            if let Some(quota) = quota {
+                *quota = (*quota + uploaded_size).saturating_sub(size);
+            }
+        }
+        if let Some(file) = self.files.get_mut(&inode) {
+            file.attr.state = FileState::Local;
+        }
+        Ok(())
+    }
 
-        let props = self.drive.list(&full_path, PropfindDepth::WithChildren)?;
+    /// `err` of an upload of `inode`. A refusal for lack of storage hit the quota of the user if
+    /// the server reports one for a directory above, otherwise the server itself is full
+    fn upload_error(&self, inode: InodeId, err: Errors) -> Errors {
+        match err {
+            Errors::InsufficientStorage(_) if self.quota_dir(inode).is_some() => {
+                Errors::QuotaExceeded(inode)
+            }
+            err => err,
+        }
+    }
 
-        let _files: Vec<File> = props.into_iter().map(|f| f.into()).skip(offset).collect();
+    /// Whether `inode` lies below one of the paths of `Config::write_barrier`
+    fn has_write_barrier(&self, inode: InodeId) -> bool {
+        let Ok(full_path) = self.full_path_of_inode(&inode) else {
+            return false;
+        };
+        let mut barriers = self.config.write_barrier.iter();
+        barriers.any(|barrier| path::is_below(&full_path, barrier))
+    }
 
-        println!("Returned children of {}: \n {:#?}", full_path, _files);
+    /// Fails with `QuotaExceeded` if growing the buffer of `inode` from `len` to `new_len` bytes
+    /// would, together with every other change waiting for upload, exceed the quota the server
+    /// reported. Better refused now than after the data was accepted and cannot be uploaded
+    fn reserve_quota(&self, inode: InodeId, len: u64, new_len: u64) -> Result<(), Errors> {
+        let Some(dir) = self.quota_dir(inode).filter(|_| new_len > len) else {
+            return Ok(());
+        };
+        let available = self.files[&dir].quota_available.unwrap_or_default();
+        let pending: u64 = self
+            .handles
+            .values()
+            .filter(|h| h.dirty && self.quota_dir(h.inode) == Some(dir))
+            .map(|h| (h.data.as_ref().map_or(0, Vec::len) as u64).saturating_sub(h.uploaded_size))
+            .sum();
+        let blocked: u64 = self
+            .blocked
+            .iter()
+            .filter(|(inode, _)| self.quota_dir(**inode) == Some(dir))
+            .map(|(_, b)| (b.data.len() as u64).saturating_sub(b.uploaded_size))
+            .sum();
+        let pending = pending + blocked;
+        match pending + (new_len - len) > available {
+            true => Err(Errors::QuotaExceeded(inode)),
+            false => Ok(()),
+        }
+    }
 
-        for f in _files {
-            result.push((
-                self.next_inode(),
-                f.attributes().fuser_filetype(),
-                f.attributes().name.clone(),
-            ))
+    /// Nearest directory above `inode` that the server reported a quota for
+    fn quota_dir(&self, inode: InodeId) -> Option<InodeId> {
+        let mut current = inode;
+        while !current.is_filesystem_root() {
+            current = self.inodes.get(&current)?.parent;
+            if self.files.get(&current)?.quota_available.is_some() {
+                return Some(current);
+            }
         }
+        None
+    }
 
-        //TODO: Actually show files and not just . and ..
+    /// Changes the modification time of a file. Changes waiting for upload take it along with
+    /// their PUT, otherwise it is set on the server right away. Servers that refuse to change it
+    /// report their own time again with the next listing
+    fn set_mtime_(&mut self, inode: InodeId, mtime: SystemTime) -> Result<FileAttr, Errors> {
+        let mtime = mtime.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let file = self
+            .files
+            .get_mut(&inode)
+            .ok_or(Errors::FileEntryMissing(inode))?;
+        file.attr.mtime = mtime;
+        let pending = !file.attr.state.is_clean()
+            || self.handles.values().any(|h| h.inode == inode && h.dirty);
+        if !pending {
+            let full_path = self.full_path_of_inode(&inode)?;
+            if let Err(err) = self.drive.set_mtime(&full_path, mtime) {
+                debug!("Server did not take the mtime of {}: {:?}", full_path, err);
+            }
+        }
+        self.getattributes(inode)
+    }
 
-        Ok(result)
+    /// Quota of the remotes, fetched again once it is older than `attr_ttl`
+    fn quota_(&mut self) -> Result<Option<Quota>, Errors> {
+        if let Some((fetched_at, quota)) = self.quota {
+            if fetched_at.elapsed() < self.config.attr_ttl {
+                return Ok(quota);
+            }
+        }
+        let quota = self.drive.quota()?;
+        self.quota = Some((Instant::now(), quota));
+        Ok(quota)
     }
 
-    fn getattributes(&self, inode: InodeId) -> Result<FileAttr, Errors> {
-        let file_attr = self.files.get(&inode).ok_or(Errors::InodeNotFound(inode))?;
-        Ok(file_attr.to_file_attr(inode))
+    /// Keeps the buffer of `fh`, whose upload failed with an error `is_retryable` accepts, until
+    /// `retry_blocked_uploads` gets it to the server
+    fn block_upload(&mut self, fh: FileHandleId) {
+        let Some(handle) = self.handles.remove(&fh) else {
+            return;
+        };
+        let Some(data) = handle.data.filter(|_| handle.dirty) else {
+            return;
+        };
+        let blocked = BlockedUpload {
+            data,
+            uploaded_size: handle.uploaded_size,
+            attempts: 1,
+            retry_at: Instant::now() + upload_retry_wait(1),
+        };
+        self.blocked.insert(handle.inode, blocked);
+        if let Some(file) = self.files.get_mut(&handle.inode) {
+            file.attr.state = FileState::UploadBlocked;
+        }
+    }
+
+    /// Uploads blocked files again whose directory has enough quota by now and whose wait since
+    /// the last attempt is over. Uploads that fail for good are dropped
+    fn retry_blocked_uploads(&mut self) {
+        if self.config.read_only {
+            return;
+        }
+        let now = Instant::now();
+        let due = self.blocked.iter().filter(|(_, blocked)| blocked.retry_at <= now);
+        let inodes: Vec<InodeId> = due.map(|(inode, _)| *inode).collect();
+        for inode in inodes {
+            let blocked = &self.blocked[&inode];
+            let growth = (blocked.data.len() as u64).saturating_sub(blocked.uploaded_size);
+            let quota = self
+                .quota_dir(inode)
+                .and_then(|dir| self.files.get(&dir)?.quota_available);
+            if quota.map_or(false, |available| available < growth) {
+                continue;
+            }
+            let Ok(full_path) = self.full_path_of_inode(&inode) else {
+                continue;
+            };
+            let mtime = self.files.get(&inode).map(|f| f.attr.mtime);
+            match self.drive.put(&full_path, blocked.data.clone(), mtime) {
+                Ok(()) => {
+                    info!("Uploaded {} after it was blocked", full_path);
+                    self.blocked.remove(&inode);
+                    if let Some(file) = self.files.get_mut(&inode) {
+                        file.attr.state = FileState::Local;
+                    }
+                }
+                Err(err) if is_retryable(&err) => {
+                    debug!("{} is still blocked: {:?}", full_path, err);
+                    if let Some(blocked) = self.blocked.get_mut(&inode) {
+                        blocked.attempts += 1;
+                        blocked.retry_at = now + upload_retry_wait(blocked.attempts);
+                    }
+                }
+                Err(err) => {
+                    warn!(
+                        "Retrying the upload of {} failed, its changes are lost: {:?}",
+                        full_path, err
+                    );
+                    self.blocked.remove(&inode);
+                    if let Some(file) = self.files.get_mut(&inode) {
+                        file.attr.state = FileState::RemoteOnly;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Records a local change of a file that is not uploaded yet
+    fn mark_changed(&mut self, inode: InodeId, size: u64) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        if let Some(file) = self.files.get_mut(&inode) {
+            file.attr.size = size;
+            file.attr.mtime = now.as_secs();
+            file.attr.state = FileState::ChangedLocally;
+        }
+    }
+
+    /// Reads `size` bytes of a file from the server, starting at `offset`. Reads are clamped to
+    /// the size of the last listing, so nothing is requested at or beyond its end
+    fn read_(&self, inode: InodeId, offset: u64, size: u64) -> Result<Vec<u8>, Errors> {
+        let Some((full_path, version, size)) = self.remote_range(inode, offset, size)? else {
+            return Ok(vec![]);
+        };
+        match &version {
+            Some(version) => self
+                .drive
+                .get_version_range(&full_path, version, offset, size),
+            None => self.drive.get_range(&full_path, offset, size),
+        }
+    }
+
+    /// Path, version and clamped size that a read of `size` bytes at `offset` asks the server
+    /// for. `None` if the read starts at or beyond the end of the file
+    fn remote_range(
+        &self,
+        inode: InodeId,
+        offset: u64,
+        size: u64,
+    ) -> Result<Option<(String, Option<Version>, u64)>, Errors> {
+        let file = self.files.get(&inode).ok_or(Errors::InodeNotFound(inode))?;
+        let size = size.min(file.attributes().size.saturating_sub(offset));
+        if size == 0 {
+            return Ok(None);
+        }
+        let full_path = self.full_path_of_inode(&inode)?;
+        Ok(Some((full_path, file.version.clone(), size)))
+    }
+
+    /// Target of an emulated symlink
+    fn readlink_(&self, inode: InodeId) -> Result<String, Errors> {
+        let file = self.files.get(&inode).ok_or(Errors::InodeNotFound(inode))?;
+        file.attributes()
+            .symlink_target
+            .clone()
+            .ok_or(Errors::NotASymlink(inode))
+    }
+
+    /// Creates an emulated symlink called `name` inside of `parent` and looks it up
+    fn symlink_(
+        &mut self,
+        parent: InodeId,
+        name: &OsStr,
+        target: &Path,
+    ) -> Result<FileAttr, Errors> {
+        let parent_path = self.full_path_of_inode(&parent)?;
+        let file_name = name
+            .to_str()
+            .ok_or_else(|| Errors::NonUnicodeInPath(name.into()))?;
+        let target = target
+            .to_str()
+            .ok_or_else(|| Errors::NonUnicodeInPath(target.into()))?;
+        let link_path = path::child(&parent_path, &self.config.normalize(file_name));
+        self.drive.create_symlink(&link_path, target)?;
+        self.refresh_directory(parent)?;
+        self.lookup_(parent, name)
+    }
+
+    /// Creates the directory `name` inside of `parent` and looks it up. The listing that follows
+    /// brings its etag and fileid along
+    fn mkdir_(&mut self, parent: InodeId, name: &OsStr) -> Result<FileAttr, Errors> {
+        let parent_path = self.full_path_of_inode(&parent)?;
+        let dir_name = name
+            .to_str()
+            .ok_or_else(|| Errors::NonUnicodeInPath(name.into()))?;
+        self.drive
+            .mkcol(&path::child(&parent_path, &self.config.normalize(dir_name)))?;
+        self.refresh_directory(parent)?;
+        self.lookup_(parent, name)
+    }
+
+    /// Deletes the child `name` of `parent` on the server and drops it from the tree. Directories
+    /// are only removed when empty, judged by their listing since a DELETE would take their
+    /// whole content along
+    fn remove_(&mut self, parent: InodeId, name: &OsStr, directory: bool) -> Result<(), Errors> {
+        let child = self
+            .resolve_child(parent, name)?
+            .ok_or_else(|| Errors::FileDoesNotExist(name.into()))?;
+        let is_directory = self.is_directory(child)?;
+        match (directory, is_directory) {
+            (true, false) => return Err(Errors::NotADirectory(child)),
+            (false, true) => return Err(Errors::IsADirectory(child)),
+            _ => {}
+        }
+        if directory {
+            self.check_empty(child)?;
+        }
+
+        let full_path = self.full_path_of_inode(&child)?;
+        self.drive.delete(&full_path)?;
+        if let Some(parent) = self.inodes.get_mut(&parent) {
+            parent.children.retain(|_, inode| *inode != child);
+        }
+        self.remove_subtree(child);
+        if let Err(err) = self.state.forget(&full_path) {
+            warn!("Could not persist removal of {}: {:?}", full_path, err);
+        }
+        Ok(())
+    }
+
+    /// Renames the child `name` of `parent` to `new_name` inside of `new_parent` with a MOVE on
+    /// the server. The inode is moved in place, so it stays the same across the rename
+    fn rename_(
+        &mut self,
+        parent: InodeId,
+        name: &OsStr,
+        new_parent: InodeId,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<(), Errors> {
+        let child = self
+            .resolve_child(parent, name)?
+            .ok_or_else(|| Errors::FileDoesNotExist(name.into()))?;
+        let is_directory = self.is_directory(child)?;
+        let replaced = self.resolve_child(new_parent, new_name)?;
+        if let Some(replaced) = replaced.filter(|r| *r != child) {
+            // The answer a MOVE without overwriting gets from the server
+            if flags & platform::RENAME_NOREPLACE != 0 {
+                return Err(Errors::HttpStatus(412));
+            }
+            // Same rules as rename(2): types must match and only empty directories are replaced
+            match (is_directory, self.is_directory(replaced)?) {
+                (true, false) => return Err(Errors::NotADirectory(replaced)),
+                (false, true) => return Err(Errors::IsADirectory(replaced)),
+                (true, true) => self.check_empty(replaced)?,
+                (false, false) => {}
+            }
+        }
+
+        let from = self.full_path_of_inode(&child)?;
+        let new_name_str = new_name
+            .to_str()
+            .ok_or_else(|| Errors::NonUnicodeInPath(new_name.into()))?;
+        // A replaced entry may be shown under another name than it has on the server
+        let replaced = replaced.and_then(|r| Some((r, self.files.get(&r)?.remote_name.clone())));
+        let (to, remote_name) = match replaced {
+            Some((replaced, remote_name)) => (self.full_path_of_inode(&replaced)?, remote_name),
+            None => {
+                let normalized = self.config.normalize(new_name_str);
+                let remote_name = Some(OsString::from(normalized.as_ref()))
+                    .filter(|normalized| normalized.as_os_str() != new_name);
+                let new_parent_path = self.full_path_of_inode(&new_parent)?;
+                (path::child(&new_parent_path, &normalized), remote_name)
+            }
+        };
+        self.drive
+            .move_to(&from, &to, flags & platform::RENAME_NOREPLACE == 0)?;
+        self.relocate_inode(child, new_parent, new_name)?;
+        if let Some(file) = self.files.get_mut(&child) {
+            file.remote_name = remote_name;
+        }
+        Ok(())
     }
 
-    /// recursive function that builds an filesystem-absolute path by traversing the inode tree
-    /// upwards
-    fn _full_path_of_inode(&self, inode: &InodeId) -> Result<Vec<String>, Errors> {
-        let name = self
+    /// Copies the whole content of `source` over `target` with a COPY on the server, so no data
+    /// passes through this machine. Only a copy of the complete, uploaded file into an empty or
+    /// shorter target starting at offset 0 can be done this way, everything else fails with
+    /// `ServerCopyUnsupported` and the kernel falls back to reading and writing
+    fn copy_file_range_(
+        &mut self,
+        source: InodeId,
+        (target, target_fh): (InodeId, FileHandleId),
+        offsets: (u64, u64),
+        len: u64,
+    ) -> Result<u32, Errors> {
+        let size = self
             .files
-            .get(inode)
-            .ok_or(Errors::FileEntryMissing(*inode))?
+            .get(&source)
+            .ok_or(Errors::FileEntryMissing(source))?
             .attr
-            .name
-            .clone();
-        let mut path: Vec<String> = vec![name.into_string().map_err(Errors::NonUnicodeInPath)?];
+            .size;
+        let target_size = self
+            .files
+            .get(&target)
+            .ok_or(Errors::FileEntryMissing(target))?
+            .attr
+            .size;
+        let unsaved = |inode: InodeId| {
+            self.blocked.contains_key(&inode)
+                || self.handles.values().any(|h| h.inode == inode && h.dirty)
+        };
+        let whole_file = offsets == (0, 0) && len >= size && target_size <= size;
+        if !whole_file || size > u32::MAX.into() || unsaved(source) || unsaved(target) {
+            return Err(Errors::ServerCopyUnsupported);
+        }
 
-        let parent_inode = self
-            .inodes
-            .get(inode)
-            .ok_or(Errors::ParentInodeNotFound(*inode))?
-            .parent;
+        let from = self.full_path_of_inode(&source)?;
+        let to = self.full_path_of_inode(&target)?;
+        self.drive.copy_to(&from, &to)?;
+        // The handle may hold the old content of the target, the server has the new one
+        if let Some(handle) = self.handles.get_mut(&target_fh) {
+            handle.data = None;
+            handle.uploaded_size = size;
+        }
+        let parent = self.directory_of(target)?;
+        self.refresh_directory(parent)?;
+        Ok(size as u32)
+    }
 
-        // Recurse until root node is reached, then return
-        while !parent_inode.is_filesystem_root() {
-            path.append(&mut self._full_path_of_inode(&parent_inode)?);
+    /// Finds the child `name` of `parent`, listing `parent` first unless its listing is fresh
+    fn resolve_child(&mut self, parent: InodeId, name: &OsStr) -> Result<Option<InodeId>, Errors> {
+        let child = self
+            .inodes
+            .get(&parent)
+            .ok_or(Errors::ParentInodeNotFound(parent))?
+            .find_child(name, &self.config);
+        if child.is_some() || self.has_fresh_listing(parent) {
+            return Ok(child);
         }
+        self.refresh_directory(parent)?;
+        Ok(self.inodes[&parent].find_child(name, &self.config))
+    }
 
-        Ok(path)
+    /// Whether the inode is a directory
+    fn is_directory(&self, inode: InodeId) -> Result<bool, Errors> {
+        let file = self.files.get(&inode).ok_or(Errors::FileEntryMissing(inode))?;
+        Ok(file.attributes().is_directory)
     }
 
-    /// Returns the filesystem-absolute path of an inode
-    fn full_path_of_inode(&self, inode: &InodeId) -> Result<String, Errors> {
-        let mut path_vec = self._full_path_of_inode(inode)?;
-        path_vec.reverse();
-        Ok(path_vec.into_iter().collect())
+    /// Fails with `DirectoryNotEmpty` if the directory has children, listing it first if its
+    /// children are not known. The server would take the content along instead of refusing
+    fn check_empty(&mut self, directory: InodeId) -> Result<(), Errors> {
+        if !self.inodes.get(&directory).map_or(false, |ino| ino.complete) {
+            self.refresh_directory(directory)?;
+        }
+        match self.inodes[&directory].children.is_empty() {
+            true => Ok(()),
+            false => Err(Errors::DirectoryNotEmpty(directory)),
+        }
     }
-}
 
-impl Filesystem for FuseFilesystem {
-    fn readdir(
+    /// Moves an inode to `new_name` inside of `new_parent`, replacing whatever was there. Paths
+    /// of descendants are derived from the tree and follow automatically, so open inodes keep
+    /// working. Only the persisted path assignments of the whole subtree need to be rewritten
+    fn relocate_inode(
         &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        let files_in_dir = self
-            .readdir2(InodeId(ino), offset.try_into().unwrap())
-            .unwrap();
-        for (idx, entry) in files_in_dir.iter().enumerate() {
-            let full = reply.add(entry.0 .0, idx.try_into().unwrap(), entry.1, &entry.2);
-            if full {
-                break;
+        inode: InodeId,
+        new_parent: InodeId,
+        new_name: &OsStr,
+    ) -> Result<(), Errors> {
+        let old_path = self.full_path_of_inode(&inode)?;
+        let old_parent = self
+            .inodes
+            .get(&inode)
+            .ok_or(Errors::InodeNotFound(inode))?
+            .parent;
+        let file = self
+            .files
+            .get_mut(&inode)
+            .ok_or(Errors::FileEntryMissing(inode))?;
+        let old_name = std::mem::replace(&mut file.attr.name, new_name.into());
+        file.remote_name = None;
+
+        // An orphan that shows up again may have been replaced under its old name meanwhile
+        if let Some(parent) = self.inodes.get_mut(&old_parent) {
+            if parent.children.get(&old_name) == Some(&inode) {
+                parent.children.remove(&old_name);
+            }
+        }
+        self.orphans.remove(&inode);
+        let displaced = self
+            .inodes
+            .get_mut(&new_parent)
+            .ok_or(Errors::ParentInodeNotFound(new_parent))?
+            .children
+            .insert(new_name.into(), inode);
+        if let Some(displaced) = displaced.filter(|d| *d != inode) {
+            self.remove_subtree(displaced);
+        }
+        if let Some(ino) = self.inodes.get_mut(&inode) {
+            ino.parent = new_parent;
+        }
+
+        let new_path = self.full_path_of_inode(&inode)?;
+        if let Err(err) = self.state.rename_prefix(&old_path, &new_path) {
+            warn!("Could not persist move of {} to {}: {:?}", old_path, new_path, err);
+        }
+        Ok(())
+    }
+
+    /// Drops an inode and all of its descendants from the tree. Inodes the kernel still
+    /// references are kept as orphans until it forgets them, so their attributes stay available
+    fn remove_subtree(&mut self, inode: InodeId) {
+        let Some(ino) = self.inodes.get_mut(&inode) else {
+            self.files.remove(&inode);
+            return;
+        };
+        let children = std::mem::take(&mut ino.children);
+        match ino.lookups {
+            0 => self.drop_inode(inode),
+            _ => {
+                self.orphans.insert(inode);
+                self.blocked.remove(&inode);
             }
         }
-        reply.ok();
+        for child in children.into_values() {
+            self.remove_subtree(child);
+        }
     }
 
-    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        let attr = self.getattributes(InodeId(ino));
-        reply.attr(&TTL, &attr.unwrap());
+    /// Forgets everything about an inode. Its number may be handed out again later
+    fn drop_inode(&mut self, inode: InodeId) {
+        self.inodes.remove(&inode);
+        self.files.remove(&inode);
+        self.blocked.remove(&inode);
+        self.orphans.remove(&inode);
+        self.generation += 1;
     }
 
-    fn lookup(
-        &mut self,
-        _req: &Request<'_>,
-        parent: u64,
-        name: &std::ffi::OsStr,
-        reply: ReplyEntry,
-    ) {
-        if let Ok(attr) = self.lookup_(InodeId(parent), name) {
-            reply.entry(&TTL, &attr, 0);
-        } else {
-            reply.error(libc::ENOENT);
+    /// Generation of an inode as the kernel has to see it in entries
+    fn generation_of(&self, inode: u64) -> u64 {
+        self.inodes.get(&InodeId(inode)).map_or(0, |ino| ino.generation)
+    }
+
+    /// Drops `nlookup` lookups of an inode. Orphans are dropped once no lookup is left, other
+    /// inodes may be evicted
+    fn forget_(&mut self, inode: InodeId, nlookup: u64) {
+        if let Some(ino) = self.inodes.get_mut(&inode) {
+            ino.lookups = ino.lookups.saturating_sub(nlookup);
+            if ino.lookups == 0 && self.orphans.contains(&inode) {
+                self.drop_inode(inode);
+            }
         }
+        self.evict_inodes();
+    }
+
+    /// Shrinks the inode table down to `Config::max_inodes` by dropping leaves the kernel holds
+    /// no references to and that have no local changes. The parents of evicted inodes are marked
+    /// incomplete, so a later lookup resolves the child from the server again
+    fn evict_inodes(&mut self) {
+        let Some(max_inodes) = self.config.max_inodes.filter(|_| !self.config.snapshot) else {
+            return;
+        };
+        let excess = self.inodes.len().saturating_sub(max_inodes);
+        if excess == 0 {
+            return;
+        }
+
+        let evictable: Vec<InodeId> = self
+            .inodes
+            .iter()
+            .filter(|(id, ino)| {
+                !id.is_filesystem_root()
+                    && !ino.pinned
+                    && ino.lookups == 0
+                    && ino.children.is_empty()
+                    && self
+                        .files
+                        .get(id)
+                        .map_or(true, |f| f.attributes().state().is_clean())
+            })
+            .map(|(id, _)| *id)
+            .take(excess)
+            .collect();
+
+        for id in evictable {
+            let parent = self.inodes.get(&id).map(|ino| ino.parent);
+            let name = self.files.get(&id).map(|file| file.attributes().name.clone());
+            self.drop_inode(id);
+            let (Some(parent), Some(name)) = (parent, name) else {
+                continue;
+            };
+            // Orphans share their name with whatever replaced them
+            if let Some(parent) = self.inodes.get_mut(&parent) {
+                if parent.children.get(&name) == Some(&id) {
+                    parent.children.remove(&name);
+                    parent.complete = false;
+                }
+            }
+        }
+        debug!("Evicted inodes, {} remain", self.inodes.len());
+    }
+
+    /// Finds the inode of a file that the server moved into `directory`, by the fileid of its
+    /// new listing entry. Ancestors of `directory` cannot have been moved into it
+    fn moved_inode(&self, file: &File, directory: InodeId) -> Option<InodeId> {
+        let candidate = *self.fileids.get(file.fileid()?)?;
+        let known = self.files.get(&candidate)?;
+        if known.fileid() != file.fileid() || known.attr.is_directory != file.attr.is_directory {
+            return None;
+        }
+        let mut current = directory;
+        loop {
+            if current == candidate {
+                return None;
+            }
+            if current.is_filesystem_root() {
+                return Some(candidate);
+            }
+            current = self.inodes.get(&current)?.parent;
+        }
+    }
+
+    /// Replaces the cached children of a directory with a fresh listing from the server.
+    /// Children that are still present keep their inode
+    fn refresh_directory(&mut self, inode: InodeId) -> Result<(), Errors> {
+        // Snapshots keep serving the listings taken at mount time
+        if self.config.snapshot && self.inodes.get(&inode).map_or(false, |ino| ino.complete) {
+            return Ok(());
+        }
+        // Make room before the listing grows the table, so its fresh entries are not evicted
+        self.evict_inodes();
+        let full_path = self.full_path_of_inode(&inode)?;
+        let props = self.drive.list(&full_path, PropfindDepth::WithChildren)?;
+        self.apply_listing(inode, &full_path, props)?;
+        // The listing may show that storage was freed or that the server is back
+        if !self.blocked.is_empty() {
+            self.retry_blocked_uploads();
+        }
+        Ok(())
+    }
+
+    /// Fills the tree below the root from a recursive listing, so directories it covers need
+    /// no request of their own later. Each directory takes its quota and etag from its own prop
+    /// in its group before its children are applied
+    pub fn populate(&mut self, mut tree: PropTree) -> Result<(), Errors> {
+        let mut pending = vec![InodeId(FUSE_ROOT_ID)];
+        while let Some(inode) = pending.pop() {
+            let full_path = self.full_path_of_inode(&inode)?;
+            let Some(props) = tree.remove(&full_path) else {
+                continue;
+            };
+            self.apply_listing(inode, &full_path, props)?;
+            let subdirectories = self.inodes[&inode].children.values().filter(|child| {
+                self.files
+                    .get(child)
+                    .map_or(false, |f| f.attributes().is_directory)
+            });
+            pending.extend(subdirectories);
+        }
+        Ok(())
+    }
+
+    /// Replaces the cached children of the directory `inode` at `full_path` with `props`
+    fn apply_listing(
+        &mut self,
+        inode: InodeId,
+        full_path: &str,
+        props: Vec<Prop>,
+    ) -> Result<(), Errors> {
+        let mut children = BTreeMap::new();
+        // The listing contains the directory itself as well, with its quota and an etag that
+        // tells whether entries remembered as missing may have shown up
+        let own = props.iter().find(|p| p.path() == Path::new(full_path));
+        if let (Some(own), Some(dir)) = (own, self.files.get_mut(&inode)) {
+            dir.quota_available = own.quota_available();
+            if !own.etag().is_empty() {
+                dir.etag = own.etag().to_string();
+            }
+        }
+        let remote_names: BTreeSet<OsString> = props
+            .iter()
+            .filter_map(|p| p.path().file_name().map(OsStr::to_os_string))
+            .collect();
+        // A href ending in ".." has no name, the server is confused about it
+        for prop in props
+            .into_iter()
+            .filter(|p| p.path() != Path::new(full_path) && p.path().file_name().is_some())
+        {
+            let mut file = match File::try_from(prop) {
+                Ok(file) => file,
+                Err(err) => {
+                    warn!("Leaving an entry of {} out of its listing: {:?}", full_path, err);
+                    continue;
+                }
+            };
+            let name = match self.local_name(&file, &remote_names) {
+                Some(name) => name,
+                // Hidden files created at the mountpoint stay visible until they are uploaded
+                None if self.has_local_changes(inode, file.remote_name()) => {
+                    file.remote_name().to_os_string()
+                }
+                None => continue,
+            };
+            if name != file.attr.name {
+                file.remote_name = Some(std::mem::replace(&mut file.attr.name, name.clone()));
+            }
+            if self.config.is_hidden(&name) {
+                continue;
+            }
+            if let Some(as_of) = self.config.as_of {
+                let remote_path = path::child(full_path, &file.remote_name().to_string_lossy());
+                // Files without a version that old did not exist yet
+                if !self.resolve_as_of(&mut file, &remote_path, as_of) {
+                    continue;
+                }
+            }
+            let existing = self
+                .inodes
+                .get(&inode)
+                .and_then(|ino| ino.children.get(&name))
+                .copied();
+            let moved = match existing {
+                Some(_) => None,
+                None => self.moved_inode(&file, inode),
+            };
+            let child = match (existing, moved) {
+                (Some(child), _) => child,
+                (None, Some(moved)) => {
+                    debug!("{:?} was moved to {:?} in {}", moved, name, full_path);
+                    self.relocate_inode(moved, inode, &name)?;
+                    moved
+                }
+                (None, None) => {
+                    self.allocate_inode(&path::child(full_path, &name.to_string_lossy()))
+                }
+            };
+            if let Some(fileid) = file.fileid() {
+                self.fileids.insert(fileid.to_string(), child);
+            }
+            if !file.attr.is_directory {
+                let remote_path = path::child(full_path, &file.remote_name().to_string_lossy());
+                let recorded = self.state.record_etag(&remote_path, &file.etag, file.attr.mtime);
+                if let Err(err) = recorded {
+                    warn!("Could not record the etag of {}: {:?}", remote_path, err);
+                }
+            }
+            let generation = self.generation;
+            self.inodes
+                .entry(child)
+                .or_insert_with(|| Inode::new(inode, generation));
+            // Files with changes that are not uploaded yet keep their local attributes
+            let changed = self
+                .files
+                .get(&child)
+                .map_or(false, |f| !f.attributes().state().is_clean());
+            if !changed {
+                self.files.insert(child, file);
+            } else if let Some(local) = self.files.get_mut(&child) {
+                local.remote_name = file.remote_name;
+            }
+            children.insert(name, child);
+        }
+        debug!("Refreshed children of {}: {:?}", full_path, children);
+
+        let ino = self
+            .inodes
+            .get_mut(&inode)
+            .ok_or(Errors::InodeNotFound(inode))?;
+        let old_children = std::mem::replace(&mut ino.children, children);
+        ino.complete = true;
+        ino.listed_at = Some(Instant::now());
+        for (name, child) in old_children {
+            if !self.inodes[&inode].children.contains_key(&name) {
+                self.remove_subtree(child);
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes `file` show the last version that was current at `as_of`, if it changed since.
+    /// Returns false if it has no such version. Versions only exist on Nextcloud, other files
+    /// changed since then are left out as well
+    fn resolve_as_of(&self, file: &mut File, remote_path: &str, as_of: u64) -> bool {
+        if file.attr.is_directory || file.attr.mtime <= as_of {
+            return true;
+        }
+        let Some(fileid) = file.fileid() else {
+            return false;
+        };
+        let versions = match self.drive.versions(remote_path, fileid) {
+            Ok(versions) => versions,
+            Err(err) => {
+                warn!("Could not list the versions of {}: {:?}", remote_path, err);
+                return false;
+            }
+        };
+        let Some(version) = versions.into_iter().rev().find(|v| v.mtime <= as_of) else {
+            return false;
+        };
+        file.attr.size = version.size;
+        file.attr.mtime = version.mtime;
+        file.version = Some(version);
+        true
+    }
+
+    /// Name under which `file` is shown, according to `Config::hidden_files`. `None` if it is
+    /// hidden. `siblings` are the names of all entries of the listing, a stripped name never
+    /// shadows one of them
+    fn local_name(&self, file: &File, siblings: &BTreeSet<OsString>) -> Option<OsString> {
+        let name = file.remote_name();
+        match self.config.hidden_files {
+            HiddenFiles::Show => Some(name.to_os_string()),
+            HiddenFiles::Hide if file.marked_hidden() => None,
+            HiddenFiles::Hide => Some(name.to_os_string()),
+            HiddenFiles::Strip => {
+                let stripped = name
+                    .to_str()
+                    .filter(|_| is_dot_name(name))
+                    .map(|n| OsString::from(&n[1..]))
+                    .filter(|n| !n.is_empty() && !siblings.contains(n));
+                Some(stripped.unwrap_or_else(|| name.to_os_string()))
+            }
+        }
+    }
+
+    /// Whether the child `name` of `parent` has changes that are not uploaded yet
+    fn has_local_changes(&self, parent: InodeId, name: &OsStr) -> bool {
+        let child = self.inodes.get(&parent).and_then(|ino| ino.children.get(name));
+        child
+            .and_then(|child| self.files.get(child))
+            .map_or(false, |f| !f.attributes().state().is_clean())
+    }
+
+    /// Whether the cached children of a directory are complete and younger than `attr_ttl`
+    fn has_fresh_listing(&self, inode: InodeId) -> bool {
+        self.inodes.get(&inode).map_or(false, |ino| {
+            let listed_at = ino.listed_at.filter(|_| ino.complete);
+            listed_at.map_or(false, |at| at.elapsed() < self.config.attr_ttl)
+        })
+    }
+
+    /// Opens a directory with a snapshot of its entries, which all reads of the handle are
+    /// served from. Listings younger than `attr_ttl` are reused unless the directory is
+    /// configured as `refresh_on_open`
+    fn opendir_(&mut self, inode: InodeId) -> Result<FileHandleId, Errors> {
+        let full_path = self.full_path_of_inode(&inode)?;
+        if self.config.refresh_on_open.contains(&full_path) || !self.has_fresh_listing(inode) {
+            metrics::add(Counter::CacheMisses, 1);
+            self.refresh_directory(inode)?;
+        } else {
+            metrics::add(Counter::CacheHits, 1);
+        }
+        let ino = self
+            .inodes
+            .get(&inode)
+            .ok_or(Errors::InodeNotFound(inode))?;
+
+        let mut entries = vec![
+            (inode, FileType::Directory, OsString::from(".")),
+            (ino.parent, FileType::Directory, OsString::from("..")),
+        ];
+        if inode.is_filesystem_root() {
+            entries.push((
+                InodeId(CONTROL_DIR_INODE),
+                FileType::Directory,
+                CONTROL_DIR_NAME.into(),
+            ));
+        }
+        entries.extend(ino.children.iter().map(|(name, child)| {
+            let kind = self
+                .files
+                .get(child)
+                .map_or(FileType::RegularFile, |f| f.attributes().fuser_filetype());
+            (*child, kind, name.clone())
+        }));
+
+        let fh = self.next_fd;
+        self.next_fd = FileHandleId(fh.0 + 1);
+        self.dir_handles.insert(fh, entries);
+        Ok(fh)
+    }
+
+    /// Returns the entries of an open directory that follow `offset`, together with the offset
+    /// of each entry's successor. Offsets are positions in the snapshot taken on open, so they
+    /// stay valid however the directory changes meanwhile
+    fn readdir_(
+        &self,
+        fh: FileHandleId,
+        offset: i64,
+    ) -> Result<Vec<(InodeId, i64, FileType, OsString)>, Errors> {
+        let entries = self
+            .dir_handles
+            .get(&fh)
+            .ok_or(Errors::FileHandleNotFound(fh))?;
+        Ok(entries
+            .iter()
+            .cloned()
+            .enumerate()
+            .skip(offset.try_into().unwrap_or_default())
+            .map(|(idx, (child, kind, name))| (child, idx as i64 + 1, kind, name))
+            .collect())
+    }
+
+    fn getattributes(&self, inode: InodeId) -> Result<FileAttr, Errors> {
+        let file_attr = self.files.get(&inode).ok_or(Errors::InodeNotFound(inode))?;
+        Ok(file_attr.to_file_attr(inode, self.nlink(inode), &self.config))
+    }
+
+    /// Number of hard links of an inode. Files have exactly one, directories are referenced by
+    /// their parent, their own "." and the ".." of every subdirectory
+    fn nlink(&self, inode: InodeId) -> u32 {
+        let is_directory = |ino: &InodeId| {
+            self.files
+                .get(ino)
+                .map_or(false, |f| f.attributes().is_directory)
+        };
+        if !is_directory(&inode) {
+            return 1;
+        }
+        let subdirectories = self.inodes.get(&inode).map_or(0, |ino| {
+            ino.children.values().filter(|c| is_directory(c)).count()
+        });
+        2 + subdirectories as u32
+    }
+
+    /// Returns the value of the extended attribute `name` of an inode
+    fn getxattr_(&self, inode: InodeId, name: &OsStr) -> Result<Vec<u8>, Errors> {
+        let file = self.files.get(&inode).ok_or(Errors::InodeNotFound(inode))?;
+        let full_path = self.full_path_of_inode(&inode)?;
+        // The history lives in the state store, which the namespaces cannot reach
+        if name == HISTORY_XATTR {
+            return Ok(self.etag_history(&full_path).into_bytes());
+        }
+        let href = self.drive.href(&full_path);
+        self.xattrs.get(file, &href, name)
+    }
+
+    /// The etags seen for `full_path`, oldest first, as JSON array
+    fn etag_history(&self, full_path: &str) -> String {
+        let entries: Vec<serde_json::Value> = self
+            .state
+            .history(full_path)
+            .map(|record| {
+                serde_json::json!({
+                    "etag": record.etag,
+                    "mtime": record.mtime,
+                    "seen_at": record.seen_at,
+                })
+            })
+            .collect();
+        serde_json::Value::from(entries).to_string() + "\n"
+    }
+
+    /// Sets an extended attribute. Attributes that are dead properties are stored on the server
+    /// with a PROPPATCH, the local value is restored if that fails
+    fn setxattr_(&mut self, inode: InodeId, name: &OsStr, value: &[u8]) -> Result<(), Errors> {
+        let full_path = self.full_path_of_inode(&inode)?;
+        let file = self
+            .files
+            .get_mut(&inode)
+            .ok_or(Errors::InodeNotFound(inode))?;
+        let Some(prop) = name.to_str().and_then(|n| n.strip_prefix(DEAD_PROP_PREFIX)) else {
+            return self.xattrs.set(file, name, value);
+        };
+        let previous = file.dead_props().get(prop).cloned();
+        self.xattrs.set(file, name, value)?;
+        let value = String::from_utf8_lossy(value);
+        if let Err(err) = self.drive.set_property(&full_path, prop, &value) {
+            if let Some(file) = self.files.get_mut(&inode) {
+                match previous {
+                    Some(previous) => file.set_dead_prop(prop, &previous),
+                    None => {
+                        file.remove_dead_prop(prop);
+                    }
+                }
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Removes an extended attribute, from the server as well if it is a dead property
+    fn removexattr_(&mut self, inode: InodeId, name: &OsStr) -> Result<(), Errors> {
+        let full_path = self.full_path_of_inode(&inode)?;
+        let file = self
+            .files
+            .get_mut(&inode)
+            .ok_or(Errors::InodeNotFound(inode))?;
+        let Some(prop) = name.to_str().and_then(|n| n.strip_prefix(DEAD_PROP_PREFIX)) else {
+            return self.xattrs.remove(file, name);
+        };
+        let previous = file.dead_props().get(prop).cloned();
+        self.xattrs.remove(file, name)?;
+        if let Err(err) = self.drive.remove_property(&full_path, prop) {
+            if let (Some(file), Some(previous)) = (self.files.get_mut(&inode), previous) {
+                file.set_dead_prop(prop, &previous);
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Returns the NUL separated names of all extended attributes of an inode
+    fn listxattr_(&self, inode: InodeId) -> Result<Vec<u8>, Errors> {
+        let file = self.files.get(&inode).ok_or(Errors::InodeNotFound(inode))?;
+        Ok(self.xattrs.list(file))
+    }
+
+    /// Every known inode with its parent, name, etag, state and lookup count as JSON array, for
+    /// debugging lookups of a live mount
+    fn dump_inodes(&self) -> String {
+        let entries: Vec<serde_json::Value> = self
+            .inodes
+            .iter()
+            .map(|(id, ino)| {
+                let file = self.files.get(id);
+                serde_json::json!({
+                    "inode": id.0,
+                    "parent": ino.parent.0,
+                    "name": file.map(|f| f.attributes().name.to_string_lossy()),
+                    "etag": file.map(|f| f.etag()),
+                    "state": file.map(|f| format!("{:?}", f.attributes().state())),
+                    "lookups": ino.lookups,
+                    "complete": ino.complete,
+                    "pinned": ino.pinned,
+                })
+            })
+            .collect();
+        serde_json::Value::from(entries).to_string() + "\n"
+    }
+
+    /// Content of a read-only file of the control directory, generated anew on every read
+    fn control_file_data(&self, inode: u64) -> Option<String> {
+        match inode {
+            METRICS_INODE => Some(metrics::render()),
+            INODES_INODE => Some(self.dump_inodes()),
+            DELETE_REPORT_INODE => {
+                let report = self.delete_report.lock().unwrap_or_else(|e| e.into_inner());
+                Some(report.to_string() + "\n")
+            }
+            _ => None,
+        }
+    }
+
+    /// Attributes of the control directory, its command files and its read-only files
+    fn control_attr(&self, inode: u64) -> Option<FileAttr> {
+        let file = match inode {
+            CONTROL_DIR_INODE => File::synthetic(CONTROL_DIR_NAME, true),
+            CONFLICTS_DIR_INODE => File::synthetic(CONFLICTS_DIR_NAME, true),
+            SCRATCH_DIR_INODE => File::synthetic(SCRATCH_DIR_NAME, true),
+            METRICS_INODE | INODES_INODE | DELETE_REPORT_INODE => {
+                let name = match inode {
+                    METRICS_INODE => METRICS_FILE_NAME,
+                    INODES_INODE => INODES_FILE_NAME,
+                    _ => DELETE_REPORT_FILE_NAME,
+                };
+                let mut file = File::synthetic(name, false);
+                file.attr.size = self.control_file_data(inode).unwrap_or_default().len() as u64;
+                file
+            }
+            _ if self.scratch.contains(inode) => {
+                let (size, mtime) = self.scratch.attributes(inode)?;
+                // The name is not part of the attributes
+                let mut file = File::synthetic("", false);
+                file.attr.size = size;
+                file.attr.mtime = mtime;
+                file
+            }
+            _ => File::synthetic(ControlCommand::from_inode(inode)?.file_name(), false),
+        };
+        let nlink = match inode {
+            CONTROL_DIR_INODE => 4,
+            CONFLICTS_DIR_INODE | SCRATCH_DIR_INODE => 2,
+            _ => 1,
+        };
+        let mut attr = file.to_file_attr(InodeId(inode), nlink, &self.config);
+        // Commands like leaving read-only mode have to stay writable on read-only mounts, and
+        // scratch files are never uploaded anyway
+        let scratch = inode == SCRATCH_DIR_INODE || self.scratch.contains(inode);
+        if ControlCommand::from_inode(inode).is_some() || scratch {
+            attr.perm |= 0o200;
+        }
+        Some(attr)
+    }
+
+    /// Entries of the control directory in the format `readdir_` returns
+    fn control_readdir(&self, offset: i64) -> Vec<(InodeId, i64, FileType, OsString)> {
+        let dot_entries = [
+            (InodeId(CONTROL_DIR_INODE), FileType::Directory, ".".into()),
+            (InodeId(FUSE_ROOT_ID), FileType::Directory, "..".into()),
+        ];
+        let commands = ControlCommand::ALL
+            .iter()
+            .map(|c| (InodeId(c.inode()), FileType::RegularFile, c.file_name().into()));
+        let metrics = (InodeId(METRICS_INODE), FileType::RegularFile, METRICS_FILE_NAME.into());
+        let inodes = (InodeId(INODES_INODE), FileType::RegularFile, INODES_FILE_NAME.into());
+        let delete_report = (
+            InodeId(DELETE_REPORT_INODE),
+            FileType::RegularFile,
+            DELETE_REPORT_FILE_NAME.into(),
+        );
+        let conflicts = (
+            InodeId(CONFLICTS_DIR_INODE),
+            FileType::Directory,
+            CONFLICTS_DIR_NAME.into(),
+        );
+        let scratch = (
+            InodeId(SCRATCH_DIR_INODE),
+            FileType::Directory,
+            SCRATCH_DIR_NAME.into(),
+        );
+        dot_entries
+            .into_iter()
+            .chain(commands)
+            .chain([metrics, inodes, delete_report, conflicts, scratch])
+            .enumerate()
+            .skip(offset.try_into().unwrap_or_default())
+            .map(|(idx, (inode, kind, name))| (inode, idx as i64 + 1, kind, name))
+            .collect()
+    }
+
+    /// Entries of the scratch directory in the format `readdir_` returns
+    fn scratch_readdir(&self, offset: i64) -> Vec<(InodeId, i64, FileType, OsString)> {
+        let dot_entries = [
+            (InodeId(SCRATCH_DIR_INODE), FileType::Directory, ".".into()),
+            (InodeId(CONTROL_DIR_INODE), FileType::Directory, "..".into()),
+        ];
+        let files = self
+            .scratch
+            .entries()
+            .map(|(inode, name)| (InodeId(inode), FileType::RegularFile, name.to_os_string()));
+        dot_entries
+            .into_iter()
+            .chain(files)
+            .enumerate()
+            .skip(offset.try_into().unwrap_or_default())
+            .map(|(idx, (inode, kind, name))| (inode, idx as i64 + 1, kind, name))
+            .collect()
+    }
+
+    /// Known conflicted copies with the names of their entries in the conflict inbox. Only
+    /// listed directories are covered, the recursive listing at mount time lists all of them
+    fn conflicted_copies(&self) -> Vec<(InodeId, String)> {
+        self.files
+            .iter()
+            .filter(|(_, file)| file.conflict_of().is_some())
+            .filter_map(|(id, _)| {
+                let path = self.full_path_of_inode(id).ok()?;
+                Some((*id, conflicts::entry_name(&path)))
+            })
+            .collect()
+    }
+
+    /// Entries of the conflict inbox in the format `readdir_` returns. They are hard links to
+    /// the conflicted copies, so they can be read and inspected right there
+    fn conflicts_readdir(&self, offset: i64) -> Vec<(InodeId, i64, FileType, OsString)> {
+        let dot_entries = [
+            (InodeId(CONFLICTS_DIR_INODE), FileType::Directory, ".".into()),
+            (InodeId(CONTROL_DIR_INODE), FileType::Directory, "..".into()),
+        ];
+        let copies = self
+            .conflicted_copies()
+            .into_iter()
+            .map(|(inode, name)| (inode, FileType::RegularFile, name.into()));
+        dot_entries
+            .into_iter()
+            .chain(copies)
+            .enumerate()
+            .skip(offset.try_into().unwrap_or_default())
+            .map(|(idx, (inode, kind, name))| (inode, idx as i64 + 1, kind, name))
+            .collect()
+    }
+
+    /// Looks up an entry of the conflict inbox, which is the conflicted copy itself
+    fn lookup_conflict(&mut self, name: &OsStr) -> Result<FileAttr, Errors> {
+        let inode = self
+            .conflicted_copies()
+            .into_iter()
+            .find(|(_, entry)| OsStr::new(entry) == name)
+            .map(|(inode, _)| inode)
+            .ok_or_else(|| Errors::FileDoesNotExist(name.into()))?;
+        let file = self
+            .files
+            .get(&inode)
+            .ok_or(Errors::ChildInodeNotFound(inode))?;
+        let attr = file.to_file_attr(inode, self.nlink(inode), &self.config);
+        if let Some(ino) = self.inodes.get_mut(&inode) {
+            ino.lookups += 1;
+        }
+        Ok(attr)
+    }
+
+    /// Resolves a filesystem-absolute path to its inode, fetching listings of directories
+    /// along the way that are not known completely
+    fn resolve_path(&mut self, path: &str) -> Result<InodeId, Errors> {
+        let mut current = InodeId(FUSE_ROOT_ID);
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            let name = OsStr::new(segment);
+            let ino = self
+                .inodes
+                .get(&current)
+                .ok_or(Errors::InodeNotFound(current))?;
+            current = match ino.find_child(name, &self.config) {
+                Some(child) => child,
+                None if !ino.complete => {
+                    self.refresh_directory(current)?;
+                    self.inodes[&current]
+                        .find_child(name, &self.config)
+                        .ok_or(Errors::FileDoesNotExist(name.into()))?
+                }
+                None => return Err(Errors::FileDoesNotExist(name.into())),
+            };
+        }
+        Ok(current)
+    }
+
+    /// The inode itself if it is a directory, its parent otherwise
+    fn directory_of(&self, inode: InodeId) -> Result<InodeId, Errors> {
+        let file = self.files.get(&inode).ok_or(Errors::InodeNotFound(inode))?;
+        if file.attributes().is_directory {
+            return Ok(inode);
+        }
+        Ok(self
+            .inodes
+            .get(&inode)
+            .ok_or(Errors::InodeNotFound(inode))?
+            .parent)
+    }
+
+    /// Inodes of the subtree below `inode` that are currently known, including itself
+    fn known_subtree(&self, inode: InodeId) -> Vec<InodeId> {
+        let mut subtree = vec![inode];
+        let mut idx = 0;
+        while idx < subtree.len() {
+            if let Some(ino) = self.inodes.get(&subtree[idx]) {
+                subtree.extend(ino.children.values());
+            }
+            idx += 1;
+        }
+        subtree
+    }
+
+    /// Executes a command written to one of the files of the control directory
+    fn run_control_command(&mut self, command: ControlCommand, path: &str) -> Result<(), Errors> {
+        let inode = self.resolve_path(path)?;
+        info!("Control command {:?} on {}", command, path);
+        self.apply_control_command(command, inode)
+    }
+
+    fn apply_control_command(
+        &mut self,
+        command: ControlCommand,
+        inode: InodeId,
+    ) -> Result<(), Errors> {
+        match command {
+            ControlCommand::Refresh => {
+                let directory = self.directory_of(inode)?;
+                self.refresh_directory(directory)?;
+            }
+            ControlCommand::Pin | ControlCommand::Evict => {
+                let pin = command == ControlCommand::Pin;
+                for id in self.known_subtree(inode) {
+                    if let Some(ino) = self.inodes.get_mut(&id) {
+                        ino.pinned = pin;
+                        if !pin && !self.config.snapshot {
+                            ino.complete = false;
+                        }
+                    }
+                }
+            }
+            ControlCommand::Unpin => {
+                for id in self.known_subtree(inode) {
+                    if let Some(ino) = self.inodes.get_mut(&id) {
+                        ino.pinned = false;
+                    }
+                }
+            }
+            ControlCommand::Delete => {
+                if let Some((full_path, entries)) = self.delete_tree_(inode)? {
+                    self.delete_entries(&full_path, entries)?;
+                }
+            }
+            ControlCommand::SyncNow => {
+                let directory = self.directory_of(inode)?;
+                self.refresh_directory(directory)?;
+                // Listings of the subtree replace children, so collect directories afterwards
+                for id in self.known_subtree(directory).into_iter().skip(1) {
+                    let is_listed = self.inodes.get(&id).map_or(false, |ino| ino.complete);
+                    if is_listed {
+                        self.refresh_directory(id)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes `inode` with everything below it. A single DELETE removes a whole collection on
+    /// most servers, the report does not know how many entries that were. Servers that do not
+    /// support it, see `delete::is_unsupported`, get one DELETE per entry: the entries of the
+    /// tree are returned with its path for `delete_entries` or `start_delete_entries` then.
+    /// Other errors, like a forbidden or locked collection, are returned as they are
+    fn delete_tree_(&mut self, inode: InodeId) -> Result<Option<(String, Vec<String>)>, Errors> {
+        let report = self.delete_report.lock().unwrap_or_else(|e| e.into_inner());
+        if report["finished"] == false {
+            let path = report["path"].as_str().unwrap_or_default();
+            return Err(Errors::DeleteInProgress(path.into()));
+        }
+        drop(report);
+        if self.config.read_only {
+            return Err(Errors::ReadOnlyBackend(format!("inode {}", inode.0)));
+        }
+        if inode.is_filesystem_root() {
+            return Err(Errors::InvalidArguments("the root cannot be deleted".into()));
+        }
+        let full_path = self.full_path_of_inode(&inode)?;
+        match self.drive.delete(&full_path) {
+            Ok(()) => {}
+            Err(Errors::HttpStatus(404)) => return Err(Errors::FileDoesNotExist(full_path.into())),
+            Err(err) if delete::is_unsupported(&err) => {
+                info!(
+                    "Deleting {} at once failed with {:?}, deleting entry by entry",
+                    full_path, err
+                );
+                let entries = self.list_subtree(&full_path)?;
+                return Ok(Some((full_path, entries)));
+            }
+            Err(err) => return Err(err),
+        }
+        *self.delete_report.lock().unwrap_or_else(|e| e.into_inner()) = serde_json::json!({
+            "path": full_path,
+            "total": null,
+            "done": null,
+            "deleted": null,
+            "finished": true,
+            "failed": [],
+            "results": [],
+        });
+        self.forget_deleted(&full_path, 0)?;
+        Ok(None)
+    }
+
+    /// Deletes the `entries` of the tree at `full_path` level by level while the kernel waits
+    fn delete_entries(&mut self, full_path: &str, entries: Vec<String>) -> Result<(), Errors> {
+        let drive = &self.drive;
+        let failed = delete::delete_levels(full_path, entries, &self.delete_report, |level| {
+            level.iter().map(|entry| drive.delete(entry)).collect()
+        });
+        self.forget_deleted(full_path, failed)
+    }
+
+    /// Deletes the `entries` of the tree at `full_path` on `deletes` and answers `reply` once
+    /// all levels are done. The tree is dropped with the next operation after that
+    fn start_delete_entries(
+        &self,
+        deletes: Arc<Deletes>,
+        full_path: String,
+        entries: Vec<String>,
+        reply: ReplyIoctl,
+    ) {
+        let report = self.delete_report.clone();
+        let finished = self.finished_deletes.0.clone();
+        // Running from now on, another delete must not start before the thread does
+        *report.lock().unwrap_or_else(|e| e.into_inner()) = serde_json::json!({
+            "path": full_path,
+            "finished": false,
+        });
+        thread::spawn(move || {
+            let failed = delete::delete_levels(&full_path, entries, &report, |level| {
+                deletes.delete_all(level)
+            });
+            let _ = finished.send((full_path, failed));
+            match failed {
+                0 => reply.ioctl(0, &[]),
+                n => reply.error(Errors::PartiallyDeleted(n).into()),
+            }
+        });
+    }
+
+    /// Drops the tree at `full_path` after it was deleted on the server. If `failed` entries
+    /// are left, they show up again with the next listing of the parent
+    fn forget_deleted(&mut self, full_path: &str, failed: usize) -> Result<(), Errors> {
+        let inode = self.known_inode(full_path);
+        let parent = inode.and_then(|inode| self.inodes.get(&inode)).map(|ino| ino.parent);
+        if let Some(inode) = inode {
+            if let Some(parent) = parent.and_then(|parent| self.inodes.get_mut(&parent)) {
+                parent.children.retain(|_, child| *child != inode);
+            }
+            self.remove_subtree(inode);
+        }
+        if let Err(err) = self.state.forget(full_path) {
+            warn!("Could not persist removal of {}: {:?}", full_path, err);
+        }
+        if failed == 0 {
+            return Ok(());
+        }
+        if let Some(parent) = parent {
+            self.refresh_directory(parent)?;
+        }
+        warn!("Could not delete {} entries below {}", failed, full_path);
+        Err(Errors::PartiallyDeleted(failed))
+    }
+
+    /// Paths of `full_path` and everything below it on the server, deepest first. Lists one
+    /// directory at a time, many servers refuse recursive listings
+    fn list_subtree(&self, full_path: &str) -> Result<Vec<String>, Errors> {
+        let mut entries = vec![full_path.to_string()];
+        let mut pending = vec![full_path.to_string()];
+        while let Some(dir) = pending.pop() {
+            for prop in self.drive.list(&dir, PropfindDepth::WithChildren)? {
+                let entry = prop.path().to_string_lossy().trim_end_matches('/').to_string();
+                if entry == dir.trim_end_matches('/') {
+                    continue;
+                }
+                if prop.resource_type() == ResourceType::Collection {
+                    pending.push(entry.clone());
+                }
+                entries.push(entry);
+            }
+        }
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.matches('/').count()));
+        Ok(entries)
+    }
+
+    /// State flags of an inode as returned by `IOCTL_GET_STATE`
+    fn state_flags(&self, inode: InodeId) -> Result<u32, Errors> {
+        let ino = self.inodes.get(&inode).ok_or(Errors::InodeNotFound(inode))?;
+        let file = self.files.get(&inode).ok_or(Errors::FileEntryMissing(inode))?;
+        let mut flags = 0;
+        if ino.pinned {
+            flags |= control::STATE_PINNED;
+        }
+        if ino.complete {
+            flags |= control::STATE_LISTED;
+        }
+        if !file.attributes().state().is_clean() {
+            flags |= control::STATE_DIRTY;
+        }
+        Ok(flags)
+    }
+
+    /// Returns the filesystem-absolute path of an inode by traversing the inode tree upwards
+    fn full_path_of_inode(&self, inode: &InodeId) -> Result<String, Errors> {
+        let mut segments: Vec<String> = vec![];
+        let mut current = *inode;
+
+        while !current.is_filesystem_root() {
+            let name = self
+                .files
+                .get(&current)
+                .ok_or(Errors::FileEntryMissing(current))?
+                .remote_name()
+                .to_os_string();
+            segments.push(name.into_string().map_err(Errors::NonUnicodeInPath)?);
+            current = self
+                .inodes
+                .get(&current)
+                .ok_or(Errors::ParentInodeNotFound(current))?
+                .parent;
+        }
+
+        segments.reverse();
+        Ok(path::join(&segments))
+    }
+}
+
+impl Filesystem for FuseFilesystem {
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let _op = inflight::start("opendir", ino, req.pid());
+        guarded("opendir", ino, || {
+            self.apply_config_updates();
+            self.apply_remote_changes();
+            self.apply_finished_deletes();
+            if matches!(ino, CONTROL_DIR_INODE | CONFLICTS_DIR_INODE | SCRATCH_DIR_INODE) {
+                return reply.opened(0, 0);
+            }
+            match self.opendir_(InodeId(ino)) {
+                Ok(fh) => reply.opened(fh.0, 0),
+                Err(err) => {
+                    warn!("Opening directory {} failed: {:?}", ino, err);
+                    reply.error(err.into())
+                }
+            }
+        })
+    }
+
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        let _op = inflight::start("releasedir", ino, req.pid());
+        guarded("releasedir", ino, || {
+            self.dir_handles.remove(&FileHandleId(fh));
+            reply.ok();
+        })
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let _op = inflight::start("readdir", ino, req.pid());
+        guarded("readdir", ino, || {
+            self.apply_config_updates();
+            let files_in_dir = match ino {
+                CONTROL_DIR_INODE => self.control_readdir(offset),
+                CONFLICTS_DIR_INODE => self.conflicts_readdir(offset),
+                SCRATCH_DIR_INODE => self.scratch_readdir(offset),
+                _ => match self.readdir_(FileHandleId(fh), offset) {
+                    Ok(entries) => entries,
+                    Err(err) => {
+                        warn!("Reading directory {} failed: {:?}", ino, err);
+                        return reply.error(err.into());
+                    }
+                },
+            };
+            for (inode, next_offset, kind, name) in files_in_dir {
+                let full = reply.add(inode.0, next_offset, kind, &name);
+                if full {
+                    break;
+                }
+            }
+            reply.ok();
+        })
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        let _op = inflight::start("getattr", ino, req.pid());
+        guarded("getattr", ino, || {
+            self.apply_config_updates();
+            self.apply_remote_changes();
+            self.apply_finished_deletes();
+            if let Some(attr) = self.control_attr(ino) {
+                return reply.attr(&self.config.attr_ttl, &attr);
+            }
+            match self.getattributes(InodeId(ino)) {
+                Ok(attr) => reply.attr(&self.config.attr_ttl, &attr),
+                Err(err) => reply.error(err.into()),
+            }
+        })
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let _op = inflight::start("access", ino, req.pid());
+        guarded("access", ino, || {
+            self.apply_config_updates();
+            let perm = match self.control_attr(ino) {
+                Some(attr) => attr.perm,
+                None if mask & libc::W_OK != 0 && self.config.read_only => {
+                    return reply.error(libc::EROFS)
+                }
+                None => match self.getattributes(InodeId(ino)) {
+                    Ok(attr) => attr.perm,
+                    Err(err) => return reply.error(err.into()),
+                },
+            };
+            // Every entry has the same owner, root is treated like it
+            let shift = match (req.uid(), req.gid()) {
+                (uid, _) if uid == self.config.uid || uid == 0 => 6,
+                (_, gid) if gid == self.config.gid => 3,
+                _ => 0,
+            };
+            let denied = [(libc::R_OK, 0o4), (libc::W_OK, 0o2), (libc::X_OK, 0o1)]
+                .iter()
+                .any(|(flag, bit)| mask & flag != 0 && (perm >> shift) & bit == 0);
+            match denied {
+                true => reply.error(libc::EACCES),
+                false => reply.ok(),
+            }
+        })
+    }
+
+    fn lookup(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        reply: ReplyEntry,
+    ) {
+        let _op = inflight::start("lookup", parent, req.pid());
+        guarded("lookup", parent, || {
+            self.apply_config_updates();
+            self.apply_remote_changes();
+            self.apply_finished_deletes();
+            let control_inode = match parent {
+                FUSE_ROOT_ID if name == CONTROL_DIR_NAME => Some(CONTROL_DIR_INODE),
+                CONTROL_DIR_INODE if name == METRICS_FILE_NAME => Some(METRICS_INODE),
+                CONTROL_DIR_INODE if name == INODES_FILE_NAME => Some(INODES_INODE),
+                CONTROL_DIR_INODE if name == DELETE_REPORT_FILE_NAME => Some(DELETE_REPORT_INODE),
+                CONTROL_DIR_INODE if name == CONFLICTS_DIR_NAME => Some(CONFLICTS_DIR_INODE),
+                CONTROL_DIR_INODE if name == SCRATCH_DIR_NAME => Some(SCRATCH_DIR_INODE),
+                CONTROL_DIR_INODE => ControlCommand::from_file_name(name).map(|c| c.inode()),
+                SCRATCH_DIR_INODE => self.scratch.lookup(name),
+                _ => None,
+            };
+            if let Some(attr) = control_inode.and_then(|ino| self.control_attr(ino)) {
+                reply.entry(&self.config.attr_ttl, &attr, 0);
+            } else if parent == CONTROL_DIR_INODE || parent == SCRATCH_DIR_INODE {
+                reply.error(libc::ENOENT);
+            } else if parent == CONFLICTS_DIR_INODE {
+                match self.lookup_conflict(name) {
+                    Ok(attr) => {
+                        reply.entry(&self.config.attr_ttl, &attr, self.generation_of(attr.ino))
+                    }
+                    Err(err) => reply.error(err.into()),
+                }
+            } else {
+                match self.lookup_(InodeId(parent), name) {
+                    Ok(attr) => {
+                        reply.entry(&self.config.attr_ttl, &attr, self.generation_of(attr.ino))
+                    }
+                    // Inode 0 makes the kernel cache the miss, so probes for swap files or
+                    // commands on the PATH do not come back for every stat
+                    Err(Errors::FileDoesNotExist(_)) if !self.config.negative_ttl.is_zero() => {
+                        reply.entry(&self.config.negative_ttl, &negative_entry(), 0)
+                    }
+                    Err(err) => reply.error(err.into()),
+                }
+            }
+        })
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let _op = inflight::start("open", ino, req.pid());
+        guarded("open", ino, || {
+            self.apply_remote_changes();
+            self.apply_finished_deletes();
+            // Generated files change all the time, the kernel must not serve them from its cache
+            if self.control_file_data(ino).is_some() {
+                return reply.opened(0, fuser::consts::FOPEN_DIRECT_IO);
+            }
+            if self.control_attr(ino).is_some() {
+                return reply.opened(0, 0);
+            }
+            let writing = flags & libc::O_ACCMODE != libc::O_RDONLY;
+            if writing && self.config.read_only {
+                return reply.error(libc::EROFS);
+            }
+            match self.open_(InodeId(ino), flags) {
+                Ok(fh) => reply.opened(fh.0, 0),
+                Err(err) => reply.error(err.into()),
+            }
+        })
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let _op = inflight::start("create", parent, req.pid());
+        guarded("create", parent, || {
+            if parent == SCRATCH_DIR_INODE {
+                let attr = self.scratch.create(name).map(|ino| self.control_attr(ino));
+                return match attr {
+                    Ok(Some(attr)) => reply.created(&self.config.attr_ttl, &attr, 0, 0, 0),
+                    _ => reply.error(libc::EEXIST),
+                };
+            }
+            if self.config.read_only {
+                return reply.error(libc::EROFS);
+            }
+            match self.create_(InodeId(parent), name, flags) {
+                Ok((attr, fh)) => {
+                    let generation = self.generation_of(attr.ino);
+                    reply.created(&self.config.attr_ttl, &attr, generation, fh.0, 0)
+                }
+                Err(Errors::FileExists(_)) => reply.error(libc::EEXIST),
+                Err(err) => {
+                    warn!("Creating {:?} failed: {:?}", name, err);
+                    reply.error(err.into())
+                }
+            }
+        })
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let _op = inflight::start("mknod", parent, req.pid());
+        guarded("mknod", parent, || {
+            if self.config.read_only {
+                return reply.error(libc::EROFS);
+            }
+            // Devices, FIFOs and sockets have no counterpart on the server
+            if mode & libc::S_IFMT as u32 != libc::S_IFREG as u32 {
+                return reply.error(libc::EPERM);
+            }
+            match self.create_(InodeId(parent), name, libc::O_WRONLY) {
+                Ok((attr, fh)) => {
+                    self.handles.remove(&fh);
+                    reply.entry(&self.config.attr_ttl, &attr, self.generation_of(attr.ino))
+                }
+                Err(Errors::FileExists(_)) => reply.error(libc::EEXIST),
+                Err(err) => {
+                    warn!("Creating {:?} failed: {:?}", name, err);
+                    reply.error(err.into())
+                }
+            }
+        })
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let _op = inflight::start("flush", ino, req.pid());
+        guarded("flush", ino, || {
+            if fh == 0 {
+                return reply.ok();
+            }
+            match self.flush_(FileHandleId(fh)) {
+                Ok(()) => reply.ok(),
+                Err(err) => {
+                    warn!("Uploading inode {} failed: {:?}", ino, err);
+                    reply.error(err.into())
+                }
+            }
+        })
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let _op = inflight::start("fsync", ino, req.pid());
+        guarded("fsync", ino, || {
+            self.apply_config_updates();
+            if fh == 0 || !self.has_write_barrier(InodeId(ino)) {
+                return reply.ok();
+            }
+            match self.flush_(FileHandleId(fh)) {
+                Ok(()) => reply.ok(),
+                Err(err) => {
+                    warn!("Uploading inode {} failed: {:?}", ino, err);
+                    reply.error(err.into())
+                }
+            }
+        })
+    }
+
+    fn release(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let _op = inflight::start("release", ino, req.pid());
+        guarded("release", ino, || {
+            if fh == 0 {
+                return reply.ok();
+            }
+            let fh = FileHandleId(fh);
+            let result = self.flush_(fh);
+            match &result {
+                Ok(()) => {}
+                Err(err) if is_retryable(err) => {
+                    warn!("Uploading inode {} failed, retrying later: {:?}", ino, err);
+                    self.block_upload(fh);
+                }
+                Err(err) => {
+                    warn!("Uploading inode {} failed, its changes are lost: {:?}", ino, err)
+                }
+            }
+            self.handles.remove(&fh);
+            match result {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.into()),
+            }
+        })
+    }
+
+    fn read(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let _op = inflight::start("read", ino, req.pid());
+        guarded("read", ino, || {
+            let slice = |data: &[u8]| {
+                let start = (offset.max(0) as usize).min(data.len());
+                let end = start.saturating_add(size as usize).min(data.len());
+                data[start..end].to_vec()
+            };
+            if let Some(data) = self.control_file_data(ino) {
+                return reply.data(&slice(data.as_bytes()));
+            }
+            if let Some(data) = self.scratch.read(ino) {
+                return reply.data(&slice(data));
+            }
+            // Files that were written to are read from their buffer, which may be ahead of the
+            // server
+            let buffered = self.handles.get(&FileHandleId(fh)).and_then(|h| h.data.as_ref());
+            let buffered = buffered.or_else(|| self.blocked.get(&InodeId(ino)).map(|b| &b.data));
+            if let Some(data) = buffered {
+                return reply.data(&slice(data));
+            }
+            let offset = offset.max(0) as u64;
+            let (path, version, size) = match self.remote_range(InodeId(ino), offset, size.into()) {
+                Ok(Some(range)) => range,
+                Ok(None) => return reply.data(&[]),
+                Err(Errors::InodeNotFound(_)) => return reply.error(libc::ENOENT),
+                Err(err) => {
+                    warn!("Reading inode {} failed: {:?}", ino, err);
+                    return reply.error(err.into());
+                }
+            };
+            let download = Download {
+                inode: ino,
+                pid: req.pid(),
+                path,
+                version,
+                offset,
+                size,
+                reply,
+            };
+            let Some(downloads) = &self.downloads else {
+                return download.run(&self.drive);
+            };
+            if let Err(download) = downloads.start(download) {
+                download.run(&self.drive);
+            }
+        })
+    }
+
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let _op = inflight::start("readlink", ino, req.pid());
+        guarded("readlink", ino, || {
+            match self.readlink_(InodeId(ino)) {
+                Ok(target) => reply.data(target.as_bytes()),
+                Err(err) => reply.error(err.into()),
+            }
+        })
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let _op = inflight::start("mkdir", parent, req.pid());
+        guarded("mkdir", parent, || {
+            if parent == SCRATCH_DIR_INODE {
+                return reply.error(libc::EPERM);
+            }
+            if self.config.read_only {
+                return reply.error(libc::EROFS);
+            }
+            match self.mkdir_(InodeId(parent), name) {
+                Ok(attr) => reply.entry(&self.config.attr_ttl, &attr, self.generation_of(attr.ino)),
+                Err(Errors::HttpStatus(405)) => reply.error(libc::EEXIST),
+                Err(Errors::ReadOnlyBackend(_)) => reply.error(libc::EROFS),
+                Err(err) => {
+                    warn!("Creating directory {:?} failed: {:?}", name, err);
+                    reply.error(err.into())
+                }
+            }
+        })
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        let _op = inflight::start("symlink", parent, req.pid());
+        guarded("symlink", parent, || {
+            if self.config.read_only {
+                return reply.error(libc::EROFS);
+            }
+            match self.symlink_(InodeId(parent), name, link) {
+                Ok(attr) => reply.entry(&self.config.attr_ttl, &attr, self.generation_of(attr.ino)),
+                Err(err) => {
+                    warn!("Creating symlink {:?} failed: {:?}", name, err);
+                    reply.error(err.into())
+                }
+            }
+        })
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _op = inflight::start("unlink", parent, req.pid());
+        guarded("unlink", parent, || {
+            if parent == SCRATCH_DIR_INODE {
+                return match self.scratch.unlink(name) {
+                    Ok(()) => reply.ok(),
+                    Err(_) => reply.error(libc::ENOENT),
+                };
+            }
+            if self.config.read_only {
+                return reply.error(libc::EROFS);
+            }
+            match self.remove_(InodeId(parent), name, false) {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(remove_errno(name, err)),
+            }
+        })
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _op = inflight::start("rmdir", parent, req.pid());
+        guarded("rmdir", parent, || {
+            if self.config.read_only {
+                return reply.error(libc::EROFS);
+            }
+            match self.remove_(InodeId(parent), name, true) {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(remove_errno(name, err)),
+            }
+        })
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let _op = inflight::start("rename", parent, req.pid());
+        guarded("rename", parent, || {
+            match (parent == SCRATCH_DIR_INODE, newparent == SCRATCH_DIR_INODE) {
+                (true, true) => {
+                    let replacing = self.scratch.lookup(newname).is_some();
+                    if flags & platform::RENAME_EXCHANGE != 0 {
+                        return reply.error(libc::EINVAL);
+                    }
+                    if replacing && flags & platform::RENAME_NOREPLACE != 0 {
+                        return reply.error(libc::EEXIST);
+                    }
+                    return match self.scratch.rename(name, newname) {
+                        Ok(()) => reply.ok(),
+                        Err(_) => reply.error(libc::ENOENT),
+                    }
+                }
+                // Moving in or out of the scratch directory needs a copy, like between filesystems
+                (true, false) | (false, true) => return reply.error(libc::EXDEV),
+                (false, false) => {}
+            }
+            if self.config.read_only {
+                return reply.error(libc::EROFS);
+            }
+            // WebDAV cannot swap two entries in one request
+            if flags & platform::RENAME_EXCHANGE != 0 {
+                return reply.error(libc::EINVAL);
+            }
+            match self.rename_(InodeId(parent), name, InodeId(newparent), newname, flags) {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(remove_errno(name, err)),
+            }
+        })
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        let _op = inflight::start("statfs", ino, req.pid());
+        guarded("statfs", ino, || {
+            let quota = self.quota_().unwrap_or_else(|err| {
+                warn!("Fetching the quota failed: {:?}", err);
+                None
+            });
+            // Without a limit there is nothing meaningful to report, df shows zeros then
+            let quota = quota.unwrap_or_default();
+            let block_size = u64::from(self.config.block_size.max(1));
+            reply.statfs(
+                (quota.used + quota.available) / block_size,
+                quota.available / block_size,
+                quota.available / block_size,
+                0,
+                0,
+                self.config.block_size,
+                255,
+                self.config.block_size,
+            );
+        })
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request<'_>,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        let _op = inflight::start("copy_file_range", ino_out, req.pid());
+        guarded("copy_file_range", ino_out, || {
+            if self.config.read_only {
+                return reply.error(libc::EROFS);
+            }
+            let target = (InodeId(ino_out), FileHandleId(fh_out));
+            let offsets = (offset_in.max(0) as u64, offset_out.max(0) as u64);
+            match self.copy_file_range_(InodeId(ino_in), target, offsets, len) {
+                Ok(copied) => reply.written(copied),
+                // The kernel copies through read and write for these
+                Err(Errors::ServerCopyUnsupported) | Err(Errors::MoveAcrossRemotes(_)) => {
+                    reply.error(libc::EOPNOTSUPP)
+                }
+                Err(err) => {
+                    warn!("Copying inode {} to {} failed: {:?}", ino_in, ino_out, err);
+                    reply.error(err.into())
+                }
+            }
+        })
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        guarded("forget", ino, || self.forget_(InodeId(ino), nlookup));
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let _op = inflight::start("setattr", ino, req.pid());
+        guarded("setattr", ino, || {
+            if self.scratch.contains(ino) {
+                if let Some(size) = size {
+                    self.scratch.truncate(ino, size);
+                }
+                if let Some(TimeOrNow::SpecificTime(mtime)) = mtime {
+                    self.scratch.set_mtime(ino, mtime);
+                }
+            }
+            // Command files are truncated by shell redirections before they are written to
+            if let Some(attr) = self.control_attr(ino) {
+                return reply.attr(&self.config.attr_ttl, &attr);
+            }
+            let inode = InodeId(ino);
+            if size.is_none() && mtime.is_none() {
+                // Modes, owners and access times cannot be stored on the server, keep what it
+                // reports
+                return match self.getattributes(inode) {
+                    Ok(attr) => reply.attr(&self.config.attr_ttl, &attr),
+                    Err(err) => reply.error(err.into()),
+                };
+            }
+            if self.config.read_only {
+                return reply.error(libc::EROFS);
+            }
+            let mtime = mtime.map(|mtime| match mtime {
+                TimeOrNow::SpecificTime(time) => time,
+                TimeOrNow::Now => SystemTime::now(),
+            });
+            let truncated = match size {
+                Some(size) => self.truncate_(inode, fh.map(FileHandleId), size),
+                None => self.getattributes(inode),
+            };
+            let result = match mtime {
+                Some(mtime) => truncated.and_then(|_| self.set_mtime_(inode, mtime)),
+                None => truncated,
+            };
+            match result {
+                Ok(attr) => reply.attr(&self.config.attr_ttl, &attr),
+                Err(Errors::IsADirectory(_)) => reply.error(libc::EISDIR),
+                Err(Errors::FileEntryMissing(_)) => reply.error(libc::ENOENT),
+                Err(err) => {
+                    warn!("Changing attributes of inode {} failed: {:?}", ino, err);
+                    reply.error(err.into())
+                }
+            }
+        })
+    }
+
+    fn write(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let _op = inflight::start("write", ino, req.pid());
+        guarded("write", ino, || {
+            if let Some(written) = self.scratch.write(ino, offset.max(0) as u64, data) {
+                return reply.written(written);
+            }
+            if let Some(command) = ControlCommand::from_inode(ino) {
+                let Some(paths) = control::parse_paths(data) else {
+                    return reply.error(libc::EINVAL);
+                };
+                for path in paths {
+                    if let Err(err) = self.run_control_command(command, &path) {
+                        warn!("Control command {:?} on {} failed: {:?}", command, path, err);
+                        return reply.error(err.into());
+                    }
+                }
+                return reply.written(data.len() as u32);
+            }
+            if self.config.read_only {
+                return reply.error(libc::EROFS);
+            }
+            match self.write_(FileHandleId(fh), offset.max(0) as u64, data) {
+                Ok(written) => reply.written(written),
+                Err(Errors::HandleNotWritable(_)) => reply.error(libc::EBADF),
+                Err(err) => {
+                    warn!("Writing inode {} failed: {:?}", ino, err);
+                    reply.error(err.into())
+                }
+            }
+        })
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        _in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        let _op = inflight::start("ioctl", ino, req.pid());
+        guarded("ioctl", ino, || {
+            let inode = InodeId(ino);
+            if cmd == control::IOCTL_GET_STATE {
+                return match self.state_flags(inode) {
+                    Ok(flags) => reply.ioctl(0, &flags.to_ne_bytes()),
+                    Err(err) => reply.error(err.into()),
+                };
+            }
+            if cmd == control::IOCTL_SET_READ_ONLY || cmd == control::IOCTL_SET_READ_WRITE {
+                let read_only = cmd == control::IOCTL_SET_READ_ONLY;
+                if !read_only && (self.config.snapshot || self.config.as_of.is_some()) {
+                    return reply.error(libc::EROFS);
+                }
+                info!("Switching to read-{}", if read_only { "only" } else { "write" });
+                self.config.read_only = read_only;
+                return reply.ioctl(0, &[]);
+            }
+            let Some(command) = ControlCommand::from_ioctl(cmd) else {
+                return reply.error(libc::ENOTTY);
+            };
+            info!("Control command {:?} on inode {} through ioctl", command, ino);
+            if let (ControlCommand::Delete, Some(deletes)) = (command, self.deletes.clone()) {
+                return match self.delete_tree_(inode) {
+                    Ok(None) => reply.ioctl(0, &[]),
+                    Ok(Some((full_path, entries))) => {
+                        self.start_delete_entries(deletes, full_path, entries, reply)
+                    }
+                    Err(err) => {
+                        warn!("Deleting inode {} failed: {:?}", ino, err);
+                        reply.error(err.into())
+                    }
+                };
+            }
+            match self.apply_control_command(command, inode) {
+                Ok(()) => reply.ioctl(0, &[]),
+                Err(err) => {
+                    warn!("Control command {:?} on inode {} failed: {:?}", command, ino, err);
+                    reply.error(err.into())
+                }
+            }
+        })
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let _op = inflight::start("getxattr", ino, req.pid());
+        guarded("getxattr", ino, || {
+            match self.getxattr_(InodeId(ino), name) {
+                Ok(value) => reply_xattr(reply, &value, size),
+                Err(err) => reply.error(err.into()),
+            }
+        })
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let _op = inflight::start("setxattr", ino, req.pid());
+        guarded("setxattr", ino, || {
+            if self.config.read_only {
+                return reply.error(libc::EROFS);
+            }
+            match self.setxattr_(InodeId(ino), name, value) {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.into()),
+            }
+        })
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let _op = inflight::start("listxattr", ino, req.pid());
+        guarded("listxattr", ino, || {
+            match self.listxattr_(InodeId(ino)) {
+                Ok(names) => reply_xattr(reply, &names, size),
+                Err(err) => reply.error(err.into()),
+            }
+        })
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _op = inflight::start("removexattr", ino, req.pid());
+        guarded("removexattr", ino, || {
+            if self.config.read_only {
+                return reply.error(libc::EROFS);
+            }
+            match self.removexattr_(InodeId(ino), name) {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.into()),
+            }
+        })
+    }
+}
+
+/// Whether an upload that failed with `err` may succeed later without anything changing at the
+/// mountpoint: the server had no room, could not be reached or the mount was switched to
+/// read-only. Uploads the server refuses for other reasons fail the same way every time
+fn is_retryable(err: &Errors) -> bool {
+    matches!(
+        err,
+        Errors::HttpStatus(502 | 503 | 504)
+            | Errors::InsufficientStorage(_)
+            | Errors::QuotaExceeded(_)
+            | Errors::ServerUnreachable(_)
+            | Errors::WebDavReqeustFailed
+            | Errors::ReadOnlyBackend(_)
+    )
+}
+
+/// How long a blocked upload waits after failing `attempts` times
+fn upload_retry_wait(attempts: u32) -> Duration {
+    let wait = UPLOAD_RETRY_INTERVAL.saturating_mul(1 << attempts.saturating_sub(1).min(16));
+    wait.min(MAX_UPLOAD_RETRY_INTERVAL)
+}
+
+/// Runs the body of the FUSE handler `name` on `ino` and keeps a panic in it from taking the
+/// whole session down. The reply the handler dropped while unwinding answers the kernel with
+/// EIO, so only this operation fails. The state may be left half updated, which beats a wedged
+/// mount
+fn guarded(name: &str, ino: u64, handler: impl FnOnce()) {
+    let Err(panic) = panic::catch_unwind(AssertUnwindSafe(handler)) else {
+        return;
+    };
+    let message = match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message,
+        (_, Some(message)) => message.as_str(),
+        _ => "no message",
+    };
+    error!("{} on inode {} panicked: {}", name, ino, message);
+}
+
+/// Answers getxattr and listxattr. A size of zero asks for the length of the data only
+fn reply_xattr(reply: ReplyXattr, data: &[u8], size: u32) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() <= size as usize {
+        reply.data(data);
+    } else {
+        reply.error(libc::ERANGE);
+    }
+}
+
+/// Errno for a failed unlink, rmdir or rename. Only failures without a better errno than EIO
+/// are logged, a missing or non-empty entry is an answer rather than a problem
+fn remove_errno(name: &OsStr, err: Errors) -> libc::c_int {
+    let description = format!("{:?}", err);
+    match libc::c_int::from(err) {
+        libc::EIO => {
+            warn!("Removing or renaming {:?} failed: {}", name, description);
+            libc::EIO
+        }
+        errno => errno,
+    }
+}
+
+/// Attributes of a negative entry, only the inode of 0 counts
+fn negative_entry() -> FileAttr {
+    FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm: 0,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: 0,
+        flags: 0,
+    }
+}
+
+/// Whether `name` follows the Unix convention for hidden files
+fn is_dot_name(name: &OsStr) -> bool {
+    name.to_str().map_or(false, |name| name.starts_with('.') && name != "." && name != "..")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::Backend,
+        config::{Normalization, DEFAULT_ATTR_TTL},
+        prop::PropBuilder,
+        webdav,
+    };
+    use std::path::PathBuf;
+
+    /// Serves listings from `entries` and counts how often it was asked for them. Deleting a
+    /// collection at once is not supported, deleting anything named forbidden is forbidden, the
+    /// rest can be deleted. Uploads fail for lack of storage
+    struct FakeDrive {
+        entries: Arc<Mutex<Vec<Prop>>>,
+        listings: Arc<Mutex<u32>>,
+    }
+
+    impl Backend for FakeDrive {
+        fn list(&self, path: &str, _depth: PropfindDepth) -> Result<Vec<Prop>, Errors> {
+            *self.listings.lock().unwrap() += 1;
+            let dir = path.trim_end_matches('/');
+            let root = Prop::new("root".into(), PathBuf::from("/"), 0, ResourceType::Collection, 0);
+            Ok(std::iter::once(root)
+                .chain(self.entries.lock().unwrap().iter().cloned())
+                .filter(|prop| {
+                    let entry = prop.path().to_string_lossy();
+                    let entry = entry.trim_end_matches('/');
+                    let parent = entry.rsplit_once('/').map(|(parent, _)| parent);
+                    entry == dir || parent == Some(dir)
+                })
+                .collect())
+        }
+
+        fn put(&self, _path: &str, _data: Vec<u8>, _mtime: Option<u64>) -> Result<(), Errors> {
+            Err(webdav::status_error(507, ""))
+        }
+
+        fn delete(&self, path: &str) -> Result<(), Errors> {
+            let mut entries = self.entries.lock().unwrap();
+            let index = entries.iter().position(|prop| prop.path() == Path::new(path));
+            let index = index.ok_or(Errors::FileDoesNotExist(path.into()))?;
+            if path.contains("forbidden") {
+                return Err(webdav::status_error(403, ""));
+            }
+            if entries[index].resource_type() == ResourceType::Collection {
+                return Err(webdav::status_error(405, ""));
+            }
+            entries.remove(index);
+            Ok(())
+        }
+
+        fn get_range(&self, path: &str, _offset: u64, _size: u64) -> Result<Vec<u8>, Errors> {
+            Err(Errors::FileDoesNotExist(path.into()))
+        }
+
+        fn href(&self, path: &str) -> String {
+            path.to_string()
+        }
+    }
+
+    fn directory(path: &str, fileid: &str) -> Prop {
+        PropBuilder::new()
+            .path(path.into())
+            .etag(format!("etag-{fileid}"))
+            .resource_type(ResourceType::Collection)
+            .nextcloud_prop("fileid".into(), fileid.into())
+            .build()
+    }
+
+    fn file(path: &str, fileid: &str) -> Prop {
+        PropBuilder::new()
+            .path(path.into())
+            .etag(format!("etag-{fileid}"))
+            .resource_type(ResourceType::File)
+            .nextcloud_prop("fileid".into(), fileid.into())
+            .build()
+    }
+
+    /// A filesystem over a fake drive listing `entries`, with the handles to change the listing
+    /// and to count the requests
+    fn filesystem(
+        entries: Vec<Prop>,
+        attr_ttl: Duration,
+    ) -> (FuseFilesystem, Arc<Mutex<Vec<Prop>>>, Arc<Mutex<u32>>) {
+        let entries = Arc::new(Mutex::new(entries));
+        let listings = Arc::new(Mutex::new(0));
+        let drive = FakeDrive {
+            entries: entries.clone(),
+            listings: listings.clone(),
+        };
+        let config = Config {
+            attr_ttl,
+            ..Config::default()
+        };
+        let fs = FuseFilesystem::init(Remotes::Single(Box::new(drive)), config).unwrap();
+        (fs, entries, listings)
+    }
+
+    fn root() -> InodeId {
+        InodeId(FUSE_ROOT_ID)
+    }
+
+    /// Inodes of the entries of the root, without "." and ".."
+    fn listed(fs: &mut FuseFilesystem) -> Vec<(OsString, InodeId)> {
+        let fh = fs.opendir_(root()).unwrap();
+        let entries = fs.readdir_(fh, 0).unwrap();
+        fs.dir_handles.remove(&fh);
+        entries
+            .into_iter()
+            .filter(|(_, _, _, name)| name != "." && name != ".." && name != CONTROL_DIR_NAME)
+            .map(|(inode, _, _, name)| (name, inode))
+            .collect()
+    }
+
+    #[test]
+    fn readdir_reuses_inodes_across_listings() {
+        let entries = vec![file("/a.txt", "1"), file("/b.txt", "2")];
+        let (mut fs, _, listings) = filesystem(entries, Duration::ZERO);
+        let first = listed(&mut fs);
+        let second = listed(&mut fs);
+        assert_eq!(*listings.lock().unwrap(), 2);
+        assert_eq!(first.len(), 2);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn open_directory_keeps_its_snapshot() {
+        let (mut fs, entries, _) = filesystem(vec![file("/a.txt", "1")], Duration::ZERO);
+        let fh = fs.opendir_(root()).unwrap();
+        let first = fs.readdir_(fh, 0).unwrap();
+        entries.lock().unwrap().insert(0, file("/0.txt", "2"));
+        // Another process lists the directory meanwhile
+        listed(&mut fs);
+        assert_eq!(fs.readdir_(fh, 0).unwrap(), first);
+        assert_eq!(fs.readdir_(fh, 1).unwrap(), first[1..]);
+    }
+
+    #[test]
+    fn lookup_finds_listed_children() {
+        let (mut fs, _, listings) = filesystem(vec![file("/a.txt", "1")], DEFAULT_ATTR_TTL);
+        let listed = listed(&mut fs);
+        let attr = fs.lookup_(root(), OsStr::new("a.txt")).unwrap();
+        assert_eq!(InodeId(attr.ino), listed[0].1);
+        // The fresh listing answers the lookup without asking the server again
+        assert_eq!(*listings.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn new_entries_get_new_inodes_and_old_ones_keep_theirs() {
+        let (mut fs, entries, _) = filesystem(vec![file("/a.txt", "1")], Duration::ZERO);
+        let before = listed(&mut fs);
+        entries.lock().unwrap().push(file("/b.txt", "2"));
+        let after = listed(&mut fs);
+        assert_eq!(after[0], before[0]);
+        assert_ne!(after[1].1, before[0].1);
+    }
+
+    #[test]
+    fn vanished_inode_stays_until_forgotten() {
+        let (mut fs, entries, _) = filesystem(vec![file("/a.txt", "1")], Duration::ZERO);
+        let inode = InodeId(fs.lookup_(root(), OsStr::new("a.txt")).unwrap().ino);
+        entries.lock().unwrap().clear();
+        assert!(listed(&mut fs).is_empty());
+        // The kernel still holds a lookup
+        assert!(fs.getattributes(inode).is_ok());
+        fs.forget_(inode, 1);
+        assert!(fs.getattributes(inode).is_err());
+        assert!(fs.generation > 0);
+    }
+
+    #[test]
+    fn moved_file_keeps_inode_by_fileid() {
+        let (mut fs, entries, _) = filesystem(vec![file("/a.txt", "1")], Duration::ZERO);
+        let before = listed(&mut fs);
+        *entries.lock().unwrap() = vec![file("/renamed.txt", "1")];
+        let after = listed(&mut fs);
+        assert_eq!(after, vec![(OsString::from("renamed.txt"), before[0].1)]);
+    }
+
+    #[test]
+    fn permissions_become_modes() {
+        let with_permissions = |path: &str, permissions: &str| {
+            PropBuilder::new()
+                .path(path.into())
+                .resource_type(ResourceType::File)
+                .nextcloud_prop("permissions".into(), permissions.into())
+                .build()
+        };
+        let entries = vec![
+            with_permissions("/shared.txt", "SRG"),
+            with_permissions("/own.txt", "RGDNVW"),
+        ];
+        let (mut fs, _, _) = filesystem(entries, Duration::ZERO);
+        fs.config.read_only = false;
+        let shared = fs.lookup_(root(), OsStr::new("shared.txt")).unwrap();
+        let own = fs.lookup_(root(), OsStr::new("own.txt")).unwrap();
+        assert_eq!(shared.perm, 0o444);
+        assert_eq!(own.perm, 0o644);
+        fs.config.read_only = true;
+        assert_eq!(fs.getattributes(InodeId(own.ino)).unwrap().perm, 0o444);
+    }
+
+    #[test]
+    fn as_of_leaves_out_files_without_old_version() {
+        let changed = PropBuilder::new()
+            .path("/changed.txt".into())
+            .resource_type(ResourceType::File)
+            .last_modified(100)
+            .nextcloud_prop("fileid".into(), "2".into())
+            .build();
+        let entries = vec![changed, file("/unchanged.txt", "1")];
+        let (mut fs, _, _) = filesystem(entries, Duration::ZERO);
+        // The fake drive keeps no versions
+        fs.config.as_of = Some(50);
+        let names: Vec<OsString> = listed(&mut fs).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec![OsString::from("unchanged.txt")]);
+        fs.config.as_of = Some(100);
+        assert_eq!(listed(&mut fs).len(), 2);
+    }
+
+    #[test]
+    fn populating_applies_the_own_props_of_directories() {
+        let (mut fs, _, _) = filesystem(vec![], Duration::ZERO);
+        let dir = PropBuilder::new()
+            .path("/d".into())
+            .etag("etag-d".into())
+            .resource_type(ResourceType::Collection)
+            .quota_available(10)
+            .build();
+        let tree = crate::prop::group_by_parent(vec![dir, file("/d/x.txt", "2")]);
+        fs.populate(tree).unwrap();
+        let dir = fs.inodes[&root()].children[OsStr::new("d")];
+        assert_eq!(fs.files[&dir].quota_available, Some(10));
+        assert_eq!(fs.files[&dir].etag, "etag-d");
+        assert!(fs.inodes[&dir].complete);
+    }
+
+    #[test]
+    fn writes_beyond_the_buffer_limit_fail() {
+        let (mut fs, _, _) = filesystem(vec![file("/a.txt", "1")], Duration::ZERO);
+        fs.config.max_buffer_size = 16;
+        let attr = fs.lookup_(root(), OsStr::new("a.txt")).unwrap();
+        let fh = fs.open_(InodeId(attr.ino), libc::O_WRONLY).unwrap();
+        assert!(fs.write_(fh, 8, b"12345678").is_ok());
+        let result = fs.write_(fh, 9, b"12345678");
+        assert!(matches!(result, Err(Errors::FileTooLarge(_))));
+        let result = fs.write_(fh, u64::MAX, b"1");
+        assert!(matches!(result, Err(Errors::FileTooLarge(_))));
+    }
+
+    #[test]
+    fn blocked_uploads_back_off() {
+        assert_eq!(upload_retry_wait(1), UPLOAD_RETRY_INTERVAL);
+        assert_eq!(upload_retry_wait(2), UPLOAD_RETRY_INTERVAL * 2);
+        assert_eq!(upload_retry_wait(40), MAX_UPLOAD_RETRY_INTERVAL);
+        assert!(is_retryable(&Errors::InsufficientStorage("".into())));
+        assert!(!is_retryable(&Errors::HttpStatus(403)));
+        assert!(!is_retryable(&Errors::AuthenticationFailed));
+    }
+
+    #[test]
+    fn refused_uploads_hit_the_quota_only_where_one_is_known() {
+        let (mut fs, _, _) = filesystem(vec![file("/a.txt", "1")], Duration::ZERO);
+        fs.config.read_only = false;
+        let attr = fs.lookup_(root(), OsStr::new("a.txt")).unwrap();
+        let fh = fs.open_(InodeId(attr.ino), libc::O_WRONLY).unwrap();
+        fs.write_(fh, 0, b"data").unwrap();
+        let errno = |result: Result<(), Errors>| libc::c_int::from(result.unwrap_err());
+        assert_eq!(errno(fs.flush_(fh)), libc::ENOSPC);
+        fs.files.get_mut(&root()).unwrap().quota_available = Some(100);
+        assert_eq!(errno(fs.flush_(fh)), libc::EDQUOT);
+    }
+
+    #[test]
+    fn listings_skip_entries_without_resource_type() {
+        let untyped = PropBuilder::new().path("/untyped".into()).build();
+        let (mut fs, _, _) = filesystem(vec![untyped, file("/a.txt", "1")], Duration::ZERO);
+        let names: Vec<OsString> = listed(&mut fs).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec![OsString::from("a.txt")]);
+    }
+
+    #[test]
+    fn unsupported_collection_deletes_go_level_by_level() {
+        let entries = vec![
+            directory("/d", "1"),
+            directory("/d/e", "2"),
+            file("/d/e/forbidden.txt", "3"),
+            file("/d/x.txt", "4"),
+        ];
+        let (mut fs, entries, _) = filesystem(entries, DEFAULT_ATTR_TTL);
+        fs.config.read_only = false;
+        let dir = fs.lookup_(root(), OsStr::new("d")).unwrap();
+        let err = fs.apply_control_command(ControlCommand::Delete, InodeId(dir.ino));
+        assert!(matches!(err, Err(Errors::PartiallyDeleted(3))));
+        assert_eq!(entries.lock().unwrap().len(), 3);
+        let report = fs.delete_report.lock().unwrap();
+        assert_eq!(report["total"], 4);
+        assert_eq!(report["deleted"], 1);
+        assert_eq!(report["finished"], true);
+        let failed = report["failed"].as_array().unwrap();
+        assert_eq!(failed[0]["path"], "/d/e/forbidden.txt");
+        assert_eq!(failed[0]["error"], "forbidden");
+        assert_eq!(failed[0]["status"], 403);
+        assert_eq!(failed[1]["path"], "/d/e");
+        assert_eq!(failed[1]["error"], "not_empty");
+        assert_eq!(failed[2]["path"], "/d");
+    }
+
+    #[test]
+    fn forbidden_collection_deletes_are_not_retried_entry_by_entry() {
+        let entries = vec![directory("/forbidden", "1"), file("/forbidden/x.txt", "2")];
+        let (mut fs, entries, _) = filesystem(entries, DEFAULT_ATTR_TTL);
+        fs.config.read_only = false;
+        let dir = fs.lookup_(root(), OsStr::new("forbidden")).unwrap();
+        let err = fs.apply_control_command(ControlCommand::Delete, InodeId(dir.ino));
+        assert!(matches!(err, Err(Errors::HttpStatus(403))));
+        assert_eq!(entries.lock().unwrap().len(), 2);
+        assert!(fs.delete_report.lock().unwrap().is_null());
+    }
+
+    #[test]
+    fn missing_probe_names_are_answered_without_listing() {
+        let (mut fs, _, listings) = filesystem(vec![file("/a.txt", "1")], Duration::ZERO);
+        assert!(fs.lookup_(root(), OsStr::new("desktop.ini")).is_err());
+        let before = *listings.lock().unwrap();
+        assert!(fs.lookup_(root(), OsStr::new("desktop.ini")).is_err());
+        assert_eq!(*listings.lock().unwrap(), before);
+        // Other names are looked for on the server every time
+        assert!(fs.lookup_(root(), OsStr::new("b.txt")).is_err());
+        assert_eq!(*listings.lock().unwrap(), before + 1);
+    }
+
+    #[test]
+    fn normalizing_lookups_match_other_unicode_forms() {
+        // "Café" decomposed, the way macOS clients upload it
+        let (mut fs, _, _) = filesystem(vec![file("/Cafe\u{301}.txt", "1")], DEFAULT_ATTR_TTL);
+        let composed = OsStr::new("Caf\u{e9}.txt");
+        assert!(fs.lookup_(root(), composed).is_err());
+        fs.config.normalization = Some(Normalization::Nfc);
+        assert!(fs.lookup_(root(), composed).is_ok());
     }
 }
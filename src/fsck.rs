@@ -0,0 +1,80 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use crate::{errors::Errors, prop::PropTree, state::StateStore};
+
+/// An inconsistency between the state database and the remote
+enum Problem {
+    /// The path has an inode assigned but no longer exists on the remote
+    Vanished { path: String, inode: u64 },
+    /// The inode is assigned to another path as well, only one of them can get it back
+    DuplicateInode { path: String, inode: u64, kept: String },
+}
+
+impl Problem {
+    fn path(&self) -> &str {
+        match self {
+            Problem::Vanished { path, .. } | Problem::DuplicateInode { path, .. } => path,
+        }
+    }
+}
+
+/// Checks the state database at `file` against `tree`, a recursive listing of the remote, and
+/// prints every problem found. With `repair` the affected entries are dropped and the database
+/// is compacted. Returns the number of problems that are left
+pub fn run(file: &Path, tree: &PropTree, repair: bool) -> Result<usize, Errors> {
+    if !file.exists() {
+        println!("{}: no state database, nothing to check", file.display());
+        return Ok(0);
+    }
+    let mut state = StateStore::open(file.to_path_buf())?;
+    let mut existing: BTreeSet<String> = tree
+        .values()
+        .flatten()
+        .map(|prop| prop.path().to_string_lossy().into_owned())
+        .collect();
+    existing.insert("/".into());
+
+    let mut problems = vec![];
+    let mut owners: BTreeMap<u64, String> = BTreeMap::new();
+    for (path, inode) in state.entries() {
+        if !existing.contains(path) {
+            problems.push(Problem::Vanished {
+                path: path.to_string(),
+                inode,
+            });
+        } else if let Some(kept) = owners.get(&inode) {
+            problems.push(Problem::DuplicateInode {
+                path: path.to_string(),
+                inode,
+                kept: kept.clone(),
+            });
+        } else {
+            owners.insert(inode, path.to_string());
+        }
+    }
+
+    for problem in &problems {
+        match problem {
+            Problem::Vanished { path, inode } => {
+                println!("{path}: inode {inode} assigned, but the path is gone on the remote")
+            }
+            Problem::DuplicateInode { path, inode, kept } => {
+                println!("{path}: inode {inode} is assigned to {kept} as well")
+            }
+        }
+    }
+    let count = problems.len();
+    if !repair || count == 0 {
+        println!("{}: {count} problems", file.display());
+        return Ok(count);
+    }
+    for problem in &problems {
+        state.forget(problem.path())?;
+    }
+    state.compact()?;
+    println!("{}: {count} problems repaired", file.display());
+    Ok(0)
+}
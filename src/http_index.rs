@@ -0,0 +1,167 @@
+use crate::backend::Backend;
+use crate::errors::Errors;
+use crate::metrics::{self, Counter};
+use crate::path;
+use crate::prop::*;
+use crate::webdav::{PropfindDepth, DEFAULT_USER_AGENT};
+use chrono::prelude::*;
+use reqwest::{
+    blocking::{Client, RequestBuilder, Response},
+    header::{ACCEPT, RANGE, USER_AGENT},
+};
+use serde_json::Value;
+
+/// A plain HTTP(S) server that publishes its directories as JSON indexes, like nginx with
+/// `autoindex_format json`. Only reads are possible, there is no way to upload
+pub struct HttpIndexDrive {
+    /// URL of the directory shown at the root of the drive, without trailing slash
+    prefix: String,
+    client: Client,
+    user_agent: String,
+    username: String,
+    password: String,
+}
+
+impl HttpIndexDrive {
+    /// A drive serving the directory `remote_root` of the server at `prefix`. Credentials are
+    /// sent as basic auth unless `username` is empty
+    pub fn new(prefix: &str, remote_root: &str, username: &str, password: &str) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_string() + &path::encode(remote_root),
+            client: Client::new(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    /// Sends `user_agent` instead of the default User-Agent
+    pub fn user_agent(mut self, user_agent: String) -> Self {
+        self.user_agent = user_agent;
+        self
+    }
+
+    /// Sends a GET request for `url`, `build` adds the headers of the request
+    fn send(
+        &self,
+        url: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<Response, Errors> {
+        let request = self.client.get(url).header(USER_AGENT, &self.user_agent);
+        let request = match self.username.is_empty() {
+            true => request,
+            false => request.basic_auth(&self.username, Some(&self.password)),
+        };
+        metrics::add(Counter::Requests, 1);
+        let response = build(request).send().map_err(|err| {
+            metrics::add(Counter::RequestErrors, 1);
+            Errors::ServerUnreachable(format!("{:?}", err))
+        })?;
+        match response.status().as_u16() {
+            200..=299 => Ok(response),
+            401 | 403 => Err(Errors::AuthenticationFailed),
+            status => {
+                metrics::add(Counter::RequestErrors, 1);
+                Err(Errors::HttpStatus(status))
+            }
+        }
+    }
+
+    /// Fetches the index of the directory `dir` and turns its entries into props
+    fn list_directory(&self, dir: &str) -> Result<Vec<Prop>, Errors> {
+        let url = self.prefix.clone() + &path::encode(dir.trim_end_matches('/')) + "/";
+        let response = self.send(&url, |request| request.header(ACCEPT, "application/json"))?;
+        let body = response.bytes().map_err(|_| Errors::WebDavReqeustFailed)?;
+        metrics::add(Counter::BytesReceived, body.len() as u64);
+        let entries: Vec<Value> =
+            serde_json::from_slice(&body).map_err(|err| Errors::IndexParseError(err.to_string()))?;
+
+        let mut props = vec![];
+        for entry in entries {
+            let name = entry["name"]
+                .as_str()
+                .ok_or_else(|| Errors::IndexParseError(format!("entry without name in {dir}")))?;
+            let resource_type = match entry["type"].as_str() {
+                Some("directory") => ResourceType::Collection,
+                Some("file") => ResourceType::File,
+                // Symlinks and other special files are not served by the index
+                _ => continue,
+            };
+            let mut path = path::child(dir, name);
+            if resource_type == ResourceType::Collection {
+                path.push('/');
+            }
+            let last_modified = match entry["mtime"].as_str() {
+                Some(mtime) => DateTime::parse_from_rfc2822(mtime)
+                    .map_err(Errors::DateTimeConversionError)?
+                    .timestamp()
+                    .try_into()
+                    .unwrap_or_default(),
+                None => 0,
+            };
+            let size = entry["size"].as_u64().unwrap_or_default();
+            // The index has no ETag, size and time have to tell changes apart
+            let prop = PropBuilder::new()
+                .path(path.into())
+                .etag(format!("{size}-{last_modified}"))
+                .size(size)
+                .last_modified(last_modified)
+                .resource_type(resource_type)
+                .build();
+            props.push(prop);
+        }
+        Ok(props)
+    }
+}
+
+impl Backend for HttpIndexDrive {
+    /// Lists `path` from the directory indexes. The index only describes the entries of a
+    /// directory, so the directory itself gets no size and time
+    fn list(&self, path: &str, depth: PropfindDepth) -> Result<Vec<Prop>, Errors> {
+        let dir = match path.ends_with('/') {
+            true => path.to_string(),
+            false => format!("{path}/"),
+        };
+        let mut props = vec![PropBuilder::new()
+            .path(dir.clone().into())
+            .resource_type(ResourceType::Collection)
+            .build()];
+        if let PropfindDepth::ElementOnly = depth {
+            return Ok(props);
+        }
+        let mut pending = vec![dir];
+        while let Some(dir) = pending.pop() {
+            let children = self.list_directory(&dir)?;
+            if let PropfindDepth::Recursive = depth {
+                let dirs = children
+                    .iter()
+                    .filter(|p| p.resource_type() == ResourceType::Collection);
+                pending.extend(dirs.map(|p| p.path().to_string_lossy().into_owned()));
+            }
+            props.extend(children);
+        }
+        Ok(props)
+    }
+
+    fn href(&self, path: &str) -> String {
+        self.prefix.clone() + &path::encode(path)
+    }
+
+    fn get_range(&self, path: &str, offset: u64, size: u64) -> Result<Vec<u8>, Errors> {
+        if size == 0 {
+            return Ok(vec![]);
+        }
+        let range = format!("bytes={}-{}", offset, offset + size - 1);
+        let url = self.href(path);
+        let response = self.send(&url, |request| request.header(RANGE, &range))?;
+        let partial = response.status().as_u16() == 206;
+        let body = response.bytes().map_err(|_| Errors::WebDavReqeustFailed)?;
+        metrics::add(Counter::BytesReceived, body.len() as u64);
+        // Servers without Range support send the whole file
+        let body = match partial {
+            true => &body[..],
+            false => &body[(offset as usize).min(body.len())..],
+        };
+        Ok(body[..body.len().min(size as usize)].to_vec())
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
+};
+
+use crate::{metrics, platform};
+
+/// FUSE operations currently being handled, at most one per dispatcher thread
+static OPERATIONS: Mutex<Vec<Operation>> = Mutex::new(Vec::new());
+/// Operations running longer than this many milliseconds are reported, 0 disables the check
+static THRESHOLD_MS: AtomicU64 = AtomicU64::new(0);
+/// Whether hung operations stop retrying their requests and fail
+static CANCEL: AtomicBool = AtomicBool::new(false);
+
+/// A FUSE operation in flight
+struct Operation {
+    thread: ThreadId,
+    name: &'static str,
+    inode: u64,
+    /// Process that issued the operation, 0 if the kernel did not tell
+    pid: u32,
+    /// HTTP request the operation is waiting for, if any
+    request: Option<String>,
+    started: Instant,
+    /// Whether the operation was reported as hung already
+    hung: bool,
+}
+
+/// Marks the operation of the current thread as finished when dropped
+pub struct OperationGuard {
+    thread: ThreadId,
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        let mut operations = OPERATIONS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(idx) = operations.iter().position(|op| op.thread == self.thread) {
+            let op = operations.swap_remove(idx);
+            if op.hung {
+                info!(
+                    "{} on inode {} finished after {:.1?}",
+                    op.name,
+                    op.inode,
+                    op.started.elapsed()
+                );
+            }
+        }
+    }
+}
+
+/// Records the start of the FUSE operation `name` on `inode`, issued by the process `pid`, for
+/// the current thread
+pub fn start(name: &'static str, inode: u64, pid: u32) -> OperationGuard {
+    metrics::count_operation(name);
+    track(name, inode, pid)
+}
+
+/// Records an operation that `start` counted already, which the current thread continues
+pub fn track(name: &'static str, inode: u64, pid: u32) -> OperationGuard {
+    let thread = thread::current().id();
+    let mut operations = OPERATIONS.lock().unwrap_or_else(|e| e.into_inner());
+    operations.retain(|op| op.thread != thread);
+    operations.push(Operation {
+        thread,
+        name,
+        inode,
+        pid,
+        request: None,
+        started: Instant::now(),
+        hung: false,
+    });
+    OperationGuard { thread }
+}
+
+/// Records the HTTP request the operation of the current thread is waiting for
+pub fn set_request(method: &str, url: &str) {
+    let thread = thread::current().id();
+    let mut operations = OPERATIONS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(op) = operations.iter_mut().find(|op| op.thread == thread) {
+        op.request = Some(format!("{method} {url}"));
+    }
+}
+
+/// Whether the operation of the current thread hangs or was interrupted, and should give up
+/// instead of retrying
+pub fn cancelled() -> bool {
+    if interrupted() {
+        return true;
+    }
+    if !CANCEL.load(Ordering::Relaxed) {
+        return false;
+    }
+    let thread = thread::current().id();
+    let operations = OPERATIONS.lock().unwrap_or_else(|e| e.into_inner());
+    operations.iter().any(|op| op.thread == thread && op.hung)
+}
+
+/// Whether the current thread handles an operation for a process that can be told apart
+pub fn interruptible() -> bool {
+    caller().is_some()
+}
+
+/// Whether the process that issued the operation of the current thread is being killed, by
+/// Ctrl-C for example. Nobody waits for the answer anymore. fuser does not pass on the
+/// interrupts of the kernel, so the process is asked directly
+pub fn interrupted() -> bool {
+    caller().map_or(false, platform::fatal_signal_pending)
+}
+
+/// Process that issued the operation of the current thread
+fn caller() -> Option<u32> {
+    let thread = thread::current().id();
+    let operations = OPERATIONS.lock().unwrap_or_else(|e| e.into_inner());
+    let op = operations.iter().find(|op| op.thread == thread)?;
+    Some(op.pid).filter(|&pid| pid != 0)
+}
+
+/// Sets the threshold for hung operations, `None` disables the check
+pub fn configure(threshold: Option<Duration>, cancel: bool) {
+    let millis = threshold.map_or(0, |t| t.as_millis() as u64);
+    THRESHOLD_MS.store(millis, Ordering::Relaxed);
+    CANCEL.store(cancel, Ordering::Relaxed);
+}
+
+/// Starts a thread that logs every operation running longer than the configured threshold,
+/// together with the request it is blocked on
+pub fn spawn_monitor() {
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_secs(1));
+        let threshold = match THRESHOLD_MS.load(Ordering::Relaxed) {
+            0 => continue,
+            millis => Duration::from_millis(millis),
+        };
+        let mut operations = OPERATIONS.lock().unwrap_or_else(|e| e.into_inner());
+        for op in operations.iter_mut() {
+            if op.hung || op.started.elapsed() < threshold {
+                continue;
+            }
+            op.hung = true;
+            warn!(
+                "{} on inode {} is hanging for {:.1?}, waiting for {}",
+                op.name,
+                op.inode,
+                op.started.elapsed(),
+                op.request.as_deref().unwrap_or("no request")
+            );
+        }
+    });
+}
@@ -1,34 +1,510 @@
-// A nextcloud server path to test against
-const SERVER_URL: &str = "https://testcloud.chaos/remote.php/dav/files/test";
-
 use fuser::{self, MountOption};
 use rustydav::client::Client;
+use std::{
+    path::Path,
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+use backend::Backend;
+use errors::Errors;
+use http_index::HttpIndexDrive;
 
 #[macro_use]
 extern crate log;
 
+mod backend;
+mod cli;
+mod config;
+mod conflicts;
+mod control;
+mod daemon;
+mod delete;
+mod dirs;
+mod download;
 mod errors;
 mod filesystem;
+mod fsck;
+mod http_index;
+mod inflight;
+mod metrics;
+mod middleware;
+mod notify;
+mod path;
+mod platform;
+mod profile;
 mod prop;
+mod put;
+mod reload;
+mod remotes;
+mod scratch;
+mod state;
+mod systemd;
+mod watch;
 mod webdav;
+mod xattr;
+
+/// How often the filesystem is mounted again after its FUSE session died
+const MAX_REMOUNTS: u32 = 5;
+/// Pause before the first remount, grows linearly with every further attempt
+const REMOUNT_BACKOFF: Duration = Duration::from_secs(2);
 
 fn main() {
-    env_logger::init();
-    // Webdav client setup
-    let webdav_client = Client::init("test", "test");
-    let webdav_drive = webdav::WebdavDrive::new(SERVER_URL.to_string(), webdav_client);
+    let mut args: Vec<String> = std::env::args().collect();
+    let error_format = match cli::take_error_format(&mut args) {
+        Ok(format) => format,
+        Err(err) => fail(err, cli::ErrorFormat::Text, None),
+    };
+    let command = match cli::parse(&args) {
+        Ok(command) => command,
+        Err(err) => {
+            if error_format == cli::ErrorFormat::Text {
+                eprintln!("{}\n", cli::USAGE);
+            }
+            fail(err, error_format, None)
+        }
+    };
+    let mut mount = match command {
+        cli::Command::Mount(mount) => mount,
+        cli::Command::Systemd(systemd_args) => {
+            if let Err(err) = systemd::run(&systemd_args) {
+                fail(err, error_format, None);
+            }
+            return;
+        }
+        cli::Command::Umount {
+            mountpoint,
+            pidfile,
+        } => {
+            if let Err(err) = daemon::umount(&mountpoint, &pidfile) {
+                fail(err, error_format, None);
+            }
+            return;
+        }
+        cli::Command::Paths { url } => {
+            println!("config file: {}", dirs::config_file().display());
+            match url {
+                Some(url) => {
+                    println!("cache:       {}", dirs::remote_cache_dir(&url).display());
+                    println!("state:       {}", dirs::remote_state_dir(&url).display());
+                }
+                None => {
+                    println!("cache:       {}", dirs::cache_dir().display());
+                    println!("state:       {}", dirs::state_dir().display());
+                }
+            }
+            return;
+        }
+        cli::Command::Set {
+            mountpoint,
+            read_only,
+        } => {
+            let cmd = match read_only {
+                true => control::IOCTL_SET_READ_ONLY,
+                false => control::IOCTL_SET_READ_WRITE,
+            };
+            if let Err(err) = control::issue_ioctl(&mountpoint, cmd) {
+                fail(err, error_format, None);
+            }
+            return;
+        }
+        cli::Command::Delete { path } => {
+            let progress = |report: &serde_json::Value| {
+                if error_format != cli::ErrorFormat::Json {
+                    eprintln!("deleted {} of {} entries", report["done"], report["total"]);
+                }
+            };
+            match control::delete_tree(&path, progress) {
+                Ok(report) => {
+                    let failed = print_report(&report, error_format);
+                    if error_format == cli::ErrorFormat::Text {
+                        // A collection deleted at once does not tell how many entries it held
+                        match (report["deleted"].as_u64(), failed) {
+                            (None, _) => println!("deleted {}", path.display()),
+                            (Some(deleted), 0) => println!("deleted {} entries", deleted),
+                            (Some(deleted), n) => {
+                                println!("deleted {} entries, {} failed", deleted, n)
+                            }
+                        }
+                    }
+                    if failed > 0 {
+                        std::process::exit(1);
+                    }
+                    return;
+                }
+                Err(err) => fail(err, error_format, None),
+            }
+        }
+        cli::Command::Put {
+            mut mount,
+            source,
+            target,
+            recursive,
+        } => {
+            discover_roots(&mut mount);
+            match put::upload(&connect_remotes(&mount), &source, &target, recursive) {
+                Ok(report) => {
+                    let failed = print_report(&report, error_format);
+                    if error_format == cli::ErrorFormat::Text {
+                        match failed {
+                            0 => println!("uploaded {} entries", report["uploaded"]),
+                            n => println!("uploaded {} entries, {} failed", report["uploaded"], n),
+                        }
+                    }
+                    if failed > 0 {
+                        std::process::exit(1);
+                    }
+                    return;
+                }
+                Err(err) => fail(err, error_format, None),
+            }
+        }
+        cli::Command::History { path } => match control::read_history(&path) {
+            Ok(history) => {
+                for record in history {
+                    println!(
+                        "{}  {}  modified {}",
+                        format_time(record.seen_at),
+                        record.etag,
+                        format_time(record.mtime)
+                    );
+                }
+                return;
+            }
+            Err(err) => fail(err, error_format, None),
+        },
+        cli::Command::Watch {
+            mut mount,
+            path,
+            interval,
+        } => {
+            discover_roots(&mut mount);
+            let events = watch::spawn_watcher(connect_remotes(&mount), path, interval);
+            for event in events {
+                let kind = match event {
+                    watch::ChangeEvent::Created(_) => "created",
+                    watch::ChangeEvent::Modified(_) => "modified",
+                    watch::ChangeEvent::Removed(_) => "removed",
+                };
+                println!("{} {}", kind, event.path());
+            }
+            return;
+        }
+        cli::Command::Fsck { mut mount, repair } => {
+            discover_roots(&mut mount);
+            let tree = connect_remotes(&mount).list_tree("/", webdav::PropfindDepth::Recursive);
+            let file = mount.config.state_file.expect("fsck always has a state file");
+            match tree.and_then(|tree| fsck::run(&file, &tree, repair)) {
+                Ok(0) => return,
+                Ok(_) => std::process::exit(1),
+                Err(err) => fail(err, error_format, None),
+            }
+        }
+    };
+    if mount.fake {
+        return;
+    }
+
+    // Without RUST_LOG the level is taken from the configuration and may change on reload
+    if std::env::var_os("RUST_LOG").is_some() {
+        env_logger::init();
+    } else {
+        env_logger::Builder::new()
+            .filter_level(log::LevelFilter::Trace)
+            .init();
+        log::set_max_level(mount.config.log_level);
+    }
+
+    if let Err(err) = platform::check_fuse_available() {
+        fail(err, error_format, None);
+    }
+
+    // Fork before the HTTP client and the session start their threads. The parent exits once
+    // the child reports that the mount succeeded
+    let mut detached = (!mount.foreground).then(daemon::daemonize);
+
+    discover_roots(&mut mount);
+    // One recursive listing fills the whole tree, instead of a request per directory later
+    let remotes = connect_remotes(&mount);
+    let tree = match mount.config.snapshot {
+        true => remotes.list_snapshot(),
+        false => remotes.list_tree("/", webdav::PropfindDepth::Recursive),
+    };
+    let mut tree = match tree {
+        Ok(tree) => Some(tree),
+        Err(err) => fail(err, error_format, detached.take()),
+    };
+    let atomic_uploads = mount.atomic_uploads || mount.remotes.iter().any(|r| r.atomic_uploads);
+    if atomic_uploads && !mount.config.read_only {
+        if let Some(tree) = tree.as_mut() {
+            remotes.remove_stale_uploads(tree);
+        }
+    }
 
-    let props = webdav_drive
-        .list("/", webdav::PropfindDepth::Recursive)
-        .unwrap();
+    let mut mount_options = vec![
+        MountOption::NoAtime,
+        MountOption::FSName(match mount.url.as_str() {
+            "" => "webdavfs".to_string(),
+            url => url.to_string(),
+        }),
+        MountOption::Subtype("webdavfs".to_string()),
+    ];
+    // Other mounts enforce read_only themselves, so it can be switched while mounted and the
+    // control directory stays writable
+    if mount.config.snapshot || mount.config.as_of.is_some() {
+        mount_options.push(MountOption::RO);
+    }
+    mount_options.extend(mount.fuse_options.clone());
+    let mount_options = platform::supported_mount_options(mount_options);
 
-    let fs = filesystem::FuseFilesystem::init(webdav_drive);
+    inflight::configure(mount.config.hung_threshold, mount.config.cancel_hung);
+    inflight::spawn_monitor();
 
-    let mut mount_options = vec![MountOption::NoAtime];
-    // read only for now
-    mount_options.push(MountOption::RO);
+    let config_updates = Arc::new(Mutex::new(reload::spawn_reloader(args.clone())));
+    let pidfile = mount
+        .pidfile
+        .clone()
+        .unwrap_or_else(|| daemon::default_pidfile(&mount.mountpoint));
+    let mut remounts = 0;
+    loop {
+        // Settings reloaded while the previous session ran are picked up by parsing again
+        let config = match cli::parse(&args) {
+            Ok(cli::Command::Mount(reparsed)) => reparsed.config,
+            _ => mount.config.clone(),
+        };
+        let mut fs = match filesystem::FuseFilesystem::init(connect_remotes(&mount), config) {
+            Ok(fs) => fs,
+            Err(err) => fail(err, error_format, detached.take()),
+        };
+        fs.reload_config_from(config_updates.clone());
+        if mount.download_threads > 0 {
+            let connect = || connect_remotes(&mount);
+            let downloads = download::Downloads::spawn(mount.download_threads, connect);
+            fs.download_in_background(downloads);
+        }
+        if mount.delete_threads > 0 {
+            let connect = || connect_remotes(&mount);
+            fs.delete_in_background(delete::Deletes::spawn(mount.delete_threads, connect));
+        }
+        let changes = mount.watch.map(|interval| {
+            let (sender, receiver) = mpsc::channel();
+            fs.follow_changes(receiver);
+            (interval, sender)
+        });
+        // A remounted snapshot has to serve the very same tree again
+        let initial_tree = match mount.config.snapshot {
+            true => tree.clone(),
+            false => tree.take(),
+        };
+        if let Some(tree) = initial_tree {
+            if let Err(err) = fs.populate(tree) {
+                warn!("Could not fill the tree from the initial listing: {:?}", err);
+            }
+        }
 
-    println!("{:#?}", props);
+        let mut session = match fuser::Session::new(fs, &mount.mountpoint, &mount_options) {
+            Ok(session) => session,
+            Err(err) if remounts == 0 => {
+                let err = mount_error(&mount.mountpoint, err);
+                fail(err, error_format, detached.take())
+            }
+            Err(err) if remounts < MAX_REMOUNTS => {
+                remounts += 1;
+                warn!("Remounting failed with {}, retrying ({}/{})", err, remounts, MAX_REMOUNTS);
+                std::thread::sleep(REMOUNT_BACKOFF * remounts);
+                continue;
+            }
+            Err(err) => {
+                error!("Remounting failed with {}, giving up", err);
+                break;
+            }
+        };
+
+        // Every session gets its own watcher. It ends with the first change after the
+        // filesystem it feeds is gone
+        if let Some((interval, sender)) = changes {
+            let events = watch::spawn_watcher(connect_remotes(&mount), "/".into(), interval);
+            let notifier = session.notifier();
+            watch::spawn_invalidator(events, sender, notifier, mount.mountpoint.clone());
+        }
+
+        // The initial listing and the mount succeeded, so the filesystem is usable now
+        if let Some(detached) = detached.take() {
+            if let Err(err) = detached.ready(&pidfile, mount.log_file.as_deref()) {
+                error!("Could not finish detaching: {:?}", err);
+            }
+        }
+        if remounts == 0 {
+            notify::notify("READY=1");
+            if let Some(interval) = notify::watchdog_interval() {
+                notify::spawn_watchdog(mount.mountpoint.clone(), interval);
+            }
+        }
+
+        // Returns Ok once the filesystem got unmounted
+        let Err(err) = session.run() else {
+            break;
+        };
+        drop(session);
+        if remounts == MAX_REMOUNTS {
+            error!("FUSE session failed with {}, giving up", err);
+            break;
+        }
+        remounts += 1;
+        warn!(
+            "FUSE session failed with {}, remounting ({}/{})",
+            err, remounts, MAX_REMOUNTS
+        );
+        notify::notify("RELOADING=1");
+        platform::lazy_unmount(&mount.mountpoint);
+        std::thread::sleep(REMOUNT_BACKOFF * remounts);
+        notify::notify("READY=1");
+    }
+    if !mount.foreground {
+        let _ = std::fs::remove_file(&pidfile);
+    }
+}
+
+/// Prints the entries of a batch `report` that failed, with `--error-format json` the whole
+/// report instead. Returns how many failed
+fn print_report(report: &serde_json::Value, error_format: cli::ErrorFormat) -> usize {
+    let failed = report["failed"].as_array().cloned().unwrap_or_default();
+    if error_format == cli::ErrorFormat::Json {
+        println!("{}", report);
+        return failed.len();
+    }
+    for entry in &failed {
+        let path = entry["path"].as_str().unwrap_or_default();
+        let error = entry["error"].as_str().unwrap_or_default();
+        match entry["status"].as_u64() {
+            Some(status) => eprintln!("{}: {} (HTTP {})", path, error, status),
+            None => eprintln!("{}: {}", path, error),
+        }
+    }
+    failed.len()
+}
+
+/// Replaces URLs that only name a host by the DAV root the server advertises
+fn discover_roots(mount: &mut cli::MountArgs) {
+    let discover = |remote: cli::Remote| {
+        // Plain HTTP servers have no DAV root to discover
+        if remote.http_index || !matches!(path::url_path(&remote.url), "" | "/") {
+            return remote.url;
+        }
+        match connect_webdav(&remote).discover_root(&remote.username) {
+            Ok(root) => {
+                info!("Discovered DAV root {} for {}", root, remote.url);
+                root
+            }
+            Err(err) => {
+                warn!("Discovering the DAV root of {} failed: {:?}", remote.url, err);
+                remote.url
+            }
+        }
+    };
+    if mount.remotes.is_empty() {
+        mount.url = discover(mount.remote());
+    }
+    for remote in &mut mount.remotes {
+        remote.url = discover(remote.clone());
+    }
+}
+
+/// Connects to the remote of `mount`, or to each of its remotes
+fn connect_remotes(mount: &cli::MountArgs) -> remotes::Remotes {
+    if mount.remotes.is_empty() {
+        return remotes::Remotes::Single(connect_drive(&mount.remote()));
+    }
+    if mount.union {
+        let write_target = mount
+            .remotes
+            .iter()
+            .position(|r| Some(&r.name) == mount.write_target.as_ref())
+            .unwrap_or_default();
+        return remotes::Remotes::Union {
+            drives: mount.remotes.iter().map(connect_drive).collect(),
+            write_target,
+        };
+    }
+    let drives = mount
+        .remotes
+        .iter()
+        .map(|remote| (remote.name.clone(), connect_drive(remote)));
+    remotes::Remotes::Multiple(drives.collect())
+}
+
+/// Creates the drive for `remote` with the backend it is configured for
+fn connect_drive(remote: &cli::Remote) -> Box<dyn Backend> {
+    if !remote.http_index {
+        return Box::new(connect_webdav(remote));
+    }
+    let drive = HttpIndexDrive::new(
+        &remote.url,
+        &remote.remote_root,
+        &remote.username,
+        &remote.password,
+    );
+    match &remote.user_agent {
+        Some(user_agent) => Box::new(drive.user_agent(user_agent.clone())),
+        None => Box::new(drive),
+    }
+}
+
+/// Creates a drive that logs in again with the same credentials whenever its connections die
+fn connect_webdav(remote: &cli::Remote) -> webdav::WebdavDrive {
+    let username = remote.username.clone();
+    let password = remote.password.clone();
+    let drive = webdav::WebdavDrive::new(&remote.url, &remote.remote_root, move || {
+        Client::init(&username, &password)
+    });
+    let drive = match &remote.mirror {
+        Some(mirror) => drive.mirror(mirror, &remote.remote_root),
+        None => drive,
+    };
+    let drive = match remote.atomic_uploads {
+        true => drive.atomic_uploads(),
+        false => drive,
+    };
+    let drive = match remote.rate_limit {
+        Some(limit) => drive.middleware(middleware::RateLimit::new(limit)),
+        None => drive,
+    };
+    match &remote.user_agent {
+        Some(user_agent) => drive.user_agent(user_agent.clone()),
+        None => drive,
+    }
+}
+
+/// Seconds since the epoch as local time, for humans
+fn format_time(secs: u64) -> String {
+    use chrono::TimeZone;
+    match chrono::NaiveDateTime::from_timestamp_opt(secs as i64, 0) {
+        Some(utc) => chrono::Local
+            .from_utc_datetime(&utc)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+        None => secs.to_string(),
+    }
+}
+
+/// Classifies an error of mounting, so a busy mountpoint gets its own exit code
+fn mount_error(mountpoint: &Path, err: std::io::Error) -> Errors {
+    let message = format!("mounting {} failed: {}", mountpoint.display(), err);
+    match err.raw_os_error() {
+        Some(libc::EBUSY) | Some(libc::ENOTEMPTY) => Errors::MountpointBusy(message),
+        _ => Errors::MountFailed(message),
+    }
+}
 
-    let _mount = fuser::mount2(fs, "/home/nick/repo/fuse/webdav_fuse/mnt", &mount_options);
+/// Reports a fatal error in the requested format and exits with its exit code. A detached
+/// child passes the code on to the parent waiting for it
+fn fail(err: Errors, format: cli::ErrorFormat, detached: Option<daemon::Daemon>) -> ! {
+    match format {
+        cli::ErrorFormat::Text => eprintln!("{:?}", err),
+        cli::ErrorFormat::Json => eprintln!("{}", err.to_json()),
+    }
+    if let Some(detached) = detached {
+        detached.exit(err.exit_code());
+    }
+    std::process::exit(err.exit_code())
 }
@@ -0,0 +1,94 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// FUSE operations handled since mounting, by name
+static OPERATIONS: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+/// Values of the counters, in the order of `Counter::ALL`
+static COUNTERS: [AtomicU64; Counter::ALL.len()] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Everything counted besides the operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    /// HTTP requests sent, including retries
+    Requests,
+    /// Requests that failed with an error status or could not be sent at all
+    RequestErrors,
+    /// Requests sent again after reconnecting
+    Retries,
+    /// Bytes of file content uploaded
+    BytesSent,
+    /// Bytes of responses read
+    BytesReceived,
+    /// Directory listings served from memory
+    CacheHits,
+    /// Directory listings fetched from the server
+    CacheMisses,
+}
+
+impl Counter {
+    pub const ALL: [Counter; 7] = [
+        Counter::Requests,
+        Counter::RequestErrors,
+        Counter::Retries,
+        Counter::BytesSent,
+        Counter::BytesReceived,
+        Counter::CacheHits,
+        Counter::CacheMisses,
+    ];
+
+    fn key(&self) -> &'static str {
+        match self {
+            Counter::Requests => "requests",
+            Counter::RequestErrors => "request_errors",
+            Counter::Retries => "retries",
+            Counter::BytesSent => "bytes_sent",
+            Counter::BytesReceived => "bytes_received",
+            Counter::CacheHits => "cache_hits",
+            Counter::CacheMisses => "cache_misses",
+        }
+    }
+
+    fn value(&self) -> &'static AtomicU64 {
+        let idx = Self::ALL.iter().position(|c| c == self).unwrap_or_default();
+        &COUNTERS[idx]
+    }
+}
+
+/// Adds `amount` to `counter`
+pub fn add(counter: Counter, amount: u64) {
+    counter.value().fetch_add(amount, Ordering::Relaxed);
+}
+
+/// Counts one handled FUSE operation `name`
+pub fn count_operation(name: &'static str) {
+    let mut operations = OPERATIONS.lock().unwrap_or_else(|e| e.into_inner());
+    *operations.entry(name).or_default() += 1;
+}
+
+/// All counters as `key=value` lines, operations are prefixed with `ops.`
+pub fn render() -> String {
+    let mut text = String::new();
+    for counter in Counter::ALL {
+        let value = counter.value().load(Ordering::Relaxed);
+        let _ = writeln!(text, "{}={}", counter.key(), value);
+    }
+    let operations = OPERATIONS.lock().unwrap_or_else(|e| e.into_inner());
+    for (name, count) in operations.iter() {
+        let _ = writeln!(text, "ops.{name}={count}");
+    }
+    text
+}
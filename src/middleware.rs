@@ -0,0 +1,136 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use reqwest::{
+    blocking::{RequestBuilder, Response},
+    Method,
+};
+
+use crate::{inflight, webdav::webdav_move};
+
+/// How often a failed request is retried with a fresh connection before giving up
+pub const MAX_RECONNECTS: u32 = 3;
+/// Pause before the first reconnect, grows linearly with every further attempt
+pub const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A request as the middlewares see it
+pub struct RequestInfo<'a> {
+    pub method: &'a Method,
+    pub url: &'a str,
+    /// 0 for the first try, counts up with every retry
+    pub attempt: u32,
+}
+
+/// What a request resulted in. Transport errors mean no response arrived at all
+pub enum Outcome<'a> {
+    Response(&'a Response),
+    TransportError(&'a reqwest::Error),
+}
+
+/// What to do after a response, decided by the middlewares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Next {
+    Continue,
+    /// Send the request again after a pause. Transport errors reconnect before
+    Retry { after: Duration },
+}
+
+/// Hooks into every request the WebDAV drive sends, for signing, extra headers, metrics and
+/// the like. Middlewares run in the order they were added for requests and in reverse order
+/// for responses. All of them see every response, the first one asking for a retry decides
+pub trait Middleware: Send + Sync {
+    fn on_request(&self, _request: &RequestInfo, builder: RequestBuilder) -> RequestBuilder {
+        builder
+    }
+
+    fn on_response(&self, _request: &RequestInfo, _outcome: &Outcome) -> Next {
+        Next::Continue
+    }
+}
+
+/// Retries requests that got no response, up to `max_attempts` times. rustydav only fails on
+/// transport errors, e.g. when pooled connections died after a suspend or a VPN change. MOVE
+/// and DELETE are never retried here, the drive probes whether they were applied instead
+pub struct Retry {
+    pub max_attempts: u32,
+    pub backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            max_attempts: MAX_RECONNECTS,
+            backoff: RECONNECT_BACKOFF,
+        }
+    }
+}
+
+impl Middleware for Retry {
+    fn on_response(&self, request: &RequestInfo, outcome: &Outcome) -> Next {
+        let Outcome::TransportError(err) = outcome else {
+            return Next::Continue;
+        };
+        let once = *request.method == Method::DELETE || *request.method == webdav_move();
+        if once || request.attempt >= self.max_attempts || inflight::cancelled() {
+            return Next::Continue;
+        }
+        let attempt = request.attempt + 1;
+        warn!(
+            "Request failed with {:?}, reconnecting ({}/{})",
+            err, attempt, self.max_attempts
+        );
+        Next::Retry {
+            after: self.backoff * attempt,
+        }
+    }
+}
+
+/// Logs every request with its status at debug level
+pub struct Logging;
+
+impl Middleware for Logging {
+    fn on_response(&self, request: &RequestInfo, outcome: &Outcome) -> Next {
+        match outcome {
+            Outcome::Response(response) => {
+                debug!("{} {} -> {}", request.method, request.url, response.status())
+            }
+            Outcome::TransportError(err) => {
+                debug!("{} {} -> {:?}", request.method, request.url, err)
+            }
+        }
+        Next::Continue
+    }
+}
+
+/// Spaces requests evenly so no more than `per_second` are sent, retries included
+pub struct RateLimit {
+    interval: Duration,
+    /// When the next request may be sent
+    next: Mutex<Instant>,
+}
+
+impl RateLimit {
+    pub fn new(per_second: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / per_second.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl Middleware for RateLimit {
+    fn on_request(&self, _request: &RequestInfo, builder: RequestBuilder) -> RequestBuilder {
+        let wait = {
+            let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let slot = (*next).max(now);
+            *next = slot + self.interval;
+            slot - now
+        };
+        thread::sleep(wait);
+        builder
+    }
+}
@@ -0,0 +1,63 @@
+use std::{
+    os::unix::net::UnixDatagram,
+    path::PathBuf,
+    thread,
+    time::Duration,
+};
+
+use crate::control::CONTROL_DIR_NAME;
+
+/// Sends a state change like "READY=1" to systemd. Does nothing when not started by systemd
+/// with `Type=notify`
+pub fn notify(state: &str) {
+    let Some(socket_path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        let socket_path = socket_path.to_string_lossy();
+        // Sockets in the abstract namespace are announced with a leading '@'. They only exist
+        // on Linux, like systemd itself
+        #[cfg(target_os = "linux")]
+        if let Some(name) = socket_path.strip_prefix('@') {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+            return socket.send_to_addr(state.as_bytes(), &addr);
+        }
+        socket.send_to(state.as_bytes(), &*socket_path)
+    });
+    if let Err(err) = result {
+        warn!("Could not notify systemd about {}: {}", state, err);
+    }
+}
+
+/// Interval in which systemd expects watchdog pings, if the watchdog is enabled for us
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // The watchdog may be meant for another process of the service
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec))
+}
+
+/// Starts a thread that pings the watchdog as long as the filesystem answers requests.
+///
+/// Every check lists the control directory, which always reaches the FUSE dispatcher but never
+/// the server. If the dispatcher is wedged, the listing blocks, pings stop and systemd restarts
+/// the service
+pub fn spawn_watchdog(mountpoint: PathBuf, interval: Duration) {
+    let probe = mountpoint.join(CONTROL_DIR_NAME);
+    thread::spawn(move || loop {
+        // Ping at half the interval, as systemd recommends
+        thread::sleep(interval / 2);
+        match std::fs::read_dir(&probe) {
+            Ok(entries) => {
+                entries.for_each(drop);
+                notify("WATCHDOG=1");
+            }
+            Err(err) => warn!("Health check of {} failed: {}", probe.display(), err),
+        }
+    });
+}
@@ -0,0 +1,126 @@
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::errors::Errors;
+
+/// Characters that are escaped inside a single path segment. Everything except the unreserved
+/// characters of RFC 3986 is encoded, so names containing spaces, '#', '?' or unicode survive
+/// the trip to the server
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Joins file names into a filesystem-absolute path. No segments results in the root "/"
+pub fn join<S: AsRef<str>>(segments: &[S]) -> String {
+    let mut path = String::from("/");
+    path.push_str(
+        &segments
+            .iter()
+            .map(|s| s.as_ref())
+            .collect::<Vec<&str>>()
+            .join("/"),
+    );
+    path
+}
+
+/// Appends a file name to a filesystem-absolute directory path
+pub fn child(parent: &str, name: &str) -> String {
+    if parent.ends_with('/') {
+        format!("{parent}{name}")
+    } else {
+        format!("{parent}/{name}")
+    }
+}
+
+/// Percent-encodes every segment of a filesystem path so it can be appended to a request URL.
+/// The separating slashes are kept as they are
+pub fn encode(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, SEGMENT).to_string())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Decodes a href returned by the server into a plain path. This is the inverse of `encode`
+pub fn decode(href: &str) -> Result<String, Errors> {
+    percent_decode_str(href)
+        .decode_utf8()
+        .map(|p| p.into_owned())
+        .map_err(|_| Errors::HrefDecodeError(href.to_string()))
+}
+
+/// Whether `path` is `prefix` itself or lies inside of it
+pub fn is_below(path: &str, prefix: &str) -> bool {
+    path == prefix
+        || path
+            .strip_prefix(prefix)
+            .map_or(false, |rest| prefix.ends_with('/') || rest.starts_with('/'))
+}
+
+/// The part of `path` below `root`, which starts with "/" or is empty for `root` itself. `None`
+/// if `path` lies outside of `root`, "/dav/user2" is not below "/dav/user". A trailing slash of
+/// `root` makes no difference
+pub fn strip_root<'a>(path: &'a str, root: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(root.trim_end_matches('/'))?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+/// Returns the path component of a URL, "https://host/dav/" becomes "/dav/". Strings that are
+/// already plain paths are returned unchanged
+pub fn url_path(url: &str) -> &str {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    without_scheme.find('/').map_or("/", |i| &without_scheme[i..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_builds_absolute_paths() {
+        assert_eq!(join::<&str>(&[]), "/");
+        assert_eq!(join(&["Meine Bilder 2024", "a.jpg"]), "/Meine Bilder 2024/a.jpg");
+        assert_eq!(child("/", "a"), "/a");
+        assert_eq!(child("/a", "b c"), "/a/b c");
+    }
+
+    #[test]
+    fn encode_escapes_each_segment() {
+        assert_eq!(encode("/"), "/");
+        assert_eq!(
+            encode("/Meine Bilder 2024/Grüße.txt"),
+            "/Meine%20Bilder%202024/Gr%C3%BC%C3%9Fe.txt"
+        );
+        assert_eq!(encode("/a#b/c?d"), "/a%23b/c%3Fd");
+    }
+
+    #[test]
+    fn decode_is_inverse_of_encode() {
+        for path in ["/", "/Meine Bilder 2024/", "/日本語/ファイル 1.txt", "/100% sure"] {
+            assert_eq!(decode(&encode(path)).unwrap(), path);
+        }
+    }
+
+    #[test]
+    fn url_path_strips_scheme_and_host() {
+        assert_eq!(url_path("https://cloud.example/remote.php/dav/"), "/remote.php/dav/");
+        assert_eq!(url_path("https://cloud.example"), "/");
+        assert_eq!(url_path("/remote.php/dav/a"), "/remote.php/dav/a");
+    }
+
+    #[test]
+    fn strip_root_stops_at_segment_boundaries() {
+        assert_eq!(strip_root("/dav/files/user/x", "/dav/files/user"), Some("/x"));
+        assert_eq!(strip_root("/dav/files/user/x", "/dav/files/user/"), Some("/x"));
+        assert_eq!(strip_root("/dav/files/user", "/dav/files/user"), Some(""));
+        assert_eq!(strip_root("/dav/files/user2/x", "/dav/files/user"), None);
+        assert_eq!(strip_root("/other/x", "/dav/files/user"), None);
+        assert_eq!(strip_root("/x", ""), Some("/x"));
+    }
+
+    #[test]
+    fn decode_rejects_invalid_utf8() {
+        assert!(decode("/%FF%FE").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use std::{ffi::CStr, path::Path};
+
+use fuser::MountOption;
+use libc::c_int;
+
+use crate::errors::Errors;
+
+/// errno for a missing extended attribute. Linux calls it ENODATA, FreeBSD ENOATTR
+#[cfg(target_os = "linux")]
+pub const ENOATTR: c_int = libc::ENODATA;
+#[cfg(not(target_os = "linux"))]
+pub const ENOATTR: c_int = libc::ENOATTR;
+
+/// rename flag: fail if the destination exists. macOS calls it RENAME_EXCL
+#[cfg(target_os = "linux")]
+pub const RENAME_NOREPLACE: u32 = libc::RENAME_NOREPLACE;
+#[cfg(not(target_os = "linux"))]
+pub const RENAME_NOREPLACE: u32 = 0x4;
+
+/// rename flag: swap source and destination. macOS calls it RENAME_SWAP
+#[cfg(target_os = "linux")]
+pub const RENAME_EXCHANGE: u32 = libc::RENAME_EXCHANGE;
+#[cfg(not(target_os = "linux"))]
+pub const RENAME_EXCHANGE: u32 = 0x2;
+
+/// Command and arguments that unmount a FUSE filesystem as an unprivileged user
+#[cfg(target_os = "linux")]
+pub const UNMOUNT_COMMAND: (&str, &[&str]) = ("fusermount", &["-u"]);
+#[cfg(not(target_os = "linux"))]
+pub const UNMOUNT_COMMAND: (&str, &[&str]) = ("umount", &[]);
+
+/// Whether the process or thread `pid` is about to die from a signal. The kernel marks every
+/// thread of a process that got a fatal signal with a pending SIGKILL
+#[cfg(target_os = "linux")]
+pub fn fatal_signal_pending(pid: u32) -> bool {
+    let Ok(status) = std::fs::read_to_string(format!("/proc/{pid}/status")) else {
+        return false;
+    };
+    status
+        .lines()
+        .filter_map(|line| line.strip_prefix("SigPnd:"))
+        .filter_map(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+        .any(|mask| mask & (1 << (libc::SIGKILL - 1)) != 0)
+}
+#[cfg(not(target_os = "linux"))]
+pub fn fatal_signal_pending(_pid: u32) -> bool {
+    false
+}
+
+/// Detaches a mount whose FUSE session died, so the mountpoint can be used again
+pub fn lazy_unmount(mountpoint: &Path) {
+    #[cfg(target_os = "linux")]
+    let status = std::process::Command::new("fusermount")
+        .arg("-uz")
+        .arg(mountpoint)
+        .status();
+    #[cfg(not(target_os = "linux"))]
+    let status = std::process::Command::new("umount")
+        .arg("-f")
+        .arg(mountpoint)
+        .status();
+    if !status.map_or(false, |s| s.success()) {
+        warn!("Could not detach the stale mount at {}", mountpoint.display());
+    }
+}
+
+/// Reads the extended attribute `name` of `path`
+pub fn getxattr(path: &Path, name: &str) -> std::io::Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    let invalid = |_| std::io::Error::from(std::io::ErrorKind::InvalidInput);
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(invalid)?;
+    let name = std::ffi::CString::new(name).map_err(invalid)?;
+    // A call with an empty buffer asks for the size. The value may grow before it is read,
+    // which fails with ERANGE and starts over
+    loop {
+        let mut value = vec![0; raw_getxattr(&path, &name, &mut [])?];
+        match raw_getxattr(&path, &name, &mut value) {
+            Ok(size) => {
+                value.truncate(size);
+                return Ok(value);
+            }
+            Err(err) if err.raw_os_error() == Some(libc::ERANGE) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn raw_getxattr(path: &CStr, name: &CStr, value: &mut [u8]) -> std::io::Result<usize> {
+    // SAFETY: both strings are NUL terminated and `value` is valid for its length
+    let size = unsafe {
+        libc::getxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr().cast(), value.len())
+    };
+    usize::try_from(size).map_err(|_| std::io::Error::last_os_error())
+}
+
+#[cfg(target_os = "macos")]
+fn raw_getxattr(path: &CStr, name: &CStr, value: &mut [u8]) -> std::io::Result<usize> {
+    // SAFETY: both strings are NUL terminated and `value` is valid for its length
+    let size = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+            0,
+            0,
+        )
+    };
+    usize::try_from(size).map_err(|_| std::io::Error::last_os_error())
+}
+
+/// Other systems keep extended attributes behind extattr(2), which has no `user.` prefix
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn raw_getxattr(_path: &CStr, _name: &CStr, _value: &mut [u8]) -> std::io::Result<usize> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+pub fn current_uid() -> u32 {
+    // SAFETY: getuid cannot fail
+    unsafe { libc::getuid() }
+}
+
+pub fn current_gid() -> u32 {
+    // SAFETY: getgid cannot fail
+    unsafe { libc::getgid() }
+}
+
+/// Checks at runtime that the kernel side of FUSE is present, so a missing module results in
+/// a helpful message instead of an obscure mount error
+pub fn check_fuse_available() -> Result<(), Errors> {
+    if Path::new("/dev/fuse").exists() {
+        return Ok(());
+    }
+    let hint = if cfg!(target_os = "freebsd") {
+        "load it with `kldload fusefs` or add fusefs_load=\"YES\" to /boot/loader.conf"
+    } else {
+        "load it with `modprobe fuse`"
+    };
+    Err(Errors::FuseUnavailable(format!(
+        "/dev/fuse does not exist, the FUSE kernel module is missing: {hint}"
+    )))
+}
+
+/// Drops mount options the platform's FUSE implementation does not understand
+pub fn supported_mount_options(options: Vec<MountOption>) -> Vec<MountOption> {
+    options
+        .into_iter()
+        .filter(|option| {
+            // fusefs on FreeBSD cannot unmount on its own when the process dies
+            let supported =
+                cfg!(target_os = "linux") || !matches!(option, MountOption::AutoUnmount);
+            if !supported {
+                warn!("Mount option {:?} is not supported on this platform", option);
+            }
+            supported
+        })
+        .collect()
+}
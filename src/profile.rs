@@ -0,0 +1,63 @@
+use std::{collections::BTreeMap, path::Path};
+
+use crate::errors::Errors;
+
+/// Named sets of mount options read from the configuration file.
+///
+/// The file is made of sections, one per profile, holding `key = value` lines. Keys are the
+/// same as the ones accepted by `-o`, e.g.
+///
+/// ```text
+/// [work]
+/// url = https://cloud.example/remote.php/dav/files/me
+/// username = me
+/// password_file = /etc/rust_webdav/work.pass
+/// case_insensitive
+/// ```
+#[derive(Debug, Default)]
+pub struct Profiles {
+    profiles: BTreeMap<String, Vec<(String, Option<String>)>>,
+}
+
+impl Profiles {
+    pub fn load(file: &Path) -> Result<Self, Errors> {
+        let content = std::fs::read_to_string(file)
+            .map_err(|e| Errors::ConfigFileError(format!("{}: {}", file.display(), e)))?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Self, Errors> {
+        let mut profiles: BTreeMap<String, Vec<(String, Option<String>)>> = BTreeMap::new();
+        let mut current: Option<String> = None;
+
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let name = name.trim().to_string();
+                profiles.entry(name.clone()).or_default();
+                current = Some(name);
+                continue;
+            }
+            let Some(profile) = &current else {
+                return Err(Errors::ConfigFileError(format!(
+                    "line {}: option outside of a [profile] section",
+                    idx + 1
+                )));
+            };
+            let option = match line.split_once('=') {
+                Some((key, value)) => (key.trim().to_string(), Some(value.trim().to_string())),
+                None => (line.to_string(), None),
+            };
+            profiles.entry(profile.clone()).or_default().push(option);
+        }
+        Ok(Self { profiles })
+    }
+
+    /// Options of the profile called `name`, in the order they appear in the file
+    pub fn get(&self, name: &str) -> Option<&[(String, Option<String>)]> {
+        self.profiles.get(name).map(Vec::as_slice)
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -15,11 +16,11 @@ pub enum ResourceType {
 
 /// Stores the data belonging to what WebDAV calls a "Prop".
 /// This can be a file or a collection (basically a folder)
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Prop {
     /// Etag is guaranteed to be stable if the Prop has not changed
     etag: String,
-    /// Path of the prop
+    /// Path of the prop, relative to the root of the drive
     path: PathBuf,
     /// Size in bytes
     size: u64,
@@ -27,6 +28,12 @@ pub struct Prop {
     last_modified: u64,
     /// Type of the prop
     resource_type: ResourceType,
+    /// Nextcloud/ownCloud specific properties such as `fileid`, keyed by tag name
+    nextcloud_props: BTreeMap<String, String>,
+    /// Dead properties set by clients, keyed by tag name
+    dead_props: BTreeMap<String, String>,
+    /// Bytes that can still be stored below a collection, if the server reports a limit
+    quota_available: Option<u64>,
 }
 
 impl Prop {
@@ -43,9 +50,23 @@ impl Prop {
             size,
             last_modified,
             resource_type,
+            nextcloud_props: BTreeMap::new(),
+            dead_props: BTreeMap::new(),
+            quota_available: None,
         }
     }
 
+    /// Moves the prop into the top-level directory `dir`, so "/a" becomes "/dir/a"
+    pub fn move_into(mut self, dir: &str) -> Self {
+        let inner = self.path.to_string_lossy().trim_start_matches('/').to_string();
+        self.path = match inner.as_str() {
+            "" => crate::path::join(&[dir]),
+            inner => crate::path::join(&[dir, inner]),
+        }
+        .into();
+        self
+    }
+
     // Getters
 
     pub fn etag(&self) -> &str {
@@ -67,6 +88,44 @@ impl Prop {
     pub fn resource_type(&self) -> ResourceType {
         self.resource_type
     }
+
+    pub fn nextcloud_props(&self) -> &BTreeMap<String, String> {
+        &self.nextcloud_props
+    }
+
+    pub fn quota_available(&self) -> Option<u64> {
+        self.quota_available
+    }
+
+    pub fn dead_props(&self) -> &BTreeMap<String, String> {
+        &self.dead_props
+    }
+}
+
+/// Props of a listing grouped by the path of the collection they are in. Each collection is
+/// part of its own group as well, so a group reads like a listing of just that collection with
+/// its quota and etag
+pub type PropTree = BTreeMap<String, Vec<Prop>>;
+
+/// Turns a flat listing into a `PropTree`
+pub fn group_by_parent(props: Vec<Prop>) -> PropTree {
+    let mut tree = PropTree::new();
+    for prop in props {
+        if prop.resource_type() == ResourceType::Collection {
+            // Hrefs of collections may end in a slash, the keys do not
+            let own = prop.path().to_string_lossy();
+            let own = match own.trim_end_matches('/') {
+                "" => "/".to_string(),
+                own => own.to_string(),
+            };
+            tree.entry(own).or_default().push(prop.clone());
+        }
+        if let Some(parent) = prop.path().parent() {
+            let parent = parent.to_string_lossy().into_owned();
+            tree.entry(parent).or_default().push(prop);
+        }
+    }
+    tree
 }
 
 /// Builder for `Prop`
@@ -84,6 +143,9 @@ impl PropBuilder {
                 size: 0,
                 last_modified: 0,
                 resource_type: ResourceType::Invalid,
+                nextcloud_props: BTreeMap::new(),
+                dead_props: BTreeMap::new(),
+                quota_available: None,
             },
         }
     }
@@ -112,6 +174,21 @@ impl PropBuilder {
         self
     }
 
+    pub fn nextcloud_prop(mut self, name: String, value: String) -> Self {
+        self.prop.nextcloud_props.insert(name, value);
+        self
+    }
+
+    pub fn dead_prop(mut self, name: String, value: String) -> Self {
+        self.prop.dead_props.insert(name, value);
+        self
+    }
+
+    pub fn quota_available(mut self, quota_available: u64) -> Self {
+        self.prop.quota_available = Some(quota_available);
+        self
+    }
+
     pub fn build(self) -> Prop {
         self.prop
     }
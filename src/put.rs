@@ -0,0 +1,122 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    delete,
+    errors::Errors,
+    path,
+    prop::ResourceType,
+    remotes::Remotes,
+    webdav::PropfindDepth,
+};
+
+/// Uploads `source` to `target` on `remotes`, a directory only with `recursive` and then with
+/// everything below it. Entries that fail do not stop the others, entries below a directory
+/// that could not be created are not tried. Returns the result of every entry as JSON, the
+/// way `delete::delete_levels` reports them
+pub fn upload(
+    remotes: &Remotes,
+    source: &Path,
+    target: &str,
+    recursive: bool,
+) -> Result<serde_json::Value, Errors> {
+    let metadata = fs::metadata(source).map_err(|err| local_error(source, err))?;
+    if metadata.is_dir() && !recursive {
+        let message = format!("{} is a directory, -r uploads directories", source.display());
+        return Err(Errors::InvalidArguments(message));
+    }
+    let mut results: Vec<serde_json::Value> = vec![];
+    let mut failed: Vec<String> = vec![];
+    for (local, remote, entry) in local_tree(source, target) {
+        if let Some(dir) = failed.iter().find(|dir| path::is_below(&remote, dir)) {
+            results.push(serde_json::json!({
+                "path": remote,
+                "ok": false,
+                "error": "parent_missing",
+                "status": null,
+                "message": format!("{} could not be created", dir),
+            }));
+            continue;
+        }
+        let result = entry.and_then(|is_dir| match is_dir {
+            true => create_directory(remotes, &remote),
+            false => upload_file(remotes, &local, &remote),
+        });
+        if result.is_err() {
+            failed.push(remote.clone());
+        }
+        results.push(delete::item_result(&remote, result));
+    }
+    let failures: Vec<&serde_json::Value> =
+        results.iter().filter(|result| result["ok"] != true).collect();
+    Ok(serde_json::json!({
+        "path": target,
+        "uploaded": results.len() - failures.len(),
+        "failed": failures,
+        "results": results,
+    }))
+}
+
+/// Local paths below `source` with their paths below `target` and whether they are
+/// directories, every directory before its entries
+fn local_tree(source: &Path, target: &str) -> Vec<(PathBuf, String, Result<bool, Errors>)> {
+    let mut entries = vec![];
+    let mut pending = vec![(source.to_path_buf(), target.to_string())];
+    while let Some((local, remote)) = pending.pop() {
+        let is_dir = fs::metadata(&local).map(|m| m.is_dir());
+        if let Ok(true) = is_dir {
+            match fs::read_dir(&local) {
+                Ok(dir) => {
+                    let mut children: Vec<PathBuf> = dir.flatten().map(|e| e.path()).collect();
+                    // Popped from the end, so entries go up in the order of their names
+                    children.sort();
+                    children.reverse();
+                    for child in children {
+                        let name = child.file_name().unwrap_or_default().to_string_lossy();
+                        let child_remote = path::child(&remote, &name);
+                        pending.push((child, child_remote));
+                    }
+                }
+                Err(err) => {
+                    entries.push((local.clone(), remote, Err(local_error(&local, err))));
+                    continue;
+                }
+            }
+        }
+        let is_dir = is_dir.map_err(|err| local_error(&local, err));
+        entries.push((local, remote, is_dir));
+    }
+    entries
+}
+
+/// Creates the collection at `path`, which is fine if it exists already
+fn create_directory(remotes: &Remotes, path: &str) -> Result<(), Errors> {
+    let is_collection = || {
+        let listing = remotes.list(path, PropfindDepth::ElementOnly).unwrap_or_default();
+        listing.iter().any(|prop| prop.resource_type() == ResourceType::Collection)
+    };
+    match remotes.mkcol(path) {
+        // MKCOL answers 405 if there is something at the path already, file or collection
+        Err(Errors::HttpStatus(405)) if is_collection() => Ok(()),
+        result => result,
+    }
+}
+
+/// Uploads the local file at `local` to `remote`, keeping its modification time
+fn upload_file(remotes: &Remotes, local: &Path, remote: &str) -> Result<(), Errors> {
+    let data = fs::read(local).map_err(|err| local_error(local, err))?;
+    let mtime = fs::metadata(local)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|mtime| mtime.as_secs());
+    remotes.put(remote, data, mtime)
+}
+
+/// A local entry that cannot be read
+fn local_error(path: &Path, err: std::io::Error) -> Errors {
+    Errors::LocalFileError(format!("{}: {}", path.display(), err))
+}
@@ -0,0 +1,46 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{cli, config::Config};
+
+/// Set by the signal handler, picked up by the reload thread
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sighup(_signal: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Installs a SIGHUP handler and starts a thread that re-reads the configuration whenever the
+/// signal arrives. The command line is parsed again, so profiles and the configuration file are
+/// picked up fresh. New configurations are sent to the returned receiver
+pub fn spawn_reloader(args: Vec<String>) -> Receiver<Config> {
+    // SAFETY: the handler only touches an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGHUP, on_sighup as libc::sighandler_t);
+    }
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(500));
+        if !RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+            continue;
+        }
+        info!("Received SIGHUP, reloading configuration");
+        match cli::parse(&args) {
+            Ok(cli::Command::Mount(mount)) => {
+                // The filesystem is gone, nothing left to reload
+                if sender.send(mount.config).is_err() {
+                    break;
+                }
+            }
+            Ok(_) => {}
+            Err(err) => warn!("Keeping the current configuration: {:?}", err),
+        }
+    });
+    receiver
+}
@@ -0,0 +1,330 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::backend::Backend;
+use crate::errors::Errors;
+use crate::prop::{self, Prop, PropTree, ResourceType};
+use crate::webdav::{self, PropfindDepth, Quota, Version};
+
+/// How often a snapshot is listed again because the remote changed meanwhile
+const SNAPSHOT_ATTEMPTS: u32 = 3;
+
+/// The remotes served by one filesystem. A single remote is shown at the mountpoint itself.
+/// Several remotes either each get a top-level directory named after their profile, so one
+/// process serves all accounts of a user, or are merged into a union
+pub enum Remotes {
+    Single(Box<dyn Backend>),
+    /// Drives keyed by the name of their directory
+    Multiple(BTreeMap<String, Box<dyn Backend>>),
+    /// Drives merged into one tree. A path is taken from the first drive that has it, changes
+    /// go to the drive at index `write_target`
+    Union {
+        drives: Vec<Box<dyn Backend>>,
+        write_target: usize,
+    },
+}
+
+impl Remotes {
+    /// Finds the drive `path` belongs to. Returns the name of its directory, if it has one, the
+    /// drive and the path inside of the drive. `None` for the root of several remotes, which
+    /// exists only locally. Union mounts route to their write target
+    fn route(&self, path: &str) -> Result<Option<(Option<&str>, &dyn Backend, String)>, Errors> {
+        let drives = match self {
+            Remotes::Single(drive) => return Ok(Some((None, &**drive, path.to_string()))),
+            Remotes::Union {
+                drives,
+                write_target,
+            } => return Ok(Some((None, &*drives[*write_target], path.to_string()))),
+            Remotes::Multiple(drives) => drives,
+        };
+        let relative = path.trim_start_matches('/');
+        if relative.is_empty() {
+            return Ok(None);
+        }
+        let (name, inner) = relative.split_once('/').unwrap_or((relative, ""));
+        let (name, drive) = drives
+            .get_key_value(name)
+            .ok_or_else(|| Errors::FileDoesNotExist(path.into()))?;
+        Ok(Some((Some(name.as_str()), &**drive, format!("/{inner}"))))
+    }
+
+    /// Lists `path` like `Backend::list`. Paths of the returned props are relative to the
+    /// mountpoint
+    pub fn list(&self, path: &str, depth: PropfindDepth) -> Result<Vec<Prop>, Errors> {
+        if let Remotes::Union { drives, .. } = self {
+            return list_union(drives, path, depth);
+        }
+        let (dir, drive, inner) = match self.route(path)? {
+            Some(route) => route,
+            None => return self.list_root(depth),
+        };
+        let props = drive.list(&inner, depth)?;
+        Ok(match dir {
+            Some(dir) => props.into_iter().map(|p| p.move_into(dir)).collect(),
+            None => props,
+        })
+    }
+
+    /// Like `list`, but grouped by collection, so a recursive listing can fill a whole tree
+    pub fn list_tree(&self, path: &str, depth: PropfindDepth) -> Result<PropTree, Errors> {
+        self.list(path, depth).map(prop::group_by_parent)
+    }
+
+    /// Recursive listing of everything for a snapshot mount. The sync tokens of all drives are
+    /// compared before and after listing, a listing that overlapped with changes is repeated
+    /// up to `SNAPSHOT_ATTEMPTS` times. Servers without sync tokens get a single listing
+    pub fn list_snapshot(&self) -> Result<PropTree, Errors> {
+        let mut attempt = 1;
+        loop {
+            let before = self.sync_tokens()?;
+            let tree = self.list_tree("/", PropfindDepth::Recursive)?;
+            if before.iter().any(Option::is_none) {
+                warn!("The server does not support sync tokens, the snapshot may be inconsistent");
+                return Ok(tree);
+            }
+            if self.sync_tokens()? == before {
+                info!("Snapshot taken at sync tokens {:?}", before);
+                return Ok(tree);
+            }
+            if attempt == SNAPSHOT_ATTEMPTS {
+                warn!("The remote kept changing while listing, the snapshot may be inconsistent");
+                return Ok(tree);
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Sync tokens of the roots of all drives, `None` for drives without them
+    pub fn sync_tokens(&self) -> Result<Vec<Option<String>>, Errors> {
+        self.drives().map(|drive| drive.sync_token("/")).collect()
+    }
+
+    /// Storage of all drives together. Drives without a limit are left out, `None` if no drive
+    /// has one
+    pub fn quota(&self) -> Result<Option<Quota>, Errors> {
+        let mut total: Option<Quota> = None;
+        for drive in self.drives() {
+            if let Some(quota) = drive.quota("/")? {
+                let total = total.get_or_insert_with(Quota::default);
+                total.used += quota.used;
+                total.available += quota.available;
+            }
+        }
+        Ok(total)
+    }
+
+    fn drives(&self) -> Box<dyn Iterator<Item = &dyn Backend> + '_> {
+        match self {
+            Remotes::Single(drive) => Box::new(std::iter::once(&**drive)),
+            Remotes::Multiple(drives) => Box::new(drives.values().map(|d| &**d)),
+            Remotes::Union { drives, .. } => Box::new(drives.iter().map(|d| &**d)),
+        }
+    }
+
+    /// Lists the local root holding one directory per remote
+    fn list_root(&self, depth: PropfindDepth) -> Result<Vec<Prop>, Errors> {
+        let Remotes::Multiple(drives) = self else {
+            return Ok(vec![]);
+        };
+        let directory = |path: &str| {
+            Prop::new(String::new(), PathBuf::from(path), 0, ResourceType::Collection, 0)
+        };
+        let mut props = vec![directory("/")];
+        for (name, drive) in drives {
+            match depth {
+                PropfindDepth::ElementOnly => {}
+                PropfindDepth::WithChildren => props.push(directory(&format!("/{name}"))),
+                PropfindDepth::Recursive => props.extend(
+                    drive
+                        .list("/", depth)?
+                        .into_iter()
+                        .map(|p| p.move_into(name)),
+                ),
+            }
+        }
+        Ok(props)
+    }
+
+    /// The drive holding `path` and the path inside of it. Fails for the root of several
+    /// remotes, nothing can be changed there
+    fn drive_for(&self, path: &str) -> Result<(&dyn Backend, String), Errors> {
+        match self.route(path)? {
+            Some((_, drive, inner)) => Ok((drive, inner)),
+            None => Err(Errors::FileDoesNotExist(path.into())),
+        }
+    }
+
+    /// Reads a part of the file at `path` like `Backend::get_range`. Union mounts read from
+    /// the first drive that has the file, the one its listing entry came from
+    pub fn get_range(&self, path: &str, offset: u64, size: u64) -> Result<Vec<u8>, Errors> {
+        let Remotes::Union { drives, .. } = self else {
+            let (drive, inner) = self.drive_for(path)?;
+            return drive.get_range(&inner, offset, size);
+        };
+        for drive in drives {
+            match drive.get_range(path, offset, size) {
+                Err(Errors::HttpStatus(404)) => continue,
+                result => return result,
+            }
+        }
+        Err(Errors::FileDoesNotExist(path.into()))
+    }
+
+    /// Earlier versions of the file at `path` with the Nextcloud `fileid`. File ids are only
+    /// unique per server, so union mounts, which cannot tell which drive a file came from, have
+    /// no versions
+    pub fn versions(&self, path: &str, fileid: &str) -> Result<Vec<Version>, Errors> {
+        if let Remotes::Union { .. } = self {
+            return Ok(vec![]);
+        }
+        let (drive, _) = self.drive_for(path)?;
+        drive.versions(fileid)
+    }
+
+    /// Reads a part of an earlier version of the file at `path`
+    pub fn get_version_range(
+        &self,
+        path: &str,
+        version: &Version,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, Errors> {
+        let (drive, _) = self.drive_for(path)?;
+        drive.get_version_range(version, offset, size)
+    }
+
+    pub fn put(&self, path: &str, data: Vec<u8>, mtime: Option<u64>) -> Result<(), Errors> {
+        let (drive, inner) = self.drive_for(path)?;
+        drive.put(&inner, data, mtime)
+    }
+
+    pub fn mkcol(&self, path: &str) -> Result<(), Errors> {
+        let (drive, inner) = self.drive_for(path)?;
+        drive.mkcol(&inner)
+    }
+
+    pub fn create_symlink(&self, path: &str, target: &str) -> Result<(), Errors> {
+        let (drive, inner) = self.drive_for(path)?;
+        drive.create_symlink(&inner, target)
+    }
+
+    /// URL of `path` on its server. The root of several remotes has none and gets its path
+    pub fn href(&self, path: &str) -> String {
+        match self.route(path) {
+            Ok(Some((_, drive, inner))) => drive.href(&inner),
+            _ => path.to_string(),
+        }
+    }
+
+    pub fn set_property(&self, path: &str, name: &str, value: &str) -> Result<(), Errors> {
+        let (drive, inner) = self.drive_for(path)?;
+        drive.set_property(&inner, name, value)
+    }
+
+    pub fn remove_property(&self, path: &str, name: &str) -> Result<(), Errors> {
+        let (drive, inner) = self.drive_for(path)?;
+        drive.remove_property(&inner, name)
+    }
+
+    pub fn set_mtime(&self, path: &str, mtime: u64) -> Result<(), Errors> {
+        let (drive, inner) = self.drive_for(path)?;
+        drive.set_mtime(&inner, mtime)
+    }
+
+    /// Deletes the file or directory at `path`. Union mounts only delete from the write target,
+    /// a copy on another drive shows up again with the next listing
+    pub fn delete(&self, path: &str) -> Result<(), Errors> {
+        let (drive, inner) = self.drive_for(path)?;
+        drive.delete(&inner)
+    }
+
+    /// Moves `from` to `to`, which must belong to the same drive. Moving between the remotes of
+    /// a mount needs a copy, which is left to the caller
+    pub fn move_to(&self, from: &str, to: &str, overwrite: bool) -> Result<(), Errors> {
+        let (Some((from_name, drive, from_inner)), Some((to_name, _, to_inner))) =
+            (self.route(from)?, self.route(to)?)
+        else {
+            return Err(Errors::FileDoesNotExist(from.into()));
+        };
+        if from_name != to_name {
+            return Err(Errors::MoveAcrossRemotes(from.into()));
+        }
+        drive.move_to(&from_inner, &to_inner, overwrite)
+    }
+
+    /// Copies `from` to `to` on the server, both must belong to the same drive
+    pub fn copy_to(&self, from: &str, to: &str) -> Result<(), Errors> {
+        let (Some((from_name, drive, from_inner)), Some((to_name, _, to_inner))) =
+            (self.route(from)?, self.route(to)?)
+        else {
+            return Err(Errors::FileDoesNotExist(from.into()));
+        };
+        if from_name != to_name {
+            return Err(Errors::MoveAcrossRemotes(from.into()));
+        }
+        drive.copy_to(&from_inner, &to_inner)
+    }
+
+    /// Deletes the temporary files of atomic uploads in `tree` that are older than
+    /// `webdav::STALE_PART_AGE` and were left behind by interrupted uploads. Younger ones may
+    /// belong to an upload still running on another client and are kept
+    pub fn remove_stale_uploads(&self, tree: &mut PropTree) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let cutoff = now.saturating_sub(webdav::STALE_PART_AGE);
+        for props in tree.values_mut() {
+            props.retain(|prop| {
+                let stale = prop.resource_type() == ResourceType::File
+                    && Duration::from_secs(prop.last_modified()) < cutoff
+                    && prop
+                        .path()
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .map_or(false, webdav::is_part_name);
+                if !stale {
+                    return true;
+                }
+                let path = prop.path().to_string_lossy();
+                info!("Removing stale upload {path}");
+                match self.drive_for(&path).and_then(|(drive, inner)| drive.delete(&inner)) {
+                    Ok(()) => false,
+                    Err(err) => {
+                        warn!("Cannot remove stale upload {path}: {err:?}");
+                        true
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Merges the listings of every drive that has `path`. Entries of earlier drives hide the
+/// entries with the same path of later ones. Fails only if no drive has `path`
+fn list_union(
+    drives: &[Box<dyn Backend>],
+    path: &str,
+    depth: PropfindDepth,
+) -> Result<Vec<Prop>, Errors> {
+    let mut seen = BTreeSet::new();
+    let mut merged = vec![];
+    let mut first_error = None;
+    let mut found = false;
+    for drive in drives {
+        match drive.list(path, depth) {
+            Ok(props) => {
+                found = true;
+                let unseen = props
+                    .into_iter()
+                    .filter(|prop| seen.insert(prop.path().to_path_buf()));
+                merged.extend(unseen);
+            }
+            Err(err) => {
+                first_error.get_or_insert(err);
+            }
+        }
+    }
+    match first_error {
+        Some(err) if !found => Err(err),
+        _ => Ok(merged),
+    }
+}
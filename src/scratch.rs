@@ -0,0 +1,152 @@
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{control::CONTROL_DIR_INODE, errors::Errors};
+
+/// Scratch files get inodes from this one up to the control directory, far above the inodes
+/// handed out for remote files
+const FIRST_SCRATCH_INODE: u64 = CONTROL_DIR_INODE - (1 << 32);
+
+/// A file of the scratch directory
+struct ScratchFile {
+    name: OsString,
+    data: Vec<u8>,
+    /// Seconds since the epoch
+    mtime: u64,
+}
+
+/// Files kept in memory only, for swap and backup files of editors that insist on creating
+/// them somewhere. Nothing of it is ever uploaded, it is gone once the mount ends. The
+/// directory is flat, subdirectories cannot be created
+pub struct ScratchDir {
+    files: BTreeMap<u64, ScratchFile>,
+    next_inode: u64,
+}
+
+impl Default for ScratchDir {
+    fn default() -> Self {
+        Self {
+            files: BTreeMap::new(),
+            next_inode: FIRST_SCRATCH_INODE,
+        }
+    }
+}
+
+impl ScratchDir {
+    pub fn contains(&self, inode: u64) -> bool {
+        self.files.contains_key(&inode)
+    }
+
+    pub fn lookup(&self, name: &OsStr) -> Option<u64> {
+        let mut files = self.files.iter();
+        files.find(|(_, file)| file.name == name).map(|(inode, _)| *inode)
+    }
+
+    /// Size and modification time of a file
+    pub fn attributes(&self, inode: u64) -> Option<(u64, u64)> {
+        let file = self.files.get(&inode)?;
+        Some((file.data.len() as u64, file.mtime))
+    }
+
+    /// Every file with its inode, ordered by inode
+    pub fn entries(&self) -> impl Iterator<Item = (u64, &OsStr)> {
+        self.files.iter().map(|(inode, file)| (*inode, file.name.as_os_str()))
+    }
+
+    /// Creates an empty file and returns its inode
+    pub fn create(&mut self, name: &OsStr) -> Result<u64, Errors> {
+        if self.lookup(name).is_some() {
+            return Err(Errors::FileExists(name.into()));
+        }
+        let inode = self.next_inode;
+        // Wrapping around takes billions of files, far more than a mount holds at once
+        self.next_inode = match inode + 1 {
+            CONTROL_DIR_INODE => FIRST_SCRATCH_INODE,
+            next => next,
+        };
+        let file = ScratchFile {
+            name: name.to_os_string(),
+            data: vec![],
+            mtime: now(),
+        };
+        self.files.insert(inode, file);
+        Ok(inode)
+    }
+
+    pub fn read(&self, inode: u64) -> Option<&[u8]> {
+        self.files.get(&inode).map(|file| &file.data[..])
+    }
+
+    /// Writes `data` at `offset`, filling a gap after the end with zeros
+    pub fn write(&mut self, inode: u64, offset: u64, data: &[u8]) -> Option<u32> {
+        let file = self.files.get_mut(&inode)?;
+        let start = offset as usize;
+        let end = start + data.len();
+        if file.data.len() < end {
+            file.data.resize(end, 0);
+        }
+        file.data[start..end].copy_from_slice(data);
+        file.mtime = now();
+        Some(data.len() as u32)
+    }
+
+    pub fn truncate(&mut self, inode: u64, size: u64) -> Option<()> {
+        let file = self.files.get_mut(&inode)?;
+        file.data.resize(size as usize, 0);
+        file.mtime = now();
+        Some(())
+    }
+
+    pub fn set_mtime(&mut self, inode: u64, mtime: SystemTime) -> Option<()> {
+        let file = self.files.get_mut(&inode)?;
+        file.mtime = mtime.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        Some(())
+    }
+
+    pub fn unlink(&mut self, name: &OsStr) -> Result<(), Errors> {
+        let inode = self
+            .lookup(name)
+            .ok_or_else(|| Errors::FileDoesNotExist(name.into()))?;
+        self.files.remove(&inode);
+        Ok(())
+    }
+
+    /// Renames `from` to `to`, replacing a file that has that name already
+    pub fn rename(&mut self, from: &OsStr, to: &OsStr) -> Result<(), Errors> {
+        let inode = self
+            .lookup(from)
+            .ok_or_else(|| Errors::FileDoesNotExist(from.into()))?;
+        if let Some(replaced) = self.lookup(to).filter(|&replaced| replaced != inode) {
+            self.files.remove(&replaced);
+        }
+        if let Some(file) = self.files.get_mut(&inode) {
+            file.name = to.to_os_string();
+        }
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+    now.unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rename_replaces_the_target() {
+        let mut scratch = ScratchDir::default();
+        let swap = scratch.create(OsStr::new("a.swp")).unwrap();
+        let old = scratch.create(OsStr::new("a~")).unwrap();
+        scratch.write(swap, 2, b"hi").unwrap();
+        scratch.rename(OsStr::new("a.swp"), OsStr::new("a~")).unwrap();
+        assert_eq!(scratch.lookup(OsStr::new("a~")), Some(swap));
+        assert!(!scratch.contains(old));
+        assert_eq!(scratch.read(swap), Some(&b"\0\0hi"[..]));
+    }
+}
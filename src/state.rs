@@ -0,0 +1,363 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{errors::Errors, path};
+
+/// How many etags are remembered per file
+pub const HISTORY_LEN: usize = 16;
+
+/// An etag a listing reported for a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EtagRecord {
+    pub etag: String,
+    /// Modification time the server reported with the etag
+    pub mtime: u64,
+    /// When the etag was first seen, in seconds since the epoch
+    pub seen_at: u64,
+}
+
+/// Persistent state of a mount that has to survive remounts.
+///
+/// The database is an append-only text file with one `<inode> <encoded path>` record per line.
+/// Later records win over earlier ones, so updating an entry is a matter of appending it again.
+/// A record with inode 0, which FUSE never uses, removes the path.
+///
+/// The etags seen for each path are kept next to it in a `.history` file with one
+/// `<seen_at> <mtime> <encoded etag> <encoded path>` record per line.
+///
+/// Entries known to be missing are kept in a `.missing` file with one
+/// `<encoded etag of the directory> <encoded path>` record per line
+pub struct StateStore {
+    /// Location of the database, `None` keeps the state in memory only
+    file: Option<PathBuf>,
+    inodes: BTreeMap<String, u64>,
+    /// The last `HISTORY_LEN` etags of each path, oldest first
+    history: BTreeMap<String, VecDeque<EtagRecord>>,
+    /// Names of entries missing from a directory, by its path, with the etag it had then
+    missing: BTreeMap<String, (String, BTreeSet<String>)>,
+}
+
+impl StateStore {
+    /// A store that forgets everything on unmount
+    pub fn in_memory() -> Self {
+        Self {
+            file: None,
+            inodes: BTreeMap::new(),
+            history: BTreeMap::new(),
+            missing: BTreeMap::new(),
+        }
+    }
+
+    /// Opens the database at `file`, creating it if it does not exist yet
+    pub fn open(file: PathBuf) -> Result<Self, Errors> {
+        let mut inodes = BTreeMap::new();
+        if file.exists() {
+            let reader = BufReader::new(File::open(&file).map_err(io_error)?);
+            for line in reader.lines() {
+                let line = line.map_err(io_error)?;
+                // Skip lines a crash left half written
+                let Some((inode, encoded)) = line.split_once(' ') else {
+                    continue;
+                };
+                match (inode.parse(), path::decode(encoded)) {
+                    (Ok(0), Ok(path)) => {
+                        inodes.remove(&path);
+                    }
+                    (Ok(inode), Ok(path)) => {
+                        inodes.insert(path, inode);
+                    }
+                    _ => {}
+                }
+            }
+        } else if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir).map_err(io_error)?;
+        }
+        let history = read_history(&history_file(&file))?;
+        let missing = read_missing(&missing_file(&file))?;
+        Ok(Self {
+            file: Some(file),
+            inodes,
+            history,
+            missing,
+        })
+    }
+
+    /// The inode that was assigned to `path` during an earlier mount
+    pub fn inode_for(&self, path: &str) -> Option<u64> {
+        self.inodes.get(path).copied()
+    }
+
+    /// Largest inode number ever handed out
+    pub fn max_inode(&self) -> Option<u64> {
+        self.inodes.values().max().copied()
+    }
+
+    /// Remembers that `path` has the inode number `inode`
+    pub fn record_inode(&mut self, path: &str, inode: u64) -> Result<(), Errors> {
+        if self.inodes.insert(path.to_string(), inode) == Some(inode) {
+            return Ok(());
+        }
+        self.append(&[(inode, path)])
+    }
+
+    /// Every remembered path with its inode
+    pub fn entries(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.inodes.iter().map(|(path, inode)| (path.as_str(), *inode))
+    }
+
+    /// Etags seen for the file at `path`, oldest first. History is kept by path, a renamed file
+    /// starts a new one
+    pub fn history(&self, path: &str) -> impl Iterator<Item = &EtagRecord> {
+        self.history.get(path).into_iter().flatten()
+    }
+
+    /// Remembers that a listing reported `etag` for `path`. Nothing is recorded as long as the
+    /// etag stays the same
+    pub fn record_etag(&mut self, path: &str, etag: &str, mtime: u64) -> Result<(), Errors> {
+        let records = self.history.entry(path.to_string()).or_default();
+        if records.back().map_or(false, |last| last.etag == etag) {
+            return Ok(());
+        }
+        let seen_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let record = EtagRecord {
+            etag: etag.to_string(),
+            mtime,
+            seen_at: seen_at.as_secs(),
+        };
+        let Some(file) = &self.file else {
+            push_bounded(records, record);
+            return Ok(());
+        };
+        let mut db = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(history_file(file))
+            .map_err(io_error)?;
+        writeln!(db, "{}", history_line(path, &record)).map_err(io_error)?;
+        push_bounded(records, record);
+        Ok(())
+    }
+
+    /// Whether `name` was missing from the directory at `directory` while it had the etag
+    /// `etag`
+    pub fn is_missing(&self, directory: &str, etag: &str, name: &str) -> bool {
+        self.missing
+            .get(directory)
+            .filter(|(known_etag, _)| known_etag == etag)
+            .map_or(false, |(_, names)| names.contains(name))
+    }
+
+    /// Remembers that `name` is missing from the directory at `directory`, which has the etag
+    /// `etag`. Names recorded under another etag are dropped
+    pub fn record_missing(
+        &mut self,
+        directory: &str,
+        etag: &str,
+        name: &str,
+    ) -> Result<(), Errors> {
+        if self.is_missing(directory, etag, name) {
+            return Ok(());
+        }
+        let path = path::child(directory, name);
+        if let Some(file) = &self.file {
+            let mut db = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(missing_file(file))
+                .map_err(io_error)?;
+            writeln!(db, "{} {}", path::encode(etag), path::encode(&path)).map_err(io_error)?;
+        }
+        insert_missing(&mut self.missing, &path, etag);
+        Ok(())
+    }
+
+    /// Drops the assignment of `path`, it gets a fresh inode when it shows up again
+    pub fn forget(&mut self, path: &str) -> Result<(), Errors> {
+        if self.inodes.remove(path).is_none() {
+            return Ok(());
+        }
+        self.append(&[(0, path)])
+    }
+
+    /// Rewrites the database with only the current assignments, dropping every record that
+    /// was overwritten or removed later
+    pub fn compact(&self) -> Result<(), Errors> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let temporary = file.with_extension("compact");
+        let mut db = File::create(&temporary).map_err(io_error)?;
+        for (path, inode) in &self.inodes {
+            writeln!(db, "{} {}", inode, path::encode(path)).map_err(io_error)?;
+        }
+        db.sync_all().map_err(io_error)?;
+        std::fs::rename(&temporary, file).map_err(io_error)?;
+
+        // The history file only grows while mounted and is trimmed to `HISTORY_LEN` here
+        let temporary = file.with_extension("history.compact");
+        let mut db = File::create(&temporary).map_err(io_error)?;
+        for (path, records) in &self.history {
+            for record in records {
+                writeln!(db, "{}", history_line(path, record)).map_err(io_error)?;
+            }
+        }
+        db.sync_all().map_err(io_error)?;
+        std::fs::rename(&temporary, history_file(file)).map_err(io_error)?;
+
+        // Names recorded under an etag that changed later are left out
+        let temporary = file.with_extension("missing.compact");
+        let mut db = File::create(&temporary).map_err(io_error)?;
+        for (directory, (etag, names)) in &self.missing {
+            for name in names {
+                let path = path::encode(&path::child(directory, name));
+                writeln!(db, "{} {}", path::encode(etag), path).map_err(io_error)?;
+            }
+        }
+        db.sync_all().map_err(io_error)?;
+        std::fs::rename(&temporary, missing_file(file)).map_err(io_error)
+    }
+
+    /// Moves the assignments of `from` and everything below it to `to`
+    pub fn rename_prefix(&mut self, from: &str, to: &str) -> Result<(), Errors> {
+        let moved: Vec<(String, u64)> = self
+            .inodes
+            .iter()
+            .filter(|(path, _)| path::is_below(path, from))
+            .map(|(path, inode)| (path.clone(), *inode))
+            .collect();
+
+        let mut records = Vec::with_capacity(moved.len() * 2);
+        for (old_path, inode) in &moved {
+            let new_path = to.to_string() + &old_path[from.len()..];
+            self.inodes.remove(old_path);
+            self.inodes.insert(new_path.clone(), *inode);
+            records.push((0, old_path.clone()));
+            records.push((*inode, new_path));
+        }
+        let records: Vec<(u64, &str)> = records.iter().map(|(i, p)| (*i, p.as_str())).collect();
+        self.append(&records)
+    }
+
+    /// Appends records to the database file, if there is one
+    fn append(&self, records: &[(u64, &str)]) -> Result<(), Errors> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let mut db = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)
+            .map_err(io_error)?;
+        for (inode, path) in records {
+            writeln!(db, "{} {}", inode, path::encode(path)).map_err(io_error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the etag history of the database at `file` is kept
+fn history_file(file: &Path) -> PathBuf {
+    file.with_extension("history")
+}
+
+fn history_line(path: &str, record: &EtagRecord) -> String {
+    format!(
+        "{} {} {} {}",
+        record.seen_at,
+        record.mtime,
+        path::encode(&record.etag),
+        path::encode(path)
+    )
+}
+
+/// Loads the etag history, keeping the last `HISTORY_LEN` records of each path
+fn read_history(file: &Path) -> Result<BTreeMap<String, VecDeque<EtagRecord>>, Errors> {
+    let mut history: BTreeMap<String, VecDeque<EtagRecord>> = BTreeMap::new();
+    if !file.exists() {
+        return Ok(history);
+    }
+    let reader = BufReader::new(File::open(file).map_err(io_error)?);
+    for line in reader.lines() {
+        let line = line.map_err(io_error)?;
+        let fields: Vec<&str> = line.splitn(4, ' ').collect();
+        // Skip lines a crash left half written
+        let [seen_at, mtime, etag, encoded] = fields[..] else {
+            continue;
+        };
+        let (Ok(seen_at), Ok(mtime), Ok(etag), Ok(path)) = (
+            seen_at.parse(),
+            mtime.parse(),
+            path::decode(etag),
+            path::decode(encoded),
+        ) else {
+            continue;
+        };
+        let record = EtagRecord {
+            etag,
+            mtime,
+            seen_at,
+        };
+        push_bounded(history.entry(path).or_default(), record);
+    }
+    Ok(history)
+}
+
+/// Where the entries known to be missing of the database at `file` are kept
+fn missing_file(file: &Path) -> PathBuf {
+    file.with_extension("missing")
+}
+
+/// Loads the entries known to be missing, keeping the last etag recorded for each directory
+fn read_missing(file: &Path) -> Result<BTreeMap<String, (String, BTreeSet<String>)>, Errors> {
+    let mut missing = BTreeMap::new();
+    if !file.exists() {
+        return Ok(missing);
+    }
+    let reader = BufReader::new(File::open(file).map_err(io_error)?);
+    for line in reader.lines() {
+        let line = line.map_err(io_error)?;
+        // Skip lines a crash left half written
+        let Some((etag, encoded)) = line.split_once(' ') else {
+            continue;
+        };
+        if let (Ok(etag), Ok(path)) = (path::decode(etag), path::decode(encoded)) {
+            insert_missing(&mut missing, &path, &etag);
+        }
+    }
+    Ok(missing)
+}
+
+/// Adds `path` to the names missing from its directory, starting over if the directory had
+/// another etag so far
+fn insert_missing(
+    missing: &mut BTreeMap<String, (String, BTreeSet<String>)>,
+    path: &str,
+    etag: &str,
+) {
+    let Some((directory, name)) = path.rsplit_once('/') else {
+        return;
+    };
+    let directory = if directory.is_empty() { "/" } else { directory };
+    let (known_etag, names) = missing.entry(directory.to_string()).or_default();
+    if known_etag != etag {
+        *known_etag = etag.to_string();
+        names.clear();
+    }
+    names.insert(name.to_string());
+}
+
+fn push_bounded(records: &mut VecDeque<EtagRecord>, record: EtagRecord) {
+    records.push_back(record);
+    if records.len() > HISTORY_LEN {
+        records.pop_front();
+    }
+}
+
+fn io_error(err: std::io::Error) -> Errors {
+    Errors::StateIoError(err.kind())
+}
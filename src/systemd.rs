@@ -0,0 +1,166 @@
+use std::path::{Path, PathBuf};
+
+use crate::errors::Errors;
+
+/// Directory `systemd-creds` stores encrypted credentials in by default
+const CREDSTORE: &str = "/etc/credstore.encrypted";
+
+/// Arguments of `rust_webdav systemd`
+#[derive(Debug)]
+pub struct SystemdArgs {
+    pub profile: String,
+    pub mountpoint: PathBuf,
+    /// Write the units into this directory instead of printing them
+    pub output_dir: Option<PathBuf>,
+}
+
+impl SystemdArgs {
+    pub fn parse(args: &[String]) -> Result<Self, Errors> {
+        let mut profile = None;
+        let mut mountpoint = None;
+        let mut output_dir = None;
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            let mut value = || {
+                iter.next()
+                    .cloned()
+                    .ok_or_else(|| Errors::InvalidArguments(format!("{arg} needs a value")))
+            };
+            match arg.as_str() {
+                "--profile" => profile = Some(value()?),
+                "--mountpoint" => mountpoint = Some(PathBuf::from(value()?)),
+                "--output-dir" => output_dir = Some(PathBuf::from(value()?)),
+                other => {
+                    return Err(Errors::InvalidArguments(format!(
+                        "unknown argument {other}"
+                    )))
+                }
+            }
+        }
+        Ok(Self {
+            profile: profile
+                .ok_or_else(|| Errors::InvalidArguments("--profile is required".into()))?,
+            mountpoint: mountpoint
+                .ok_or_else(|| Errors::InvalidArguments("--mountpoint is required".into()))?,
+            output_dir,
+        })
+    }
+}
+
+/// Escapes a path the way `systemd-escape --path` does, which is how mount and automount
+/// units have to be named
+pub fn escape_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    let trimmed = path.trim_matches('/');
+    if trimmed.is_empty() {
+        return "-".into();
+    }
+    let mut escaped = String::new();
+    for (idx, byte) in trimmed.bytes().enumerate() {
+        match byte {
+            b'/' => escaped.push('-'),
+            b'.' if idx == 0 => escaped.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => {
+                escaped.push(byte as char)
+            }
+            _ => escaped.push_str(&format!("\\x{byte:02x}")),
+        }
+    }
+    escaped
+}
+
+/// Returns file names and contents of the units for mounting a profile.
+///
+/// The service runs the filesystem in the foreground and suits mounts that should come up at
+/// boot. The mount and automount units go through the mount.webdavfs helper and mount on
+/// first access instead. Both read the password from an encrypted systemd credential
+pub fn units(args: &SystemdArgs, executable: &Path) -> Vec<(String, String)> {
+    let profile = &args.profile;
+    let mountpoint = args.mountpoint.display();
+    let unit_name = escape_path(&args.mountpoint);
+    let credential = format!("{CREDSTORE}/rust_webdav-{profile}.password");
+
+    let service = format!(
+        "\
+[Unit]
+Description=WebDAV mount of profile {profile} at {mountpoint}
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+WatchdogSec=60
+LoadCredentialEncrypted=password:{credential}
+ExecStart={exe} webdavfs {mountpoint} -o profile={profile},password_file=${{CREDENTIALS_DIRECTORY}}/password
+ExecStop=/bin/fusermount -u {mountpoint}
+Restart=on-failure
+
+[Install]
+WantedBy=multi-user.target
+",
+        exe = executable.display()
+    );
+
+    let mount = format!(
+        "\
+[Unit]
+Description=WebDAV mount of profile {profile}
+Wants=network-online.target
+After=network-online.target
+
+[Mount]
+What=webdavfs
+Where={mountpoint}
+Type=webdavfs
+Options=profile={profile},password_file=%d/password,_netdev
+LoadCredentialEncrypted=password:{credential}
+"
+    );
+
+    let automount = format!(
+        "\
+[Unit]
+Description=Automount of WebDAV profile {profile}
+
+[Automount]
+Where={mountpoint}
+TimeoutIdleSec=600
+
+[Install]
+WantedBy=remote-fs.target
+"
+    );
+
+    vec![
+        (format!("rust_webdav-{profile}.service"), service),
+        (format!("{unit_name}.mount"), mount),
+        (format!("{unit_name}.automount"), automount),
+    ]
+}
+
+/// Prints or writes the units and explains how to set up the credential they expect
+pub fn run(args: &SystemdArgs) -> Result<(), Errors> {
+    let executable = std::env::current_exe()
+        .map_err(|e| Errors::InvalidArguments(format!("cannot locate executable: {e}")))?;
+
+    for (name, content) in units(args, &executable) {
+        match &args.output_dir {
+            Some(dir) => {
+                let file = dir.join(&name);
+                std::fs::write(&file, content).map_err(|e| {
+                    Errors::InvalidArguments(format!("cannot write {}: {}", file.display(), e))
+                })?;
+                eprintln!("wrote {}", file.display());
+            }
+            None => println!("# {name}\n{content}"),
+        }
+    }
+    eprintln!(
+        "Store the password with\n    \
+         systemd-creds encrypt --name=password - {CREDSTORE}/rust_webdav-{}.password\n\
+         and enable either the .service or the .automount unit",
+        args.profile
+    );
+    Ok(())
+}
+
@@ -0,0 +1,193 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::Duration,
+};
+
+use fuser::Notifier;
+
+use crate::{
+    errors::Errors,
+    prop::{Prop, ResourceType},
+    remotes::Remotes,
+    webdav::PropfindDepth,
+};
+
+/// How often a watched remote is polled if nothing else is configured
+pub const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A change below a watched path, found by comparing two listings. Paths are relative to the
+/// mountpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeEvent {
+    Created(String),
+    /// The etag of a file changed. Directories change with everything below them and are only
+    /// reported when created or removed
+    Modified(String),
+    Removed(String),
+}
+
+impl ChangeEvent {
+    pub fn path(&self) -> &str {
+        match self {
+            ChangeEvent::Created(path) => path,
+            ChangeEvent::Modified(path) => path,
+            ChangeEvent::Removed(path) => path,
+        }
+    }
+}
+
+/// What a listing reported for a path: its etag and whether it is a directory
+type Listing = BTreeMap<String, (String, bool)>;
+
+/// Starts a thread that polls `path` every `interval` and sends every change below it to the
+/// returned receiver. The whole subtree is listed again only if the sync tokens of the remotes
+/// changed, servers without sync tokens are listed on every poll. The thread ends once the
+/// receiver is dropped
+pub fn spawn_watcher(remotes: Remotes, path: String, interval: Duration) -> Receiver<ChangeEvent> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut tokens = None;
+        let mut known = None;
+        loop {
+            match poll(&remotes, &path, &mut tokens, &mut known) {
+                Ok(events) => {
+                    for event in events {
+                        if sender.send(event).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(err) => warn!("Could not poll {} for changes: {:?}", path, err),
+            }
+            thread::sleep(interval);
+        }
+    });
+    receiver
+}
+
+/// Starts a thread that passes the `events` of a watcher on to the filesystem and then tells
+/// the kernel to drop what it cached about the changed entries. The kernel asks the filesystem
+/// again, which takes in the changes first. The thread ends once the filesystem is gone
+pub fn spawn_invalidator(
+    events: Receiver<ChangeEvent>,
+    filesystem: Sender<ChangeEvent>,
+    notifier: Notifier,
+    mountpoint: PathBuf,
+) {
+    thread::spawn(move || {
+        for event in events {
+            if filesystem.send(event.clone()).is_err() {
+                return;
+            }
+            match invalidate(&notifier, &mountpoint, &event) {
+                Ok(()) => debug!("Invalidated {:?}", event),
+                // Nothing was cached about entries the kernel never saw
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => warn!("Could not invalidate {:?}: {}", event, err),
+            }
+        }
+    });
+}
+
+/// Drops the entry of a changed path and the attributes of its directory from the caches of the
+/// kernel, and the content of modified files as well. Inode numbers are looked up through the
+/// mountpoint, which the kernel answers from its cache if it has the entry
+fn invalidate(notifier: &Notifier, mountpoint: &Path, event: &ChangeEvent) -> io::Result<()> {
+    let relative = event.path().trim_start_matches('/');
+    // The root itself is never created or removed, and its entry is not cached
+    if relative.is_empty() {
+        return Ok(());
+    }
+    let path = mountpoint.join(relative);
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Ok(());
+    };
+    if let ChangeEvent::Modified(_) = event {
+        notifier.inval_inode(std::fs::symlink_metadata(&path)?.ino(), 0, 0)?;
+    }
+    let parent = std::fs::symlink_metadata(parent)?.ino();
+    notifier.inval_entry(parent, name)?;
+    notifier.inval_inode(parent, 0, 0)
+}
+
+/// Lists `path` if the remotes changed since `tokens` were taken and returns the differences
+/// to the `known` listing. The first poll only takes the listing
+fn poll(
+    remotes: &Remotes,
+    path: &str,
+    tokens: &mut Option<Vec<Option<String>>>,
+    known: &mut Option<Listing>,
+) -> Result<Vec<ChangeEvent>, Errors> {
+    let current_tokens = remotes.sync_tokens()?;
+    let unchanged = tokens.as_ref() == Some(&current_tokens);
+    if unchanged && current_tokens.iter().all(Option::is_some) {
+        return Ok(vec![]);
+    }
+    let listing = to_listing(remotes.list(path, PropfindDepth::Recursive)?);
+    let events = match known {
+        Some(known) => diff(known, &listing),
+        None => vec![],
+    };
+    *tokens = Some(current_tokens);
+    *known = Some(listing);
+    Ok(events)
+}
+
+fn to_listing(props: Vec<Prop>) -> Listing {
+    props
+        .into_iter()
+        .map(|prop| {
+            let is_directory = prop.resource_type() == ResourceType::Collection;
+            let path = prop.path().to_string_lossy().into_owned();
+            (path, (prop.etag().to_string(), is_directory))
+        })
+        .collect()
+}
+
+/// Changes between two listings, in the order of their paths
+fn diff(old: &Listing, new: &Listing) -> Vec<ChangeEvent> {
+    let mut events = vec![];
+    for (path, (etag, is_directory)) in new {
+        match old.get(path) {
+            None => events.push(ChangeEvent::Created(path.clone())),
+            Some((old_etag, _)) if old_etag != etag && !is_directory => {
+                events.push(ChangeEvent::Modified(path.clone()))
+            }
+            Some(_) => {}
+        }
+    }
+    let removed = old.keys().filter(|path| !new.contains_key(*path));
+    events.extend(removed.map(|path| ChangeEvent::Removed(path.clone())));
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(entries: &[(&str, &str, bool)]) -> Listing {
+        entries
+            .iter()
+            .map(|(path, etag, dir)| (path.to_string(), (etag.to_string(), *dir)))
+            .collect()
+    }
+
+    #[test]
+    fn diff_reports_created_modified_and_removed_entries() {
+        let old = listing(&[("/", "1", true), ("/a.txt", "1", false), ("/b.txt", "1", false)]);
+        let new = listing(&[("/", "2", true), ("/a.txt", "2", false), ("/c", "1", true)]);
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                ChangeEvent::Modified("/a.txt".into()),
+                ChangeEvent::Created("/c".into()),
+                ChangeEvent::Removed("/b.txt".into()),
+            ]
+        );
+    }
+}
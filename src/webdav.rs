@@ -1,7 +1,87 @@
+use crate::backend::Backend;
 use crate::errors::Errors;
+use crate::inflight;
+use crate::metrics::{self, Counter};
+use crate::middleware::{
+    self, Middleware, Next, Outcome, RequestInfo, MAX_RECONNECTS, RECONNECT_BACKOFF,
+};
+use crate::path;
 use crate::prop::*;
 use chrono::prelude::*;
+use reqwest::{
+    blocking::{RequestBuilder, Response},
+    header::{
+        HeaderName, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RANGE,
+        USER_AGENT,
+    },
+    Method,
+};
 use rustydav::client;
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    path::Path,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Suffix of the temporary names atomic uploads use
+pub const PART_SUFFIX: &str = ".part";
+/// Temporary uploads older than this are left over from interrupted uploads
+pub const STALE_PART_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+/// Listings kept for revalidation, the cache starts over once it is full
+const MAX_CACHED_LISTINGS: usize = 4096;
+/// How long reads stay on the mirror before the primary server is tried again
+const FAILBACK_INTERVAL: Duration = Duration::from_secs(60);
+/// How often a request sent for a FUSE operation checks whether the operation was interrupted
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Minimum time between two summaries of unhandled tags
+const UNHANDLED_TAGS_INTERVAL: Duration = Duration::from_secs(300);
+/// Longest sample of an unhandled tag that is kept for the summary
+const UNHANDLED_TAG_SAMPLE_LEN: usize = 80;
+
+/// User-Agent sent unless a profile configures another one. Some Nextcloud instances apply
+/// policies per client and need to tell this one apart from others
+pub const DEFAULT_USER_AGENT: &str = concat!("rust_webdav/", env!("CARGO_PKG_VERSION"));
+
+/// Body of PROPFIND requests, asking for every live and dead property
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8" ?>
+<D:propfind xmlns:D="DAV:" xmlns:oc="http://owncloud.org/ns">
+    <D:allprop/>
+    <D:include><D:quota-available-bytes/><oc:permissions/></D:include>
+</D:propfind>"#;
+
+const SYNC_TOKEN_BODY: &str = r#"<?xml version="1.0" encoding="utf-8" ?>
+<D:propfind xmlns:D="DAV:">
+    <D:prop><D:sync-token/></D:prop>
+</D:propfind>"#;
+
+const QUOTA_BODY: &str = r#"<?xml version="1.0" encoding="utf-8" ?>
+<D:propfind xmlns:D="DAV:">
+    <D:prop><D:quota-available-bytes/><D:quota-used-bytes/></D:prop>
+</D:propfind>"#;
+
+/// Body of PROPFIND requests listing the versions Nextcloud keeps of a file
+const VERSIONS_BODY: &str = r#"<?xml version="1.0" encoding="utf-8" ?>
+<D:propfind xmlns:D="DAV:">
+    <D:prop><D:getlastmodified/><D:getcontentlength/><D:resourcetype/></D:prop>
+</D:propfind>"#;
+
+/// Exception Sabre reports when an upload does not fit into the quota or the storage left
+const INSUFFICIENT_STORAGE_EXCEPTION: &str = "Sabre\\DAV\\Exception\\InsufficientStorage";
+
+/// XML namespace of all properties defined by RFC 4918
+const DAV_NAMESPACE: &str = "DAV:";
+/// XML namespace of the properties this program sets itself
+const RUST_WEBDAV_NAMESPACE: &str = "https://github.com/codingHahn/rust_webdav";
+/// Dead property holding the target of an emulated symlink
+pub const SYMLINK_PROPERTY: &str = "symlink";
+/// XML namespaces Nextcloud and ownCloud use for their own properties
+const NEXTCLOUD_NAMESPACES: [&str; 2] = ["http://owncloud.org/ns", "http://nextcloud.org/ns"];
 
 /// PROPFIND supports three different depths:
 ///     - ELEMENT_ONLY, which corresponds to "0" and returns information about
@@ -27,30 +107,317 @@ impl From<PropfindDepth> for &str {
     }
 }
 
+/// Storage of a drive as reported with the properties of RFC 4331
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Quota {
+    pub used: u64,
+    pub available: u64,
+}
+
+/// An earlier version of a file, as kept by the versions app of Nextcloud
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    /// Modification time of the file when this version was current
+    pub mtime: u64,
+    pub size: u64,
+    /// Where the content of the version is downloaded from
+    pub url: String,
+}
+
 /// Information about the drive
 pub struct WebdavDrive {
-    /// Prefix of the URL to prepend on request
+    /// Prefix of the URL to prepend on request. Points to the remote root, without trailing slash
     prefix: String,
-    client: client::Client,
+    /// Decoded path component of `prefix` without trailing slash. Hrefs returned by the server
+    /// start with it
+    root_path: String,
+    client: Mutex<client::Client>,
+    /// Creates a new client, used to replace one whose connections died
+    connect: Box<dyn Fn() -> client::Client + Send>,
+    user_agent: String,
+    /// Prefix of a server with the same content, used for reads while this one is unreachable
+    mirror: Option<String>,
+    /// Since when reads go to the mirror
+    failed_over: Mutex<Option<Instant>>,
+    /// Upload to a temporary name and move into place once complete
+    atomic_uploads: bool,
+    /// Listings by depth and URL, revalidated with the validator the server sent along
+    listings: Mutex<BTreeMap<String, CachedListing>>,
+    /// Properties of listings the parser does not know, summarized in the log now and then
+    unhandled_tags: Mutex<UnhandledTags>,
+    /// Run around every request, see `Middleware`
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+/// Unknown tags seen since the last summary, by name with their count and the first sample
+#[derive(Default)]
+struct UnhandledTags {
+    tags: BTreeMap<String, (u64, String)>,
+    logged_at: Option<Instant>,
+}
+
+impl UnhandledTags {
+    fn record(&mut self, name: &str, href: &str, text: &str) {
+        let (count, _) = self.tags.entry(name.to_string()).or_insert_with(|| {
+            let text: String = text.chars().take(UNHANDLED_TAG_SAMPLE_LEN).collect();
+            (0, format!("{href}: {text:?}"))
+        });
+        *count += 1;
+    }
+
+    /// Logs and clears the tags seen so far, unless the last summary is too recent
+    fn log_summary(&mut self) {
+        let due = self
+            .logged_at
+            .map_or(true, |at| at.elapsed() >= UNHANDLED_TAGS_INTERVAL);
+        if self.tags.is_empty() || !due {
+            return;
+        }
+        for (name, (count, sample)) in std::mem::take(&mut self.tags) {
+            debug!("Unhandled tag {} seen {} times, first at {}", name, count, sample);
+        }
+        self.logged_at = Some(Instant::now());
+    }
+}
+
+/// A listing the server can confirm as unchanged with 304 Not Modified
+struct CachedListing {
+    /// Conditional header and the value that asks whether the listing changed
+    condition: (HeaderName, String),
+    props: Vec<Prop>,
 }
 
 impl WebdavDrive {
-    pub fn new(prefix: String, client: client::Client) -> Self {
-        Self { prefix, client }
+    /// Creates a drive exposing `remote_root`, a path like "/Photos/2024" below the WebDAV URL
+    /// `prefix`, as its root
+    pub fn new(
+        prefix: &str,
+        remote_root: &str,
+        connect: impl Fn() -> client::Client + Send + 'static,
+    ) -> Self {
+        let remote_root = remote_root.trim_end_matches('/');
+        let prefix = prefix.trim_end_matches('/').to_string() + &path::encode(remote_root);
+        let root_path = path::decode(path::url_path(&prefix))
+            .unwrap_or_default()
+            .trim_end_matches('/')
+            .to_string();
+        Self {
+            prefix,
+            root_path,
+            client: Mutex::new(connect()),
+            connect: Box::new(connect),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            mirror: None,
+            failed_over: Mutex::new(None),
+            atomic_uploads: false,
+            listings: Mutex::new(BTreeMap::new()),
+            unhandled_tags: Mutex::new(UnhandledTags::default()),
+            middlewares: vec![
+                Box::new(middleware::Logging),
+                Box::new(middleware::Retry::default()),
+            ],
+        }
+    }
+
+    /// Adds `middleware` after the ones already present, so it sees requests last and
+    /// responses first
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// Uploads to a temporary name first, see `put`
+    pub fn atomic_uploads(mut self) -> Self {
+        self.atomic_uploads = true;
+        self
+    }
+
+    /// Reads from the server at `mirror` while this one is unreachable. The mirror has to serve
+    /// the same tree, so `remote_root` applies to it as well
+    pub fn mirror(mut self, mirror: &str, remote_root: &str) -> Self {
+        let remote_root = remote_root.trim_end_matches('/');
+        self.mirror = Some(mirror.trim_end_matches('/').to_string() + &path::encode(remote_root));
+        self
+    }
+
+    /// Identifies the requests with `user_agent` instead of `DEFAULT_USER_AGENT`
+    pub fn user_agent(mut self, user_agent: String) -> Self {
+        self.user_agent = user_agent;
+        self
+    }
+
+    /// Sends a request with the headers every request carries. `build` adds the rest.
+    ///
+    /// Reads go to the mirror, if there is one, while the primary server is unreachable. The
+    /// primary is tried again after `FAILBACK_INTERVAL`
+    fn send(
+        &self,
+        method: Method,
+        url: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<Response, Errors> {
+        let mirror_url = match &self.mirror {
+            Some(mirror) if method == Method::GET || method == propfind() => {
+                Some(url.replacen(&self.prefix, mirror, 1))
+            }
+            _ => None,
+        };
+        let Some(mirror_url) = mirror_url else {
+            return self.send_to(method, url, build);
+        };
+
+        let mut failed_over = self.failed_over.lock().unwrap_or_else(|e| e.into_inner());
+        if failed_over.map_or(false, |since| since.elapsed() < FAILBACK_INTERVAL) {
+            return self.send_to(method, &mirror_url, build);
+        }
+        match self.send_to(method.clone(), url, &build) {
+            Err(Errors::ServerUnreachable(reason)) => {
+                warn!("{} is unreachable ({}), reading from the mirror", self.prefix, reason);
+                *failed_over = Some(Instant::now());
+                self.send_to(method, &mirror_url, build)
+            }
+            result => {
+                if failed_over.take().is_some() {
+                    info!("{} is reachable again, leaving the mirror", self.prefix);
+                }
+                result
+            }
+        }
+    }
+
+    /// Sends a request to `url` through the middlewares. Retries they ask for after a transport
+    /// error replace the client first, its pooled connections are likely dead
+    fn send_to(
+        &self,
+        method: Method,
+        url: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<Response, Errors> {
+        inflight::set_request(method.as_str(), url);
+        let mut client = self.client.lock().map_err(|_| Errors::WebDavReqeustFailed)?;
+        let mut attempt = 0;
+        loop {
+            let info = RequestInfo {
+                method: &method,
+                url,
+                attempt,
+            };
+            let request = client
+                .start_request(method.clone(), url)
+                .header(USER_AGENT, &self.user_agent);
+            let request = self
+                .middlewares
+                .iter()
+                .fold(build(request), |request, m| m.on_request(&info, request));
+            metrics::add(Counter::Requests, 1);
+            let result = send_interruptible(request).map_err(|err| {
+                info!("Abandoned {} {}, the process waiting for it is gone", method, url);
+                err
+            })?;
+            let outcome = match &result {
+                Ok(response) => Outcome::Response(response),
+                Err(err) => Outcome::TransportError(err),
+            };
+            let next = self
+                .middlewares
+                .iter()
+                .rev()
+                .map(|m| m.on_response(&info, &outcome))
+                .fold(Next::Continue, |next, other| match next {
+                    Next::Continue => other,
+                    retry => retry,
+                });
+            match (result, next) {
+                (result, Next::Retry { after }) => {
+                    attempt += 1;
+                    metrics::add(Counter::Retries, 1);
+                    thread::sleep(after);
+                    if result.is_err() {
+                        *client = (self.connect)();
+                    }
+                }
+                (Ok(response), Next::Continue) => {
+                    return check_status(response).map_err(|err| {
+                        metrics::add(Counter::RequestErrors, 1);
+                        err
+                    })
+                }
+                (Err(err), Next::Continue) => {
+                    metrics::add(Counter::RequestErrors, 1);
+                    error!("Request failed with {:?}, giving up", err);
+                    return Err(Errors::ServerUnreachable(format!("{:?}", err)));
+                }
+            }
+        }
+    }
+
+    /// Turns a href of the server into a filesystem-absolute path relative to the drive. Hrefs
+    /// outside of the root of the drive are an error
+    fn relative_path(&self, href: &str) -> Result<String, Errors> {
+        let href = path::decode(path::url_path(href))?;
+        match path::strip_root(&href, &self.root_path) {
+            Some("") => Ok("/".into()),
+            Some(relative) => Ok(relative.into()),
+            None => Err(Errors::HrefOutsideRoot(href)),
+        }
+    }
+
+    /// Builds the request URL for a filesystem-absolute path like "/Meine Bilder/a.txt"
+    fn url(&self, path: &str) -> String {
+        self.prefix.clone() + &path::encode(path)
     }
     /// Executes a "PROPFIND" request against `path` with depth as specified in `PropfindDepth`
     pub fn list(&self, path: &str, depth: PropfindDepth) -> Result<Vec<Prop>, Errors> {
         let mut ret: Vec<Prop> = vec![];
 
-        let http_response = self
-            .client
-            .list(&(self.prefix.clone() + path), depth.into())
-            .map_err(|_| Errors::WebDavReqeustFailed)?;
-        let resp_text = http_response
-            .text()
-            .map_err(|_| Errors::WebDavReqeustFailed)?;
+        let url = self.url(path);
+        let key = format!("{} {}", <&str>::from(depth), url);
+        let condition = self
+            .listings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&key)
+            .map(|cached| cached.condition.clone());
+        let request = |condition: &Option<(HeaderName, String)>| {
+            self.send(propfind(), &url, |request| {
+                let request = request
+                    .header("Depth", <&str>::from(depth))
+                    .header(CONTENT_TYPE, "application/xml")
+                    .body(PROPFIND_BODY);
+                match condition {
+                    Some((name, value)) => request.header(name, value),
+                    None => request,
+                }
+            })
+        };
+        let mut response = request(&condition)?;
+        if response.status().as_u16() == 304 {
+            let cached = self
+                .listings
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .get(&key)
+                .map(|cached| cached.props.clone());
+            match cached {
+                Some(props) => return Ok(props),
+                // The cache started over since the request was sent, so there is no body to
+                // parse. Ask for the whole listing again
+                None => response = request(&None)?,
+            }
+        }
+        let header = |name: HeaderName| {
+            let value = response.headers().get(&name)?.to_str().ok()?.to_string();
+            Some((name, value))
+        };
+        let condition = match header(ETAG) {
+            Some((_, etag)) => Some((IF_NONE_MATCH, etag)),
+            None => header(LAST_MODIFIED).map(|(_, date)| (IF_MODIFIED_SINCE, date)),
+        };
+        let resp_text = response.text().map_err(|_| Errors::WebDavReqeustFailed)?;
+        metrics::add(Counter::BytesReceived, resp_text.len() as u64);
         let parser =
             roxmltree::Document::parse(&resp_text).map_err(Errors::XMLDocumentParseError)?;
+        let mut unhandled_tags = self.unhandled_tags.lock().unwrap_or_else(|e| e.into_inner());
 
         // Gets all nodes with "response" tag. One prop per response
         let responses = parser.descendants().filter(|n| n.has_tag_name("response"));
@@ -62,7 +429,7 @@ impl WebdavDrive {
                 .find(|n| n.has_tag_name("prop"))
                 .ok_or_else(|| Errors::XMLTagEmptyWhenItShouldNot("prop".into()))?;
 
-            // the href, which contains the path (I think?) is one level above the prop
+            // the href, which contains the path of the prop, is one level above the prop
             let href = response
                 .descendants()
                 .find(|n| n.has_tag_name("href"))
@@ -73,11 +440,13 @@ impl WebdavDrive {
             //println!("{:#?}", props);
             //println!("{:?}", props.descendants().count());
 
-            let mut propb = PropBuilder::new().path(href.into());
+            let mut propb = PropBuilder::new().path(self.relative_path(href)?.into());
 
             // Iterate over all elements of the prop node. This extracts important file metadata
             // such as the etag, last-modified-time, resource_type and the size
             for el in props.children() {
+                let namespace = el.tag_name().namespace();
+                let text = el.text().unwrap_or_default();
                 // Handle the current tag accordingly
                 match el.tag_name().name() {
                     "getlastmodified" => {
@@ -108,6 +477,12 @@ impl WebdavDrive {
                                 .map_err(|_| Errors::PropSizeError)?,
                         )
                     }
+                    // Negative values mean unknown or unlimited on Nextcloud and ownCloud
+                    "quota-available-bytes" => {
+                        if let Ok(available) = text.parse::<u64>() {
+                            propb = propb.quota_available(available)
+                        }
+                    }
                     "getetag" => {
                         propb = propb.etag(
                             el.text()
@@ -118,11 +493,687 @@ impl WebdavDrive {
                                 .to_string(),
                         )
                     }
-                    unknown_tag => println!("unhandled tag name found in xml: {unknown_tag}"),
+                    name if namespace.map_or(false, |ns| NEXTCLOUD_NAMESPACES.contains(&ns)) => {
+                        propb = propb.nextcloud_prop(name.into(), text.into())
+                    }
+                    // Everything outside of the DAV: namespace was set by a client
+                    name if el.is_element() && namespace != Some(DAV_NAMESPACE) => {
+                        propb = propb.dead_prop(name.into(), text.into())
+                    }
+                    unknown_tag => unhandled_tags.record(unknown_tag, href, text),
                 }
             }
             ret.push(propb.build())
         }
+        unhandled_tags.log_summary();
+        drop(unhandled_tags);
+        // Sabre, and with it Nextcloud, sends neither header with a multistatus. The etag of
+        // the listed collection itself changes along with its members there
+        let condition = condition.or_else(|| {
+            let own = ret.iter().find(|p| p.path() == Path::new(path))?;
+            (!own.etag().is_empty()).then(|| (IF_NONE_MATCH, quote_etag(own.etag())))
+        });
+        if let Some(condition) = condition {
+            let mut listings = self.listings.lock().unwrap_or_else(|e| e.into_inner());
+            if listings.len() >= MAX_CACHED_LISTINGS {
+                listings.clear();
+            }
+            let props = ret.clone();
+            listings.insert(key, CachedListing { condition, props });
+        }
         Ok(ret)
     }
+
+    /// Like `list`, but grouped by collection, so a recursive listing can fill a whole tree
+    pub fn list_tree(&self, path: &str, depth: PropfindDepth) -> Result<PropTree, Errors> {
+        self.list(path, depth).map(group_by_parent)
+    }
+
+    /// Downloads `size` bytes of the file at `path`, starting at `offset`. Fewer bytes are
+    /// returned at the end of the file
+    pub fn get_range(&self, path: &str, offset: u64, size: u64) -> Result<Vec<u8>, Errors> {
+        self.get_url_range(&self.url(path), offset, size)
+    }
+
+    /// Like `get_range`, for a file outside of the drive like an earlier version
+    fn get_url_range(&self, url: &str, offset: u64, size: u64) -> Result<Vec<u8>, Errors> {
+        if size == 0 {
+            return Ok(vec![]);
+        }
+        let mut data = Vec::with_capacity(size as usize);
+        let mut etag = None;
+        let mut attempt = 0;
+        // Proxies cut long downloads off. The rest is asked for with a Range request of its own,
+        // as long as the file keeps its etag
+        loop {
+            let start = offset + data.len() as u64;
+            let range = format!("bytes={}-{}", start, offset + size - 1);
+            let response = self.send(Method::GET, url, |request| {
+                request.header(RANGE, &range)
+            })?;
+            let response_etag = response.headers().get(ETAG).cloned();
+            if attempt > 0 && response_etag != etag {
+                warn!("{} changed while it was read, not resuming", url);
+                return Err(Errors::WebDavReqeustFailed);
+            }
+            etag = response_etag;
+            match read_body(response, start, offset + size - start, &mut data) {
+                Ok(()) => return Ok(data),
+                Err(err) if attempt < MAX_RECONNECTS && !inflight::cancelled() => {
+                    attempt += 1;
+                    metrics::add(Counter::Retries, 1);
+                    warn!(
+                        "GET {} was cut off at byte {} ({}), resuming ({}/{})",
+                        url,
+                        offset + data.len() as u64,
+                        err,
+                        attempt,
+                        MAX_RECONNECTS
+                    );
+                    thread::sleep(RECONNECT_BACKOFF * attempt);
+                }
+                Err(_) => return Err(Errors::WebDavReqeustFailed),
+            }
+        }
+    }
+
+    /// Uploads `data` as the content of the file at `path`. The file keeps the modification
+    /// time `mtime`, if given, instead of getting the time of the upload
+    ///
+    /// With atomic uploads the data goes to a temporary name first and is moved into place
+    /// once complete, so other clients never see a half uploaded file
+    pub fn put(&self, path: &str, data: Vec<u8>, mtime: Option<u64>) -> Result<(), Errors> {
+        let upload_path = match self.atomic_uploads {
+            true => part_path(path),
+            false => path.to_string(),
+        };
+        let response = self.send(Method::PUT, &self.url(&upload_path), |request| match mtime {
+            // Nextcloud and ownCloud take the time from this header
+            Some(mtime) => request.header("X-OC-MTime", mtime).body(data.clone()),
+            None => request.body(data.clone()),
+        })?;
+        metrics::add(Counter::BytesSent, data.len() as u64);
+        if self.atomic_uploads {
+            self.move_to(&upload_path, path, true)?;
+        }
+        let Some(mtime) = mtime else {
+            return Ok(());
+        };
+        let accepted = response
+            .headers()
+            .get("X-OC-MTime")
+            .map_or(false, |value| value == "accepted");
+        if accepted {
+            return Ok(());
+        }
+        // Other servers may allow setting the live property instead
+        if let Err(err) = self.set_mtime(path, mtime) {
+            debug!("Server did not keep the mtime of {}: {:?}", path, err);
+        }
+        Ok(())
+    }
+
+    /// Sets the modification time of `path` without uploading it again, for servers that allow
+    /// changing the live property
+    pub fn set_mtime(&self, path: &str, mtime: u64) -> Result<(), Errors> {
+        let date = Utc
+            .timestamp_opt(mtime as i64, 0)
+            .single()
+            .ok_or_else(|| Errors::InvalidArguments(format!("mtime {mtime} out of range")))?;
+        let prop = format!("<D:getlastmodified>{}</D:getlastmodified>", date.to_rfc2822());
+        self.proppatch(path, "set", &prop)
+    }
+
+    /// Sends a request that must not be repeated blindly. If the response is lost, the request
+    /// may have been applied anyway. `applied` asks the server whether that is the case before
+    /// the request is sent again, so a retry never moves a file back or deletes it twice
+    fn send_probed(
+        &self,
+        method: Method,
+        url: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+        applied: impl Fn() -> Result<bool, Errors>,
+    ) -> Result<(), Errors> {
+        let mut attempt = 0;
+        loop {
+            match self.send(method.clone(), url, &build) {
+                Err(Errors::ServerUnreachable(_))
+                    if attempt < MAX_RECONNECTS && !inflight::cancelled() =>
+                {
+                    attempt += 1;
+                    thread::sleep(RECONNECT_BACKOFF * attempt);
+                    if applied()? {
+                        info!("{} {} was applied although its response got lost", method, url);
+                        return Ok(());
+                    }
+                    metrics::add(Counter::Retries, 1);
+                    warn!(
+                        "{} {} got no response, retrying ({}/{})",
+                        method, url, attempt, MAX_RECONNECTS
+                    );
+                }
+                result => return result.map(|_| ()),
+            }
+        }
+    }
+
+    /// Whether something exists at `path` on the primary server, a mirror may lag behind
+    fn exists(&self, path: &str) -> Result<bool, Errors> {
+        let response = self.send_to(propfind(), &self.url(path), |request| {
+            request
+                .header("Depth", "0")
+                .header(CONTENT_TYPE, "application/xml")
+                .body(PROPFIND_BODY)
+        });
+        match response {
+            Ok(_) => Ok(true),
+            Err(Errors::HttpStatus(404)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Creates the collection `path`. Its parent has to exist
+    pub fn mkcol(&self, path: &str) -> Result<(), Errors> {
+        self.send(mkcol(), &self.url(path), |request| request)?;
+        Ok(())
+    }
+
+    /// Moves `from` to `to` on the server. Without `overwrite` an existing `to` is kept and the
+    /// server answers 412 Precondition Failed
+    pub fn move_to(&self, from: &str, to: &str, overwrite: bool) -> Result<(), Errors> {
+        let destination = self.url(to);
+        let overwrite = if overwrite { "T" } else { "F" };
+        let build = |request: RequestBuilder| {
+            request
+                .header("Destination", &destination)
+                .header("Overwrite", overwrite)
+        };
+        // Moved once the source is gone and the destination is there
+        let applied = || Ok(!self.exists(from)? && self.exists(to)?);
+        self.send_probed(webdav_move(), &self.url(from), build, applied)
+    }
+
+    /// Copies `from` to `to` on the server, replacing `to` if it exists. Repeating the request
+    /// leads to the same result, so it is retried like any other
+    pub fn copy_to(&self, from: &str, to: &str) -> Result<(), Errors> {
+        let destination = self.url(to);
+        self.send(webdav_copy(), &self.url(from), |request| {
+            request
+                .header("Destination", &destination)
+                .header("Overwrite", "T")
+        })?;
+        Ok(())
+    }
+
+    pub fn delete(&self, path: &str) -> Result<(), Errors> {
+        let applied = || Ok(!self.exists(path)?);
+        self.send_probed(Method::DELETE, &self.url(path), |request| request, applied)
+    }
+
+    /// Sets the property `name` of our own namespace on `path`
+    pub fn set_property(&self, path: &str, name: &str, value: &str) -> Result<(), Errors> {
+        let prop = format!("<R:{name}>{}</R:{name}>", xml_escape(value));
+        self.proppatch(path, "set", &prop)
+    }
+
+    /// Removes the property `name` of our own namespace from `path`
+    pub fn remove_property(&self, path: &str, name: &str) -> Result<(), Errors> {
+        self.proppatch(path, "remove", &format!("<R:{name}/>"))
+    }
+
+    /// Sets or removes, depending on `instruction`, a property given as XML element. Its tag
+    /// uses the prefix `D` for the DAV: and `R` for our own namespace
+    fn proppatch(&self, path: &str, instruction: &str, prop: &str) -> Result<(), Errors> {
+        let body = format!(
+            r#"<?xml version="1.0" encoding="utf-8" ?>
+<D:propertyupdate xmlns:D="DAV:" xmlns:R="{RUST_WEBDAV_NAMESPACE}">
+    <D:{instruction}><D:prop>{prop}</D:prop></D:{instruction}>
+</D:propertyupdate>"#
+        );
+        self.send(proppatch(), &self.url(path), |request| {
+            request
+                .header(CONTENT_TYPE, "application/xml")
+                .body(body.clone())
+        })?;
+        Ok(())
+    }
+
+    /// Finds the DAV root of a server given by its bare host name through RFC 5785 discovery.
+    /// `/.well-known/webdav` redirects to the root, which is the base of all users on
+    /// Nextcloud and ownCloud, so the files of `username` are chosen there
+    pub fn discover_root(&self, username: &str) -> Result<String, Errors> {
+        let url = self.prefix.clone() + "/.well-known/webdav";
+        let response = self.send(propfind(), &url, |request| {
+            request
+                .header("Depth", "0")
+                .header(CONTENT_TYPE, "application/xml")
+                .body(PROPFIND_BODY)
+        })?;
+        let root = response.url().as_str().trim_end_matches('/').to_string();
+        if root.ends_with("/remote.php/dav") {
+            return Ok(format!("{root}/files/{}", path::encode(username)));
+        }
+        Ok(root)
+    }
+
+    /// The RFC 6578 sync token of the collection at `path`, which changes with every change
+    /// below it. `None` if the server does not support sync tokens
+    pub fn sync_token(&self, path: &str) -> Result<Option<String>, Errors> {
+        let response = self.send(propfind(), &self.url(path), |request| {
+            request
+                .header("Depth", "0")
+                .header(CONTENT_TYPE, "application/xml")
+                .body(SYNC_TOKEN_BODY)
+        })?;
+        let text = response.text().map_err(|_| Errors::WebDavReqeustFailed)?;
+        let document = roxmltree::Document::parse(&text).map_err(Errors::XMLDocumentParseError)?;
+        let token = document
+            .descendants()
+            .find(|n| n.has_tag_name((DAV_NAMESPACE, "sync-token")))
+            .and_then(|n| n.text())
+            .map(str::to_string);
+        Ok(token)
+    }
+
+    /// Bytes used and available below `path`. `None` if the server reports no limit, which
+    /// Nextcloud and ownCloud do with a negative available value
+    pub fn quota(&self, path: &str) -> Result<Option<Quota>, Errors> {
+        let response = self.send(propfind(), &self.url(path), |request| {
+            request
+                .header("Depth", "0")
+                .header(CONTENT_TYPE, "application/xml")
+                .body(QUOTA_BODY)
+        })?;
+        let text = response.text().map_err(|_| Errors::WebDavReqeustFailed)?;
+        let document = roxmltree::Document::parse(&text).map_err(Errors::XMLDocumentParseError)?;
+        let value = |name: &str| {
+            let node = document
+                .descendants()
+                .find(|n| n.has_tag_name((DAV_NAMESPACE, name)))?;
+            node.text()?.trim().parse::<u64>().ok()
+        };
+        let quota = value("quota-available-bytes").map(|available| Quota {
+            used: value("quota-used-bytes").unwrap_or_default(),
+            available,
+        });
+        Ok(quota)
+    }
+
+    /// Earlier versions of the file with the Nextcloud `fileid`, oldest first. Empty for servers
+    /// without the versions API, which is only found next to the `remote.php/dav/files/USER`
+    /// URLs of Nextcloud
+    pub fn versions(&self, fileid: &str) -> Result<Vec<Version>, Errors> {
+        let Some((base, rest)) = self.prefix.split_once("/remote.php/dav/files/") else {
+            return Ok(vec![]);
+        };
+        let user = rest.split('/').next().unwrap_or_default();
+        let url = format!("{base}/remote.php/dav/versions/{user}/versions/{fileid}");
+        let response = self.send(propfind(), &url, |request| {
+            request
+                .header("Depth", "1")
+                .header(CONTENT_TYPE, "application/xml")
+                .body(VERSIONS_BODY)
+        });
+        let response = match response {
+            // Files that were never changed have no versions collection
+            Err(Errors::HttpStatus(404)) => return Ok(vec![]),
+            response => response?,
+        };
+        let text = response.text().map_err(|_| Errors::WebDavReqeustFailed)?;
+        let document = roxmltree::Document::parse(&text).map_err(Errors::XMLDocumentParseError)?;
+        let origin = &base[..base.len() - path::url_path(base).trim_end_matches('/').len()];
+        let mut versions = vec![];
+        for response in document.descendants().filter(|n| n.has_tag_name("response")) {
+            let text = |name: &str| {
+                let node = response
+                    .descendants()
+                    .find(|n| n.has_tag_name((DAV_NAMESPACE, name)))?;
+                node.text().map(str::trim)
+            };
+            // The versions collection lists itself as well
+            let is_collection = response
+                .descendants()
+                .any(|n| n.has_tag_name((DAV_NAMESPACE, "collection")));
+            let (Some(href), Some(modified), false) =
+                (text("href"), text("getlastmodified"), is_collection)
+            else {
+                continue;
+            };
+            let mtime = DateTime::parse_from_rfc2822(modified)
+                .map_err(Errors::DateTimeConversionError)?
+                .timestamp();
+            versions.push(Version {
+                mtime: mtime.try_into().unwrap_or_default(),
+                size: text("getcontentlength").and_then(|s| s.parse().ok()).unwrap_or(0),
+                url: origin.to_string() + href,
+            });
+        }
+        versions.sort_by_key(|version| version.mtime);
+        Ok(versions)
+    }
+
+    /// Downloads a part of an earlier version like `get_range`
+    pub fn get_version_range(
+        &self,
+        version: &Version,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, Errors> {
+        self.get_url_range(&version.url, offset, size)
+    }
+
+    /// Emulates a symlink at `path`: an empty file whose `SYMLINK_PROPERTY` holds the target
+    pub fn create_symlink(&self, path: &str, target: &str) -> Result<(), Errors> {
+        self.put(path, vec![], None)?;
+        self.set_property(path, SYMLINK_PROPERTY, target)
+    }
+}
+
+impl Backend for WebdavDrive {
+    fn href(&self, path: &str) -> String {
+        self.url(path)
+    }
+
+    fn list(&self, path: &str, depth: PropfindDepth) -> Result<Vec<Prop>, Errors> {
+        WebdavDrive::list(self, path, depth)
+    }
+
+    fn get_range(&self, path: &str, offset: u64, size: u64) -> Result<Vec<u8>, Errors> {
+        WebdavDrive::get_range(self, path, offset, size)
+    }
+
+    fn sync_token(&self, path: &str) -> Result<Option<String>, Errors> {
+        WebdavDrive::sync_token(self, path)
+    }
+
+    fn versions(&self, fileid: &str) -> Result<Vec<Version>, Errors> {
+        WebdavDrive::versions(self, fileid)
+    }
+
+    fn get_version_range(
+        &self,
+        version: &Version,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, Errors> {
+        WebdavDrive::get_version_range(self, version, offset, size)
+    }
+
+    fn quota(&self, path: &str) -> Result<Option<Quota>, Errors> {
+        WebdavDrive::quota(self, path)
+    }
+
+    fn put(&self, path: &str, data: Vec<u8>, mtime: Option<u64>) -> Result<(), Errors> {
+        WebdavDrive::put(self, path, data, mtime)
+    }
+
+    fn mkcol(&self, path: &str) -> Result<(), Errors> {
+        WebdavDrive::mkcol(self, path)
+    }
+
+    fn delete(&self, path: &str) -> Result<(), Errors> {
+        WebdavDrive::delete(self, path)
+    }
+
+    fn move_to(&self, from: &str, to: &str, overwrite: bool) -> Result<(), Errors> {
+        WebdavDrive::move_to(self, from, to, overwrite)
+    }
+
+    fn copy_to(&self, from: &str, to: &str) -> Result<(), Errors> {
+        WebdavDrive::copy_to(self, from, to)
+    }
+
+    fn set_mtime(&self, path: &str, mtime: u64) -> Result<(), Errors> {
+        WebdavDrive::set_mtime(self, path, mtime)
+    }
+
+    fn set_property(&self, path: &str, name: &str, value: &str) -> Result<(), Errors> {
+        WebdavDrive::set_property(self, path, name, value)
+    }
+
+    fn remove_property(&self, path: &str, name: &str) -> Result<(), Errors> {
+        WebdavDrive::remove_property(self, path, name)
+    }
+
+    fn create_symlink(&self, path: &str, target: &str) -> Result<(), Errors> {
+        WebdavDrive::create_symlink(self, path, target)
+    }
+}
+
+fn propfind() -> Method {
+    Method::from_bytes(b"PROPFIND").expect("valid method")
+}
+
+fn proppatch() -> Method {
+    Method::from_bytes(b"PROPPATCH").expect("valid method")
+}
+
+fn mkcol() -> Method {
+    Method::from_bytes(b"MKCOL").expect("valid method")
+}
+
+pub(crate) fn webdav_move() -> Method {
+    Method::from_bytes(b"MOVE").expect("valid method")
+}
+
+fn webdav_copy() -> Method {
+    Method::from_bytes(b"COPY").expect("valid method")
+}
+
+/// Temporary name of an atomic upload to `path`: hidden, next to the target and ending in
+/// `PART_SUFFIX`
+pub fn part_path(path: &str) -> String {
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+    format!("{dir}/.{name}{PART_SUFFIX}")
+}
+
+/// Whether `name` is the temporary name of an atomic upload
+pub fn is_part_name(name: &str) -> bool {
+    name.len() > PART_SUFFIX.len() + 1 && name.starts_with('.') && name.ends_with(PART_SUFFIX)
+}
+
+/// Appends the body of `response`, which answers a request for `wanted` bytes from `start` on,
+/// to `data` as far as it gets. Fails if the connection drops before the body is complete
+fn read_body(
+    mut response: Response,
+    start: u64,
+    wanted: u64,
+    data: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    // Servers without Range support send the whole file
+    let skip = match response.status().as_u16() {
+        206 => 0,
+        _ => start,
+    };
+    let end = skip + wanted;
+    let mut buffer = vec![0; 64 * 1024];
+    let mut received = 0;
+    while received < end {
+        let read = match response.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read as u64,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        metrics::add(Counter::BytesReceived, read);
+        let from = skip.saturating_sub(received).min(read) as usize;
+        let to = (end - received).min(read) as usize;
+        data.extend_from_slice(&buffer[from..to]);
+        received += read;
+    }
+    // A connection that drops mid-body can look like a short but complete response
+    let expected = response.content_length().map(|expected| expected.min(end));
+    if expected.map_or(false, |expected| received < expected) {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Sends `request`, giving up on it if the FUSE operation it is sent for gets interrupted.
+/// The request is then left to run into its timeout on a thread of its own, but the
+/// operation returns right away
+fn send_interruptible(request: RequestBuilder) -> Result<reqwest::Result<Response>, Errors> {
+    if !inflight::interruptible() {
+        return Ok(request.send());
+    }
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || sender.send(request.send()));
+    loop {
+        match receiver.recv_timeout(INTERRUPT_POLL_INTERVAL) {
+            Ok(result) => return Ok(result),
+            Err(RecvTimeoutError::Timeout) if inflight::interrupted() => {
+                return Err(Errors::Interrupted)
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Err(Errors::WebDavReqeustFailed),
+        }
+    }
+}
+
+/// Turns error statuses into errors. Multi-status responses count as success
+fn check_status(response: Response) -> Result<Response, Errors> {
+    let status = response.status().as_u16();
+    if status < 400 {
+        return Ok(response);
+    }
+    let body = response.text().unwrap_or_default();
+    Err(status_error(status, &body))
+}
+
+/// The error a response with the error `status` and `body` fails with. Only 401 means the
+/// credentials were rejected, 403 forbids this one request and callers may go on with others,
+/// such as deleting a tree entry by entry. Nextcloud refuses uploads beyond the quota with
+/// Sabre's InsufficientStorage exception, which not every upload path answers with 507
+pub fn status_error(status: u16, body: &str) -> Errors {
+    if status == 401 {
+        return Errors::AuthenticationFailed;
+    }
+    match sabre_exception(body) {
+        Some((exception, message)) if exception == INSUFFICIENT_STORAGE_EXCEPTION => {
+            Errors::InsufficientStorage(message)
+        }
+        _ if status == 507 => Errors::InsufficientStorage(String::new()),
+        _ => Errors::HttpStatus(status),
+    }
+}
+
+/// Name and message of the exception Sabre describes in the body of an error response
+fn sabre_exception(body: &str) -> Option<(String, String)> {
+    let document = roxmltree::Document::parse(body).ok()?;
+    let text = |tag: &str| {
+        let node = document.descendants().find(|n| n.has_tag_name(tag))?;
+        node.text().map(str::to_string)
+    };
+    Some((text("exception")?, text("message").unwrap_or_default()))
+}
+
+/// Turns an etag the way props keep it, without quotes, back into an entity tag for headers
+fn quote_etag(etag: &str) -> String {
+    match etag.strip_prefix("W/") {
+        Some(weak) => format!("W/\"{}\"", weak),
+        None => format!("\"{}\"", etag),
+    }
+}
+
+/// Escapes text for use inside of an XML element
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, net::TcpListener, sync::Arc};
+
+    const LISTING: &str = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:">
+    <d:response>
+        <d:href>/</d:href>
+        <d:propstat><d:prop>
+            <d:getetag>"dir-1"</d:getetag><d:resourcetype><d:collection/></d:resourcetype>
+        </d:prop></d:propstat>
+    </d:response>
+    <d:response>
+        <d:href>/a.txt</d:href>
+        <d:propstat><d:prop>
+            <d:getetag>"file-1"</d:getetag><d:resourcetype/>
+        </d:prop></d:propstat>
+    </d:response>
+</d:multistatus>"#;
+
+    /// Answers one connection after the other with `responses`, given as status line and
+    /// headers, and keeps the requests it got
+    fn serve(responses: Vec<(&'static str, &'static str)>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+        thread::spawn(move || {
+            for (head, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = vec![];
+                let mut buffer = [0; 4096];
+                // Requests end with the propfind body
+                while !String::from_utf8_lossy(&request).ends_with("</D:propfind>") {
+                    let read = stream.read(&mut buffer).unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                received.lock().unwrap().push(request);
+                let response = format!(
+                    "{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    head,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn revalidate(first_head: &'static str, validator: &str) {
+        let responses = vec![(first_head, LISTING), ("HTTP/1.1 304 Not Modified", "")];
+        let (url, requests) = serve(responses);
+        let drive = WebdavDrive::new(&url, "", || client::Client::init("", ""));
+        let first = drive.list("/", PropfindDepth::WithChildren).unwrap();
+        let second = drive.list("/", PropfindDepth::WithChildren).unwrap();
+        assert_eq!(second.len(), first.len());
+        let requests = requests.lock().unwrap();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains(&format!("if-none-match: {}", validator)));
+    }
+
+    #[test]
+    fn listings_revalidate_with_the_etag_header() {
+        let head = "HTTP/1.1 207 Multi-Status\r\nETag: \"header-1\"";
+        revalidate(head, "\"header-1\"");
+    }
+
+    #[test]
+    fn listings_revalidate_with_the_etag_of_the_collection() {
+        revalidate("HTTP/1.1 207 Multi-Status", "\"dir-1\"");
+    }
+
+    #[test]
+    fn storage_refusals_are_recognized_by_status_and_exception() {
+        let body = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:error xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns">
+  <s:exception>Sabre\DAV\Exception\InsufficientStorage</s:exception>
+  <s:message>Insufficient space in /files, 10 required, 3 available</s:message>
+</d:error>"#;
+        let refused = status_error(500, body);
+        assert!(matches!(refused, Errors::InsufficientStorage(m) if m.contains("10 required")));
+        assert!(matches!(status_error(507, ""), Errors::InsufficientStorage(_)));
+        assert!(matches!(status_error(403, ""), Errors::HttpStatus(403)));
+        assert!(matches!(status_error(401, ""), Errors::AuthenticationFailed));
+    }
+
+    #[test]
+    fn weak_etags_keep_their_prefix() {
+        assert_eq!(quote_etag("W/abc"), "W/\"abc\"");
+        assert_eq!(quote_etag("abc"), "\"abc\"");
+    }
 }
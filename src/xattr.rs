@@ -0,0 +1,224 @@
+use std::ffi::OsStr;
+
+use fuser::FileType;
+
+use crate::{errors::Errors, filesystem::File, webdav::SYMLINK_PROPERTY};
+
+/// Prefix of the attributes stored as dead properties on the server
+pub const DEAD_PROP_PREFIX: &str = "user.dav.";
+
+/// Etags seen for a file as JSON array. Answered by the filesystem from its state store
+pub const HISTORY_XATTR: &str = "user.webdavfs.history";
+
+/// A group of extended attributes that share a common name prefix.
+/// Names passed to and returned from the methods never contain the prefix
+pub trait XattrNamespace: Send {
+    /// Prefix of all attribute names handled by this namespace, e.g. "user.webdav."
+    fn prefix(&self) -> &'static str;
+
+    /// Names of all attributes of this namespace that are present on `file`
+    fn list(&self, file: &File) -> Vec<String>;
+
+    /// Value of the attribute `name` of `file`, which the server knows as `href`
+    fn get(&self, file: &File, href: &str, name: &str) -> Option<Vec<u8>>;
+
+    fn set(&self, _file: &mut File, name: &str, _value: &[u8]) -> Result<(), Errors> {
+        Err(Errors::XattrReadOnly((self.prefix().to_string() + name).into()))
+    }
+
+    fn remove(&self, _file: &mut File, name: &str) -> Result<(), Errors> {
+        Err(Errors::XattrReadOnly((self.prefix().to_string() + name).into()))
+    }
+}
+
+/// Internal state of the filesystem, such as the sync state of a file
+pub struct WebdavfsNamespace;
+
+impl XattrNamespace for WebdavfsNamespace {
+    fn prefix(&self) -> &'static str {
+        "user.webdavfs."
+    }
+
+    fn list(&self, file: &File) -> Vec<String> {
+        let mut names = vec!["state".into()];
+        if file.conflict_of().is_some() {
+            names.push("conflict_of".into());
+        }
+        if !matches!(file.attributes().fuser_filetype(), FileType::Directory) {
+            names.push("history".into());
+        }
+        names
+    }
+
+    fn get(&self, file: &File, _href: &str, name: &str) -> Option<Vec<u8>> {
+        match name {
+            "state" => Some(format!("{:?}", file.attributes().state()).into_bytes()),
+            "conflict_of" => file.conflict_of().map(|path| path.as_bytes().to_vec()),
+            _ => None,
+        }
+    }
+}
+
+/// Metadata the WebDAV server reports about a file
+pub struct WebdavNamespace;
+
+impl XattrNamespace for WebdavNamespace {
+    fn prefix(&self) -> &'static str {
+        "user.webdav."
+    }
+
+    fn list(&self, file: &File) -> Vec<String> {
+        let mut names = vec!["etag".into(), "href".into()];
+        if file.fileid().is_some() {
+            names.push("fileid".into());
+        }
+        names
+    }
+
+    fn get(&self, file: &File, href: &str, name: &str) -> Option<Vec<u8>> {
+        match name {
+            "etag" => Some(file.etag().as_bytes().to_vec()),
+            "href" => Some(href.as_bytes().to_vec()),
+            "fileid" => file.fileid().map(|id| id.as_bytes().to_vec()),
+            _ => None,
+        }
+    }
+}
+
+/// Nextcloud and ownCloud specific properties returned by PROPFIND
+pub struct NextcloudNamespace;
+
+impl XattrNamespace for NextcloudNamespace {
+    fn prefix(&self) -> &'static str {
+        "user.nextcloud."
+    }
+
+    fn list(&self, file: &File) -> Vec<String> {
+        file.nextcloud_props().keys().cloned().collect()
+    }
+
+    fn get(&self, file: &File, _href: &str, name: &str) -> Option<Vec<u8>> {
+        file.nextcloud_props()
+            .get(name)
+            .map(|v| v.as_bytes().to_vec())
+    }
+}
+
+/// Dead properties, which clients can store on the server for any resource
+pub struct DeadPropNamespace;
+
+impl XattrNamespace for DeadPropNamespace {
+    fn prefix(&self) -> &'static str {
+        DEAD_PROP_PREFIX
+    }
+
+    fn list(&self, file: &File) -> Vec<String> {
+        file.dead_props().keys().cloned().collect()
+    }
+
+    fn get(&self, file: &File, _href: &str, name: &str) -> Option<Vec<u8>> {
+        file.dead_props().get(name).map(|v| v.as_bytes().to_vec())
+    }
+
+    /// Only updates `file`, the filesystem sends the change to the server
+    fn set(&self, file: &mut File, name: &str, value: &[u8]) -> Result<(), Errors> {
+        let full_name = || (DEAD_PROP_PREFIX.to_string() + name).into();
+        // The target of emulated symlinks has its own syscalls
+        if name == SYMLINK_PROPERTY {
+            return Err(Errors::XattrReadOnly(full_name()));
+        }
+        let value = std::str::from_utf8(value).map_err(|_| Errors::XattrInvalid(full_name()))?;
+        if !is_xml_name(name) {
+            return Err(Errors::XattrInvalid(full_name()));
+        }
+        file.set_dead_prop(name, value);
+        Ok(())
+    }
+
+    fn remove(&self, file: &mut File, name: &str) -> Result<(), Errors> {
+        let full_name = || (DEAD_PROP_PREFIX.to_string() + name).into();
+        if name == SYMLINK_PROPERTY {
+            return Err(Errors::XattrReadOnly(full_name()));
+        }
+        match file.remove_dead_prop(name) {
+            true => Ok(()),
+            false => Err(Errors::XattrNotFound(full_name())),
+        }
+    }
+}
+
+/// Whether `name` can be used as the tag of a property without escaping
+fn is_xml_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let first = chars.next().map_or(false, |c| c.is_ascii_alphabetic() || c == '_');
+    first && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Dispatches extended attribute requests to the namespace matching the attribute name
+pub struct XattrRegistry {
+    namespaces: Vec<Box<dyn XattrNamespace>>,
+}
+
+impl Default for XattrRegistry {
+    /// Registry with all namespaces the filesystem supports out of the box
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register(Box::new(WebdavfsNamespace));
+        registry.register(Box::new(WebdavNamespace));
+        registry.register(Box::new(NextcloudNamespace));
+        registry.register(Box::new(DeadPropNamespace));
+        registry
+    }
+}
+
+impl XattrRegistry {
+    pub fn new() -> Self {
+        Self { namespaces: vec![] }
+    }
+
+    pub fn register(&mut self, namespace: Box<dyn XattrNamespace>) {
+        self.namespaces.push(namespace);
+    }
+
+    /// Finds the namespace responsible for `name` and strips its prefix from the name
+    fn resolve<'a>(&self, name: &'a OsStr) -> Result<(&dyn XattrNamespace, &'a str), Errors> {
+        let unsupported = || Errors::XattrNamespaceUnsupported(name.into());
+        let name = name.to_str().ok_or_else(unsupported)?;
+        self.namespaces
+            .iter()
+            .find_map(|ns| name.strip_prefix(ns.prefix()).map(|n| (&**ns, n)))
+            .ok_or_else(unsupported)
+    }
+
+    pub fn get(&self, file: &File, href: &str, name: &OsStr) -> Result<Vec<u8>, Errors> {
+        let (namespace, attr) = self.resolve(name)?;
+        namespace
+            .get(file, href, attr)
+            .ok_or_else(|| Errors::XattrNotFound(name.into()))
+    }
+
+    pub fn set(&self, file: &mut File, name: &OsStr, value: &[u8]) -> Result<(), Errors> {
+        let (namespace, attr) = self.resolve(name)?;
+        namespace.set(file, attr, value)
+    }
+
+    pub fn remove(&self, file: &mut File, name: &OsStr) -> Result<(), Errors> {
+        let (namespace, attr) = self.resolve(name)?;
+        namespace.remove(file, attr)
+    }
+
+    /// Returns the full names of all attributes of `file`, in the NUL separated format
+    /// listxattr(2) expects
+    pub fn list(&self, file: &File) -> Vec<u8> {
+        let mut names = Vec::new();
+        for namespace in &self.namespaces {
+            for name in namespace.list(file) {
+                names.extend_from_slice(namespace.prefix().as_bytes());
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+        }
+        names
+    }
+}
+